@@ -1,4 +1,5 @@
 use super::*;
+use crate::defs::DEFAULT_SYS_LIB;
 
 pub const HELP: &str = 
 "shdc - Compiler for the Shard Programming Language
@@ -15,20 +16,78 @@ Options:
   -v, --verbose   log level = info
   -d, --debug     log level = debug
 
+  -O0, -O1, -O2   How hard to optimise, -O0 by default. -O1 propagates and folds constants,
+                  drops dead code, threads jumps, turns calls right before a return into jumps
+                  and tidies the emitted instructions, -O2 also propagates copies, drops dead
+                  stores and repeats all of them while they change anything
+      --opt={passes}, --no-opt={passes} Run or skip single passes whatever the level, any of
+                  const-prop, fold, copy-prop, dse, dce, jump-thread, tail-call and peephole
+                  separated by commas
+
   -a, --arch      Specify the target Architecture
+  -F, --omit-fp   Don't keep a Frame Pointer, address locals through rsp
+  -R, --fixed-regs Keep r0 to r11 in fixed x86_64 registers (r0 = rax, r1 = rbx, ...) instead
+                  of allocating them
+  -b, --bounds-check Trap on out of range array indexes at runtime
+  -L, --sys-lib   Specify the System Library directory, read by `.use`. The libraries in lib/
+                  are built in for when it doesn't have them
+  -f, --freestanding Don't rely on libc, `end` exits through a system call
+      --no-warn={unreachable,unused-label,unused-var,uninit} Turn the warnings off, any
+                  number of them separated by commas
 
   -t, --noclean   Keep Temp Files
-  -A, --asm       Compile to Assembly Only";
+  -A, --asm       Compile to Assembly Only
+      --emit={ir,cfg} Write the IR the program lowers into, or its control-flow graph as
+                  Graphviz DOT, instead";
 
 pub const VERSION: &str = "onyx 0.1.0";
 
+// what to write instead of a binary, besides -A's assembly
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Emit {
+    Ir,
+    Cfg,
+}
+
+// the warnings drawn from the control flow, see lint.rs
+#[derive(Debug, Clone, Copy)]
+pub struct Warnings {
+    pub unreachable: bool,
+    pub unused_label: bool,
+    pub unused_var: bool,
+    pub uninit: bool,
+}
+
+// the passes over the IR, see opt.rs, tail calls, see compiler.rs, and the one over the emitted
+// instructions, see peephole.rs. The -O level decides those --opt and --no-opt don't name
+#[derive(Debug, Clone, Copy)]
+pub struct Passes {
+    pub const_prop: Option<bool>,
+    pub fold: Option<bool>,
+    pub copy_prop: Option<bool>,
+    pub dse: Option<bool>,
+    pub dce: Option<bool>,
+    pub jump_thread: Option<bool>,
+    pub tail_call: Option<bool>,
+    pub peephole: Option<bool>,
+}
+
 #[derive(Debug)]
 pub struct Args {
     pub infile:  &'static str,
     pub outfile: &'static str,
     pub asm:     bool,
+    pub emit:    Option<Emit>,
     pub log_level: Level,
     pub noclean: bool,
+    pub omit_fp: bool,
+    pub fixed_regs: bool,
+    pub bounds_check: bool,
+    pub freestanding: bool,
+    pub warn:    Warnings,
+    pub opt_level: u8,
+    pub passes:  Passes,
+    pub sys_lib: &'static str,
 }
 
 // the actual args
@@ -36,8 +95,31 @@ pub static mut ARGS: Args = Args {
     infile:  "",
     outfile: "output",
     asm:     false,
+    emit:    None,
     log_level: Level::Fatal,
     noclean: false,
+    omit_fp: false,
+    fixed_regs: false,
+    bounds_check: false,
+    freestanding: false,
+    warn: Warnings {
+        unreachable: true,
+        unused_label: true,
+        unused_var: true,
+        uninit: true,
+    },
+    opt_level: 0,
+    passes: Passes {
+        const_prop: None,
+        fold: None,
+        copy_prop: None,
+        dse: None,
+        dce: None,
+        jump_thread: None,
+        tail_call: None,
+        peephole: None,
+    },
+    sys_lib: DEFAULT_SYS_LIB,
 };
 
 pub fn parse() {
@@ -72,11 +154,64 @@ pub fn parse() {
                     log!(FATAL, "expected `=` after the {} flag", arg).push();
                 }
             },
+            c if c.starts_with("--emit") => {
+                match arg.split_once('=') {
+                    Some((_, "ir")) => unsafe { ARGS.emit = Some(Emit::Ir) },
+                    Some((_, "cfg")) => unsafe { ARGS.emit = Some(Emit::Cfg) },
+                    Some((_, what)) => log!(FATAL, "Invalid Emit Option: {}", what).push(),
+                    None => log!(FATAL, "expected `=` after the {} flag", arg).push(),
+                }
+            },
+            c if c.starts_with("--no-warn") => {
+                let Some((_, warnings)) = arg.split_once('=') else {
+                    log!(FATAL, "expected `=` after the {} flag", arg).push();
+                    continue;
+                };
+                for warning in warnings.split(',') {
+                    match warning {
+                        "unreachable" => unsafe { ARGS.warn.unreachable = false },
+                        "unused-label" => unsafe { ARGS.warn.unused_label = false },
+                        "unused-var" => unsafe { ARGS.warn.unused_var = false },
+                        "uninit" => unsafe { ARGS.warn.uninit = false },
+                        _ => log!(FATAL, "Invalid Warning: {}", warning).push(),
+                    }
+                }
+            },
+            c if c.starts_with("--opt") || c.starts_with("--no-opt") => {
+                let Some((flag, passes)) = arg.split_once('=') else {
+                    log!(FATAL, "expected `=` after the {} flag", arg).push();
+                    continue;
+                };
+                let on = Some(flag == "--opt");
+                for pass in passes.split(',') {
+                    match pass {
+                        "const-prop" => unsafe { ARGS.passes.const_prop = on },
+                        "fold" => unsafe { ARGS.passes.fold = on },
+                        "copy-prop" => unsafe { ARGS.passes.copy_prop = on },
+                        "dse" => unsafe { ARGS.passes.dse = on },
+                        "dce" => unsafe { ARGS.passes.dce = on },
+                        "jump-thread" => unsafe { ARGS.passes.jump_thread = on },
+                        "tail-call" => unsafe { ARGS.passes.tail_call = on },
+                        "peephole" => unsafe { ARGS.passes.peephole = on },
+                        _ => log!(FATAL, "Invalid Pass: {}", pass).push(),
+                    }
+                }
+            },
+            c if c.starts_with("-O") => match &c[2..] {
+                "0" => unsafe { ARGS.opt_level = 0 },
+                "1" => unsafe { ARGS.opt_level = 1 },
+                "2" => unsafe { ARGS.opt_level = 2 },
+                level => log!(FATAL, "Invalid Optimisation Level: {}", level).push(),
+            },
             "--debug" | "-d" => unsafe { ARGS.log_level = Level::Debug },
             "--quiet" | "-q" => unsafe { ARGS.log_level = Level::Err },
             "--verbose" | "-v" => unsafe { ARGS.log_level = Level::Ok },
             "--noclean" | "-t" => unsafe { ARGS.noclean = true },
             "--asm" | "-A" => unsafe { ARGS.asm = true },
+            "--omit-fp" | "-F" => unsafe { ARGS.omit_fp = true },
+            "--fixed-regs" | "-R" => unsafe { ARGS.fixed_regs = true },
+            "--bounds-check" | "-b" => unsafe { ARGS.bounds_check = true },
+            "--freestanding" | "-f" => unsafe { ARGS.freestanding = true },
             "--output" | "-o" => {
                 if let Some(outfile) = args.next() {
                     unsafe { ARGS.outfile = Box::leak(outfile.into_boxed_str()) };
@@ -84,6 +219,13 @@ pub fn parse() {
                     log!(FATAL, "Missing output file argument after the output flag").push();
                 }
             },
+            "--sys-lib" | "-L" => {
+                if let Some(dir) = args.next() {
+                    unsafe { ARGS.sys_lib = Box::leak(dir.into_boxed_str()) };
+                } else {
+                    log!(FATAL, "Missing directory argument after the sys-lib flag").push();
+                }
+            },
             "--arch" | "-a" => {
                 if let Some(arch) = args.next() {
                     match arch.as_str() {
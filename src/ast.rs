@@ -1,20 +1,363 @@
-use std::rc::Rc;
-
-pub enum AST {
-    Assignment(Rc<AST>, Rc<AST>),
-    Add(Rc<AST>, Option<Rc<AST>>),
-    Subtract(Rc<AST>, Option<Rc<AST>>),
-    Multiply(Rc<AST>, Option<Rc<AST>>),
-    Divide(Rc<AST>, Option<Rc<AST>>),
-    BitwiseAnd(Rc<AST>, Option<Rc<AST>>),
-    BitwiseOr(Rc<AST>, Option<Rc<AST>>),
-    BitwiseXor(Rc<AST>, Option<Rc<AST>>),
-    BitwiseNot(Rc<AST>, Option<Rc<AST>>),
-    BitwiseLeftShift(Rc<AST>, Rc<AST>),
-    BitwiseRightShift(Rc<AST>, Rc<AST>),
-    Increment(Rc<AST>),
-    Decrement(Rc<AST>),
-    PopStackInto(Rc<AST>),
-    PeekStackInto(Rc<AST>),
+use std::ops::Range;
 
+use crate::location::Span;
+
+pub type Name = String;
+
+#[derive(Debug)]
+pub struct Program {
+    pub stmts: Vec<Stmt>,
+    pub data: Vec<DataBlock>,
+    pub functions: Vec<FnDef>,
+    pub externs: Vec<ExternDef>,
+    pub entry: Option<Name>, // .entry main
+}
+
+// .extern printf(8, ...) -> s4
+// checks `$printf` calls, which are made without a declaration too
+#[derive(Debug, Clone)]
+pub struct ExternDef {
+    pub name: Name,
+    pub params: Vec<Type>,
+    pub variadic: bool,
+    pub ret: Option<Type>,
+    pub span: Span,
+}
+
+// @add a 8, b s4 -> 8 {
+//     ret (a + b)
+// }
+// The body is flattened into Program::stmts, starting with a label named after the function
+#[derive(Debug, Clone)]
+pub struct FnDef {
+    pub name: Name,
+    pub params: Vec<Param>,
+    pub ret: Option<Type>, // None for `-> ...` being left out, such a function returns nothing
+    pub attrs: FnAttrs,
+    pub stmts: Range<usize>,
+    pub span: Span,
+}
+
+// |inline|, see expand.rs for how they are spliced into their callers
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FnAttrs {
+    pub inline: bool, // every call is replaced by the body, arguments are evaluated once into fresh variables
+    pub macro_: bool, // every call is replaced by the body, parameters stand for the argument expressions themselves
+    pub ignore: bool, // parsed, then dropped as if it were commented out. Calling it is an error
+    pub sysv: bool,   // follows System V instead of the native convention, see compiler.rs
+    pub notail: bool, // its calls stay calls right before a `ret`, its frame stays until they return
+}
+
+#[derive(Debug, Clone)]
+pub struct Param {
+    pub name: Name,
+    pub ty: Type,
+    pub span: Span,
+}
+
+// |packed|
+// Point: {
+//     x: 4
+//     y: 4 = 0
+// }
+#[derive(Debug, Clone)]
+pub struct DataBlock {
+    pub name: Name,
+    pub fields: Vec<Field>,
+    pub packed: bool, // no padding, fields may be misaligned
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub struct Field {
+    pub name: Option<Name>,
+    pub ty: Type,
+    pub value: Option<Expr>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub enum StmtKind {
+    Label(Name),                              // loop:
+    StackAssign(Name, Type, Expr),            // %n 2 = 9
+    RegAssign(Name, RegVar, Expr),            // ;temp r3 = 0, ;temp r3 s = -1
+    Mutate(Expr, MutateOp, Expr),             // 'n + 1
+    Jmp(Name),                                // jmp loop
+    JmpTo(Expr),                              // jmp r3, jmp (next.(i))
+    Switch(Expr, Vec<Name>, Option<Name>),    // .switch i {zero, one} | other, falls through without `other`
+    Ret(Option<Expr>),                        // ret, ret (a + 1)
+    End(Expr),                                // end 0, exits the process with that status
+    Call(Callee, Vec<Expr>),                  // #add 1, n
+    Cond(Expr, Box<Stmt>, Option<Box<Stmt>>), // (n > 0) => jmp loop | ret
+    Push(Expr, Type),                         // ^n 2
+    Pop(Expr),                                // ^> r3, as many bytes as r3 is wide
+    Peek(Expr),                               // ^? %n, like a pop that leaves the stack alone
+}
+
+#[derive(Debug, Clone)]
+pub enum Callee {
+    Named(Name),     // #add
+    Extern(Name),    // $puts, from outside the program, called like System V says
+    Sys(Name),       // *write, a system call
+    Addr(Box<Expr>), // <- r3, whatever address the expression evaluates to
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type {
+    Size(u8),                      // 1 | 2 | 4 | 8, or u1 | u2 | u4 | u8
+    Signed(u8),                    // s1 | s2 | s4 | s8
+    Bits(u8, u8),                  // lo:hi, or :hi. Bits lo up to (not incl.) hi of a 1, 2, 4 or 8 byte unit
+    Named(Name),                   // a data block
+    Array(Box<Type>, Option<u64>), // [4, 16], or [4] taking the length from the initializer
+}
+
+#[derive(Debug, Clone)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub enum ExprKind {
+    Int(i128), // anything from i64::MIN to u64::MAX
+    Str(String),      // "Hi\n", the address of its bytes in .rodata, followed by a 0 byte
+    Array(Vec<Expr>), // {1, 2, 3}
+    Fill(i128),       // 0*, every number within an array or data block
+    Ident(Name),
+    LabelAddr(Name), // &loop
+    Register(Register),
+    ArrIndex(Name, Box<Expr>),           // a.3, a.(i + 1)
+    StructIndex(Name, FieldRef),         // s#x, s#2
+    SizeOf(Type),                        // sizeof(Point)
+    OffsetOf(Name, FieldRef),            // offsetof(Point, y)
+    Deref(Box<Expr>),                    // [p + 8], as wide as what it's used with
+    Not(Box<Expr>),                      // ~(a | b)
+    Binary(BinOp, Box<Expr>, Box<Expr>), // (a + b)
+    Call(Callee, Vec<Expr>),             // #add 1, n as a value, which is what the call leaves in r0
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldRef {
+    Name(Name),
+    Index(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinOp {
+    Add,    // +
+    Sub,    // -
+    Mul,    // *
+    Div,    // /
+    Mod,    // %
+    AndNot, // ~  (a & ~b)
+    Or,     // |
+    And,    // &
+    Xor,    // ^
+    LogOr,  // ||
+    LogAnd, // &&
+    LogXor, // ^^
+    Gt,     // >
+    Lt,     // <
+    Ge,     // >=
+    Le,     // <=
+    Eq,     // =
+    Ne,     // ~=
+}
+
+// 'a op b, the unary ones get an implied operand from the parser
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MutateOp {
+    Set,       // =   a = b
+    Add,       // +   a = a + b
+    Sub,       // -   a = a - b
+    Mul,       // *   a = a * b
+    Div,       // /   a = a / b, signed if a or b is
+    Mod,       // %   a = a % b, signed if a or b is
+    AndNot,    // ~   a = a & ~b, clears the bits set in b
+    Or,        // |   a = a | b
+    And,       // &   a = a & b
+    Xor,       // ^   a = a ^ b
+    Shr,       // >   a = a >> b, arithmetic if a is signed
+    Shl,       // <   a = a << b
+    Store,     // :   [a] = b, a holds the address
+    Inc,       // ++  a = a + 1
+    Dec,       // --  a = a - 1
+    SetIfZero, // ?   a = b, only if a is 0
+    Clear,     // _   a = 0
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Register {
+    pub num: u8,
+    pub size: RegSize,
+}
+
+// a register bound to a name, which may also give it a sign
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RegVar {
+    pub reg: Register,
+    pub signed: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegSize {
+    ByteLow,  // l
+    ByteHigh, // h
+    Word,     // w
+    DWord,    // d
+    QWord,    // q, or no suffix
+}
+
+impl FnDef {
+    // @add a 8, b s4 -> 8
+    pub fn signature(&self) -> String {
+        let mut sig = format!("@{}", self.name);
+        for (i, param) in self.params.iter().enumerate() {
+            sig.push_str(if i == 0 { " " } else { ", " });
+            sig.push_str(&format!("{} {}", param.name, param.ty));
+        }
+        if let Some(ret) = &self.ret {
+            sig.push_str(&format!(" -> {}", ret));
+        }
+        sig
+    }
+}
+
+impl ExternDef {
+    // .extern printf(8, ...) -> s4
+    pub fn signature(&self) -> String {
+        let mut params = self.params.iter().map(|ty| ty.to_string()).collect::<Vec<_>>();
+        if self.variadic {
+            params.push("...".to_string());
+        }
+        let mut sig = format!(".extern {}({})", self.name, params.join(", "));
+        if let Some(ret) = &self.ret {
+            sig.push_str(&format!(" -> {}", ret));
+        }
+        sig
+    }
+}
+
+impl Stmt {
+    // calls `f` on the statement and on every statement nested in it
+    pub fn walk<'s>(&'s self, f: &mut impl FnMut(&'s Stmt)) {
+        f(self);
+        if let StmtKind::Cond(_, then, otherwise) = &self.kind {
+            then.walk(f);
+            if let Some(otherwise) = otherwise {
+                otherwise.walk(f);
+            }
+        }
+    }
+
+    // the expressions of the statement itself, the arms of a conditional are statements of their own
+    pub fn exprs(&self) -> Vec<&Expr> {
+        match &self.kind {
+            StmtKind::StackAssign(_, _, value) | StmtKind::RegAssign(_, _, value) => vec![value],
+            StmtKind::Mutate(target, _, value) => vec![target, value],
+            StmtKind::Ret(value) => value.iter().collect(),
+            StmtKind::Call(callee, args) => callee.addr().into_iter().chain(args).collect(),
+            StmtKind::Cond(cond, _, _) => vec![cond],
+            StmtKind::Push(value, _) | StmtKind::Pop(value) | StmtKind::Peek(value) => vec![value],
+            StmtKind::JmpTo(value) | StmtKind::Switch(value, _, _) | StmtKind::End(value) => vec![value],
+            StmtKind::Label(_) | StmtKind::Jmp(_) => Vec::new(),
+        }
+    }
+
+    // whether control never continues with the next statement
+    pub fn diverges(&self) -> bool {
+        match &self.kind {
+            StmtKind::Ret(_) | StmtKind::End(_) | StmtKind::Jmp(_) | StmtKind::JmpTo(_) | StmtKind::Switch(_, _, Some(_)) => true,
+            StmtKind::Cond(_, then, Some(otherwise)) => then.diverges() && otherwise.diverges(),
+            _ => false,
+        }
+    }
+}
+
+impl Expr {
+    // calls `f` on the expression and on every expression within it
+    pub fn walk<'e>(&'e self, f: &mut impl FnMut(&'e Expr)) {
+        f(self);
+        match &self.kind {
+            ExprKind::Array(elems) => elems.iter().for_each(|e| e.walk(f)),
+            ExprKind::ArrIndex(_, inner) | ExprKind::Deref(inner) | ExprKind::Not(inner) => inner.walk(f),
+            ExprKind::Binary(_, lhs, rhs) => {
+                lhs.walk(f);
+                rhs.walk(f);
+            },
+            ExprKind::Call(callee, args) => {
+                if let Some(addr) = callee.addr() {
+                    addr.walk(f);
+                }
+                args.iter().for_each(|a| a.walk(f));
+            },
+            _ => (),
+        }
+    }
+}
+
+impl Callee {
+    pub fn addr(&self) -> Option<&Expr> {
+        match self {
+            Callee::Named(_) | Callee::Extern(_) | Callee::Sys(_) => None,
+            Callee::Addr(addr) => Some(addr),
+        }
+    }
+}
+
+impl BinOp {
+    pub fn is_comparison(self) -> bool {
+        matches!(self, BinOp::Gt | BinOp::Lt | BinOp::Ge | BinOp::Le | BinOp::Eq | BinOp::Ne)
+    }
+
+    pub fn is_logical(self) -> bool { matches!(self, BinOp::LogOr | BinOp::LogAnd | BinOp::LogXor) }
+}
+
+impl MutateOp {
+    // the operator `'a op b` applies as `a = (a op b)`, None for stores and shifts
+    pub fn binop(self) -> Option<BinOp> {
+        match self {
+            MutateOp::Add | MutateOp::Inc => Some(BinOp::Add),
+            MutateOp::Sub | MutateOp::Dec => Some(BinOp::Sub),
+            MutateOp::Mul => Some(BinOp::Mul),
+            MutateOp::Div => Some(BinOp::Div),
+            MutateOp::Mod => Some(BinOp::Mod),
+            MutateOp::AndNot => Some(BinOp::AndNot),
+            MutateOp::Or => Some(BinOp::Or),
+            MutateOp::And => Some(BinOp::And),
+            MutateOp::Xor => Some(BinOp::Xor),
+            MutateOp::Set | MutateOp::Shr | MutateOp::Shl | MutateOp::Store | MutateOp::SetIfZero | MutateOp::Clear => None,
+        }
+    }
+
+    // ++, -- and _ take no operand
+    pub fn is_unary(self) -> bool { matches!(self, MutateOp::Inc | MutateOp::Dec | MutateOp::Clear) }
+}
+
+impl std::fmt::Display for Type {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            Type::Size(s) => write!(f, "{}", s),
+            Type::Signed(s) => write!(f, "s{}", s),
+            Type::Bits(lo, hi) => write!(f, "{}:{}", lo, hi),
+            Type::Named(name) => write!(f, "{}", name),
+            Type::Array(elem, Some(len)) => write!(f, "[{}, {}]", elem, len),
+            Type::Array(elem, None) => write!(f, "[{}]", elem),
+        }
+    }
+}
+
+impl std::fmt::Display for FieldRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            FieldRef::Name(name) => write!(f, "{}", name),
+            FieldRef::Index(i) => write!(f, "{}", i),
+        }
+    }
 }
@@ -0,0 +1,305 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::ir::{Block, Callee, Edge, Inst, Program, VReg};
+
+// The control-flow graph over the blocks of the IR. It spans the whole program since a `jmp` can
+// land in another function, and a computed jump has an edge to every label whose address is taken
+
+pub struct Cfg<'a> {
+    pub program: &'a Program,
+    pub nodes: Vec<(usize, usize)>, // function and block
+    pub succs: Vec<Vec<(usize, Edge)>>,
+    pub index: HashMap<&'a str, usize>,
+}
+
+impl<'a> Cfg<'a> {
+    pub fn new(program: &'a Program) -> Cfg<'a> {
+        let nodes = program.functions.iter().enumerate()
+            .flat_map(|(f, func)| (0..func.blocks.len()).map(move |b| (f, b)))
+            .collect::<Vec<_>>();
+        let index = nodes.iter().enumerate()
+            .map(|(i, &(f, b))| (program.functions[f].blocks[b].label.as_str(), i))
+            .collect::<HashMap<_, _>>();
+
+        let mut succs = vec![Vec::new(); nodes.len()];
+        for (i, &(f, b)) in nodes.iter().enumerate() {
+            for (label, edge) in program.functions[f].succs(b, &program.taken) {
+                // the verifier made sure every target exists
+                let Some(&j) = index.get(label.as_str()) else { continue };
+                if !succs[i].contains(&(j, edge)) {
+                    succs[i].push((j, edge));
+                }
+            }
+        }
+        Cfg { program, nodes, succs, index }
+    }
+
+    pub fn block(&self, node: usize) -> &'a Block {
+        let (f, b) = self.nodes[node];
+        &self.program.functions[f].blocks[b]
+    }
+
+    // where control may come from outside the graph: the start of every function and every label
+    // whose address is taken
+    pub fn roots(&self) -> Vec<usize> {
+        self.nodes.iter().enumerate()
+            .filter(|(_, &(_, b))| b == 0)
+            .map(|(i, _)| i)
+            .chain(self.program.taken.iter().filter_map(|l| self.index.get(l.as_str()).copied()))
+            .collect()
+    }
+
+    pub fn reachable(&self) -> Vec<bool> {
+        let mut seen = vec![false; self.nodes.len()];
+        let mut stack = self.roots();
+        while let Some(i) = stack.pop() {
+            if std::mem::replace(&mut seen[i], true) {
+                continue;
+            }
+            stack.extend(self.succs[i].iter().map(|&(j, _)| j).filter(|&j| !seen[j]));
+        }
+        seen
+    }
+
+    // one Graphviz digraph per function, blocks it can't reach are grayed out
+    pub fn dot(&self) -> String {
+        let reachable = self.reachable();
+        let mut out = String::new();
+        for (f, func) in self.program.functions.iter().enumerate() {
+            writeln!(out, "digraph \"{}\" {{", func.name).unwrap();
+            writeln!(out, "    node [shape=box, fontname=monospace];").unwrap();
+            let mut outside = Vec::new();
+            for (i, _) in self.nodes.iter().enumerate().filter(|(_, &(nf, _))| nf == f) {
+                let block = self.block(i);
+                let text = [format!("{}:", block.label)].into_iter()
+                    .chain(block.insts.iter().map(|inst| format!("    {}", inst)))
+                    .map(|line| escape(&line) + "\\l")
+                    .collect::<String>();
+                let fill = if reachable[i] { "" } else { ", style=filled, fillcolor=lightgray" };
+                writeln!(out, "    \"{}\" [label=\"{}\"{}];", block.label, text, fill).unwrap();
+
+                for &(j, edge) in &self.succs[i] {
+                    let target = self.block(j);
+                    if self.nodes[j].0 != f && !outside.contains(&j) {
+                        outside.push(j);
+                        writeln!(out, "    \"{}\" [shape=plaintext];", target.label).unwrap();
+                    }
+                    let style = match edge {
+                        Edge::Fall => "",
+                        Edge::Jump => " [color=blue]",
+                        Edge::Computed => " [style=dashed]",
+                    };
+                    writeln!(out, "    \"{}\" -> \"{}\"{};", block.label, target.label, style).unwrap();
+                }
+            }
+            writeln!(out, "}}\n").unwrap();
+        }
+        out
+    }
+}
+
+//
+// liveness
+// a set of registers, Shard's in the first four words and the temporaries after them
+pub type Regs = [u64; 5];
+
+pub fn insert(set: &mut Regs, reg: VReg) { set[reg.0 as usize / 64] |= 1 << (reg.0 % 64); }
+
+pub fn remove(set: &mut Regs, reg: VReg) { set[reg.0 as usize / 64] &= !(1 << (reg.0 % 64)); }
+
+pub fn contains(set: &Regs, reg: VReg) -> bool { set[reg.0 as usize / 64] & (1 << (reg.0 % 64)) != 0 }
+
+pub fn members(set: &Regs) -> impl Iterator<Item = VReg> + '_ {
+    (0..64 * set.len() as u16).map(VReg).filter(|&r| contains(set, r))
+}
+
+impl Cfg<'_> {
+    // the registers live before an instruction, from the ones live after it. A call needs the
+    // Shard registers live into its callee, `ins`, and one through an address those of any
+    // function. A bare `ret` hands back whatever r0 holds
+    pub fn live_before(&self, ins: &[Regs], inst: &Inst, mut live: Regs) -> Regs {
+        if let Some(reg) = inst.writes() {
+            remove(&mut live, reg);
+        }
+        for reg in inst.uses() {
+            insert(&mut live, reg);
+        }
+        let callees = match inst {
+            Inst::Call(Callee::Native(name) | Callee::SysV(name), ..) => self.index.get(name.as_str()).copied().into_iter().collect(),
+            Inst::Call(Callee::Addr(_), ..) => self.nodes.iter().enumerate().filter(|(_, &(_, b))| b == 0).map(|(i, _)| i).collect(),
+            Inst::Ret(None) => {
+                insert(&mut live, VReg(0));
+                Vec::new()
+            },
+            _ => Vec::new(),
+        };
+        for callee in callees {
+            for (live, callee) in live[..4].iter_mut().zip(ins[callee]) {
+                *live |= callee;
+            }
+        }
+        live
+    }
+
+    // what's live into and out of every block of the program. Nothing is live after a return, the
+    // callee saved whatever its caller has in registers
+    pub fn liveness(&self) -> (Vec<Regs>, Vec<Regs>) {
+        let n = self.nodes.len();
+        let (mut ins, mut outs) = (vec![[0; 5]; n], vec![[0; 5]; n]);
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for i in (0..n).rev() {
+                let mut live = [0; 5];
+                for &(succ, _) in &self.succs[i] {
+                    for (live, succ) in live.iter_mut().zip(ins[succ]) {
+                        *live |= succ;
+                    }
+                }
+                outs[i] = live;
+                for inst in self.block(i).insts.iter().rev() {
+                    live = self.live_before(&ins, inst, live);
+                }
+                if live != ins[i] {
+                    ins[i] = live;
+                    changed = true;
+                }
+            }
+        }
+        (ins, outs)
+    }
+}
+
+fn escape(line: &str) -> String {
+    line.replace('\\', "\\\\").replace('"', "\\\"").replace('{', "\\{").replace('}', "\\}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::tests::read;
+
+    // every edge as `from -> to`, computed ones dashed
+    fn edges(cfg: &Cfg) -> Vec<String> {
+        let mut edges = Vec::new();
+        for (i, succs) in cfg.succs.iter().enumerate() {
+            for &(j, edge) in succs {
+                let arrow = match edge {
+                    Edge::Fall => "->",
+                    Edge::Jump => "=>",
+                    Edge::Computed => "~>",
+                };
+                edges.push(format!("{} {} {}", cfg.block(i).label, arrow, cfg.block(j).label));
+            }
+        }
+        edges
+    }
+
+    fn live(set: &Regs) -> Vec<String> { members(set).map(|r| r.to_string()).collect() }
+
+    #[test]
+    fn successors() {
+        let program = read("
+fn main sysv {
+main:
+    addr t0, q[two]
+    br.eq r1, 0, other
+pick:
+    switch r1, {one, two}, three
+one:
+    jmp t0
+two:
+    mov r0, 2
+three:
+    jmp away
+other:
+    addr t0, q[one]
+    ret
+}
+
+fn away {
+away:
+    ret r0
+}
+");
+        let cfg = Cfg::new(&program);
+        assert_eq!(edges(&cfg), [
+            "main => other", "main -> pick",
+            "pick => one", "pick => two", "pick => three",
+            // any label whose address is taken, wherever it's taken
+            "one ~> two", "one ~> one",
+            "two -> three",
+            "three => away",
+        ]);
+        assert_eq!(cfg.roots().iter().map(|&i| &cfg.block(i).label).collect::<Vec<_>>(), ["main", "away", "two", "one"]);
+    }
+
+    #[test]
+    fn liveness_across_a_native_call() {
+        let program = read("
+fn main sysv {
+main:
+    mov r1, 1
+    mov r2, 2
+    mov r3, 3
+    call f, 0 -> r0
+    add r0, r2
+    ret r0
+}
+
+fn f {
+f:
+    mov r0, r1
+    mov r3, 0
+    ret
+}
+");
+        let cfg = Cfg::new(&program);
+        let (ins, outs) = cfg.liveness();
+        // r1 goes into the callee and r2 past it, r3 is overwritten there before it's read
+        let main = &program.functions[0].blocks[0].insts;
+        let mut after = outs[0];
+        let mut before_call = [0; 5];
+        for (i, inst) in main.iter().enumerate().rev() {
+            after = cfg.live_before(&ins, inst, after);
+            if i == 3 {
+                before_call = after;
+            }
+        }
+        assert_eq!(live(&before_call), ["r1", "r2"]);
+        assert_eq!(live(&ins[0]), Vec::<String>::new());
+        // a bare ret hands back r0
+        assert_eq!(live(&ins[1]), ["r1"]);
+        assert_eq!(live(&outs[1]), Vec::<String>::new());
+    }
+
+    #[test]
+    fn dot() {
+        let program = read("
+fn main sysv {
+main:
+    br.eq r1, 0, done
+body:
+    dec r1
+    jmp main
+done:
+    ret r1
+dead:
+    ret 0
+}
+");
+        assert_eq!(Cfg::new(&program).dot(), r#"digraph "main" {
+    node [shape=box, fontname=monospace];
+    "main" [label="main:\l    br.eq r1, 0, done\l"];
+    "main" -> "done" [color=blue];
+    "main" -> "body";
+    "body" [label="body:\l    dec r1\l    jmp main\l"];
+    "body" -> "main" [color=blue];
+    "done" [label="done:\l    ret r1\l"];
+    "dead" [label="dead:\l    ret 0\l", style=filled, fillcolor=lightgray];
+}
+
+"#);
+    }
+}
@@ -1,49 +1,674 @@
-use super::*;
-use crate::parser_old::*;
-
 use std::collections::HashSet;
 
-macro_rules! add {
-    ($out:ident, $($fmt:tt)*) => {
-        $out.push_str(&format!($($fmt)*))
+use crate::args_parser::ARGS;
+use crate::ast::{Name, RegSize};
+use crate::frame::Layout;
+use crate::ir::{self, Base, Callee, Inst, VReg};
+use crate::peephole;
+use crate::regalloc::{Homes, FIXED_REGS};
+use crate::x86_64::*;
+
+// Turns the IR into x86_64 assembly, with the registers where regalloc.rs put them. The
+// r-registers in the comments below are the ones of the fixed mapping.
+
+// System V passes the first six integer arguments in these, the rest on the stack
+pub const ARG_REGS: [Reg; 6] = [Reg::Rdi, Reg::Rsi, Reg::Rdx, Reg::Rcx, Reg::R8, Reg::R9];
+
+// besides rax, the named registers a System V callee may overwrite
+const CALLER_SAVED: [Reg; 6] = [Reg::Rcx, Reg::Rdx, Reg::Rsi, Reg::Rdi, Reg::R8, Reg::R9];
+
+// Calls. The arguments are on the stack when the call comes, the first six are loaded into rdi,
+// rsi, rdx, rcx, r8 and r9 (r5, r4, r3, r2, r6, r7) the way System V passes them, the rest pushed
+// again with the first one lowest. The result comes back in rax (r0).
+//
+// Natively a callee preserves every register but r0. Its prologue saves the ones it uses or its
+// own calls overwrite, so a caller only saves the argument registers it loads and uses itself.
+// Most functions touch a few registers, which is cheaper than spilling all of them at every call.
+//
+// The entry, |sysv| and `$` functions follow System V, preserving only rbx and r12 to r15 (r1, r8
+// to r11), callers save r2 to r7 themselves when they use them. `<- addr` may call either kind,
+// so it's made like a System V call. al tells variadic and undeclared `$` functions how many vector
+// registers hold arguments, which is always 0.
+//
+// A call statement leaves its result in r0, a call used as a value preserves r0.
+//
+// From -O1 on, a call right before a `ret` of what it returned is a tail call: the frame is left
+// first and the callee jumped to, so it returns in the caller's place and recursion that ends in
+// a call doesn't grow the stack. The callee mustn't overwrite more than whoever called the caller
+// expects, see Compiler::tail_call. |notail| functions keep their calls.
+//
+// System calls take their arguments in SYSCALL_ARGS, there's no stack to align for them. The
+// kernel overwrites rcx besides rax, so that's saved along with the argument registers.
+
+struct Compiler<'a> {
+    layout: &'a Layout,
+    homes: &'a Homes,
+    text: Vec<Instr>,
+    rodata: Vec<String>, // jump tables
+    func: Option<&'a ir::Function>,
+    depth: u64,      // bytes pushed since the prologue
+    tables: usize,   // jump tables so far
+    used: Vec<Reg>,  // the named registers of the function
+    saved: Vec<Reg>, // what its prologue saves, see the calls above
+}
+
+pub fn compiler(program: &ir::Program, layout: &Layout, homes: &Homes) -> String {
+    let mut c = Compiler {
+        layout,
+        homes,
+        text: Vec::new(),
+        rodata: Vec::new(),
+        func: None,
+        depth: 0,
+        tables: 0,
+        used: Vec::new(),
+        saved: Vec::new(),
     };
+
+    // compiler labels nothing jumps to are left out
+    let referenced = program.functions.iter()
+        .flat_map(|f| f.blocks.iter().flat_map(|b| b.insts.iter().flat_map(Inst::targets)))
+        .chain(&program.taken)
+        .collect::<HashSet<_>>();
+    for (f, func) in program.functions.iter().enumerate() {
+        c.function(func);
+        let depths = func.depths(&program.taken).0;
+        for (i, block) in func.blocks.iter().enumerate() {
+            c.depth = depths[i].max(0) as u64;
+            if !block.label.starts_with('.') || referenced.contains(&block.label) {
+                c.emit(Instr::Label(block.label.clone()));
+            }
+            if block.label == func.name {
+                c.prologue();
+            }
+            let next = match func.blocks.get(i + 1) {
+                Some(next) => Some(&next.label),
+                None => program.functions.get(f + 1).map(|f| &f.blocks[0].label),
+            };
+            let mut k = 0;
+            while k < block.insts.len() {
+                match c.tail_call(&block.insts[k..]) {
+                    Some(n) => k += n,
+                    None => {
+                        c.inst(&block.insts[k], next);
+                        k += 1;
+                    },
+                }
+            }
+        }
+    }
+
+    if peephole::enabled() {
+        peephole::peephole(&mut c.text);
+    }
+
+    let mut out = String::from(".intel_syntax noprefix\n.text\n");
+    if let Some(entry) = &program.entry {
+        out.push_str(&format!(".globl {}\n", entry));
+    }
+    for instr in &c.text {
+        out.push_str(&format!("{}\n", instr));
+    }
+    if !program.data.is_empty() {
+        out.push_str(".data\n");
+        for (label, words) in &program.data {
+            let words = words.iter()
+                .map(|w| match w {
+                    ir::Word::Int(n) => n.to_string(),
+                    ir::Word::Label(label) => label.clone(),
+                })
+                .collect::<Vec<_>>();
+            out.push_str(&format!("{}:\n    .quad {}\n", label, words.join(", ")));
+        }
+    }
+    if !program.strings.is_empty() || !c.rodata.is_empty() {
+        out.push_str(".section .rodata\n");
+        for (i, string) in program.strings.iter().enumerate() {
+            out.push_str(&format!(".Lstr{}:\n    .asciz \"{}\"\n", i, escape(string)));
+        }
+        for line in &c.rodata {
+            out.push_str(&format!("{}\n", line));
+        }
+    }
+    out.push_str(".section .note.GNU-stack,\"\",@progbits\n");
+    out
 }
 
-// TODO create an instruction enum thats architecture based and impl Display for it
-// FIXME this doesnt work with the current lexer/parser
-pub fn compiler((tokens, meta): (Vec<FatToken>, Metadata)) -> String {
-    let mut o_ro = String::new();   // section .rodata
-    let mut o_d = String::new();    // section .data
-    let mut o_t = String::from(".text\n");    // section .text
-    let mut o_b = String::new();    // section .bss
-	
-    // add entry point
-    o_t.push_str(&match meta.entry {
-        Some(name) => format!(".globl {}\n", name),
-        None => String::from(".globl main\n"),
-    });
-
-    let mut labels = HashSet::new();
-
-    for (token, at) in tokens {
-        match token {
-            Token::Label(name) => {
-                if labels.contains(&name) {
-                    // FIXME: Error Handling
-                    panic!("Label `{}` already defined", name);
+impl<'a> Compiler<'a> {
+    fn func(&self) -> &'a ir::Function { self.func.expect("set before its blocks") }
+
+    fn frame_size(&self) -> u64 { self.layout.functions[self.func().func].frame.size }
+
+    fn emit(&mut self, instr: Instr) { self.text.push(instr); }
+
+    fn function(&mut self, func: &'a ir::Function) {
+        self.func = Some(func);
+        self.used = regs_used(func, self.homes);
+        self.saved = self.callee_saved();
+    }
+
+    fn reg(&self, reg: VReg) -> Reg { self.homes.reg(reg) }
+
+    fn operand(&self, operand: &ir::Operand) -> Operand {
+        match operand {
+            ir::Operand::Reg(reg, size) => self.reg(*reg).sized(size_of_bytes(*size)),
+            ir::Operand::Mem(mem) => Operand::Mem(self.mem(mem)),
+            ir::Operand::Imm(n) => Operand::Imm(*n),
+        }
+    }
+
+    // the frame is addressed through rbp, or through rsp and what's pushed on top of it
+    fn mem(&self, mem: &ir::Mem) -> Mem {
+        let index = mem.index.map(|(reg, scale)| (self.reg(reg), scale));
+        let (base, disp) = match &mem.base {
+            Base::None => (None, mem.disp),
+            Base::Reg(reg) => (Some(self.reg(*reg)), mem.disp),
+            Base::Frame if !unsafe { ARGS.omit_fp } => (Some(Reg::Rbp), mem.disp - self.saved_bytes() as i64),
+            Base::Frame => (Some(Reg::Rsp), mem.disp + (self.frame_size() + self.depth) as i64),
+            Base::Stack => (Some(Reg::Rsp), mem.disp),
+            Base::Sym(label) => return Mem::rip(mem.size, label.clone()),
+        };
+        Mem {
+            size: mem.size,
+            base,
+            index,
+            disp,
+            label: None,
+        }
+    }
+
+    fn prologue(&mut self) {
+        let size = self.frame_size() as i64;
+        if !unsafe { ARGS.omit_fp } {
+            self.emit(Instr::Push(Reg::Rbp.q()));
+            self.emit(Instr::Mov(Reg::Rbp.q(), Reg::Rsp.q()));
+        }
+        for reg in self.saved.clone() {
+            self.emit(Instr::Push(reg.q()));
+        }
+        if size > 0 {
+            self.emit(Instr::Sub(Reg::Rsp.q(), Operand::Imm(size)));
+        }
+    }
+
+    // drops whatever is still pushed, Layout::track_pushes warns about that. The registers in
+    // `args` hold the arguments of a tail call, what was saved of them is dropped too
+    fn epilogue(&mut self, args: &[Reg]) {
+        let size = (self.frame_size() + self.depth) as i64;
+        if !unsafe { ARGS.omit_fp } && self.saved.is_empty() {
+            return self.emit(Instr::Leave);
+        }
+        if size > 0 {
+            self.emit(Instr::Add(Reg::Rsp.q(), Operand::Imm(size)));
+        }
+        for reg in self.saved.clone().iter().rev() {
+            let reg = if args.contains(reg) { Reg::R11 } else { *reg };
+            self.emit(Instr::Pop(reg.q()));
+        }
+        if !unsafe { ARGS.omit_fp } {
+            self.emit(Instr::Pop(Reg::Rbp.q()));
+        }
+    }
+
+    // `next` is the label of the block below, which needs no jump
+    fn inst(&mut self, inst: &Inst, next: Option<&Name>) {
+        match inst {
+            Inst::Mov(dst, ir::Operand::Imm(n)) if i32::try_from(*n).is_err() && dst.size() == Some(8) => {
+                let dst = self.operand(dst);
+                self.emit(Instr::Movabs(dst, *n));
+            },
+            Inst::Mov(dst, src) => {
+                let (dst, src) = (self.operand(dst), self.operand(src));
+                self.emit(Instr::Mov(dst, src));
+            },
+            Inst::Ext(dst, src, signed) => {
+                let ir::Operand::Reg(dst, _) = dst else { unreachable!("checked by the verifier") };
+                let dst = self.reg(*dst);
+                let size = src.size().unwrap_or(8);
+                let src = self.operand(src);
+                match (size, signed) {
+                    (4, true) => self.emit(Instr::Movsxd(dst.q(), src)),
+                    (_, true) => self.emit(Instr::Movsx(dst.q(), src)),
+                    // writing the low half clears the upper one
+                    (4, false) => self.emit(Instr::Mov(dst.sized(RegSize::DWord), src)),
+                    (_, false) => self.emit(Instr::Movzx(dst.sized(RegSize::DWord), src)),
                 }
-                labels.insert(name.clone());
-                add!(o_t, "{}:\n", name);
             },
+            Inst::Addr(dst, mem) => {
+                let (dst, mem) = (self.operand(dst), Operand::Mem(self.mem(mem)));
+                self.emit(Instr::Lea(dst, mem));
+            },
+            Inst::Bin(op @ (ir::BinOp::Shl | ir::BinOp::Shr | ir::BinOp::Sar), dst, count) => self.shift(*op, dst, count),
+            Inst::Bin(op, dst, src) => {
+                let (dst, src) = (self.operand(dst), self.operand(src));
+                self.emit(match op {
+                    ir::BinOp::Add => Instr::Add(dst, src),
+                    ir::BinOp::Sub => Instr::Sub(dst, src),
+                    ir::BinOp::Mul => Instr::Imul(dst, src),
+                    ir::BinOp::And => Instr::And(dst, src),
+                    ir::BinOp::Or => Instr::Or(dst, src),
+                    ir::BinOp::Xor => Instr::Xor(dst, src),
+                    _ => unreachable!("shifts are made above"),
+                });
+            },
+            Inst::Un(op, dst) => {
+                let dst = self.operand(dst);
+                self.emit(match op {
+                    ir::UnOp::Not => Instr::Not(dst),
+                    ir::UnOp::Inc => Instr::Inc(dst),
+                    ir::UnOp::Dec => Instr::Dec(dst),
+                });
+            },
+            Inst::Div(dst, src, signed, rem) => self.div(*dst, *src, *signed, *rem),
+            Inst::Set(cond, dst, lhs, rhs) => {
+                self.compare(lhs, rhs);
+                let dst = self.operand(dst);
+                self.emit(Instr::Set(cond_of(*cond), dst));
+            },
+            Inst::Param(mem, i) => self.param(mem, *i),
+            Inst::Push(ir::Operand::Imm(n)) => self.push(Operand::Imm(*n)),
+            Inst::Push(src) => match src.size().unwrap_or(8) {
+                8 => {
+                    let src = self.operand(src);
+                    self.push(src);
+                },
+                // smaller values take only their own bytes, there's no push for them
+                size => {
+                    let src = self.operand(src);
+                    self.emit(Instr::Sub(Reg::Rsp.q(), Operand::Imm(size as i64)));
+                    self.depth += size as u64;
+                    self.emit(Instr::Mov(Operand::Mem(Mem::base(size, Reg::Rsp, 0)), src));
+                },
+            },
+            Inst::Pop(dst) => match dst.size().unwrap_or(8) {
+                8 => {
+                    self.depth = self.depth.saturating_sub(8);
+                    let dst = self.operand(dst);
+                    self.emit(Instr::Pop(dst));
+                },
+                size => {
+                    let dst = self.operand(dst);
+                    self.emit(Instr::Mov(dst, Operand::Mem(Mem::base(size, Reg::Rsp, 0))));
+                    self.emit(Instr::Add(Reg::Rsp.q(), Operand::Imm(size as i64)));
+                    self.depth = self.depth.saturating_sub(size as u64);
+                },
+            },
+            Inst::Fill(mem, value, n) => self.fill(mem, *value, *n),
+            Inst::Call(callee, args, ret) => self.call(callee, *args, *ret),
 
-            Token::Ret(r) => match r{
-                Some(r) => todo!("returning with value not yet implemented"),
-                None => add!(o_t, "ret\n"),
+            Inst::Branch(cond, lhs, rhs, to) => {
+                self.compare(lhs, rhs);
+                self.emit(Instr::J(cond_of(*cond), Operand::Label(to.clone())));
+            },
+            Inst::Jmp(to) if Some(to) == next => (),
+            Inst::Jmp(to) => self.emit(Instr::Jmp(Operand::Label(to.clone()))),
+            Inst::JmpTo(target) => {
+                let target = self.operand(target);
+                self.emit(Instr::Jmp(target));
             },
+            Inst::Switch(index, labels, otherwise) => self.switch(*index, labels, otherwise),
+            Inst::Ret(value) => {
+                if let Some(value) = value {
+                    let value = self.operand(value);
+                    self.emit(Instr::Mov(Reg::Rax.q(), value));
+                }
+                self.epilogue(&[]);
+                self.emit(Instr::Ret);
+            },
+            // nothing comes back, so there's nothing to save and the stack can be aligned for good
+            Inst::Exit(code) => {
+                let code = self.operand(code);
+                self.emit(Instr::Mov(Reg::Rdi.sized(RegSize::DWord), code));
+                match unsafe { ARGS.freestanding } {
+                    true => {
+                        let num = syscall("exit_group").unwrap_or_default();
+                        self.emit(Instr::Mov(Reg::Rax.sized(RegSize::DWord), Operand::Imm(num as i64)));
+                        self.emit(Instr::Syscall);
+                    },
+                    // flushes stdio and runs atexit handlers first
+                    false => {
+                        self.emit(Instr::And(Reg::Rsp.q(), Operand::Imm(-16)));
+                        self.emit(Instr::Call(Operand::Label("exit@PLT".to_string())));
+                    },
+                }
+            },
+            Inst::Trap => self.emit(Instr::Ud2),
+        }
+    }
 
-            _ => todo!(),
+    fn compare(&mut self, lhs: &ir::Operand, rhs: &ir::Operand) {
+        let (lhs, rhs) = (self.operand(lhs), self.operand(rhs));
+        self.emit(Instr::Cmp(lhs, rhs));
+    }
+
+    // stores an argument into its variable, from a register or from above the return address
+    fn param(&mut self, mem: &ir::Mem, i: usize) {
+        let dst = Operand::Mem(self.mem(mem));
+        let size = size_of_bytes(mem.size);
+        match ARG_REGS.get(i) {
+            Some(reg) => self.emit(Instr::Mov(dst, reg.sized(size))),
+            None => {
+                // and above the saved rbp if there is one
+                let above = 8 * (i - ARG_REGS.len()) as i64 + 8;
+                let arg = match unsafe { ARGS.omit_fp } {
+                    false => Mem::base(8, Reg::Rbp, above + 8),
+                    true => Mem::base(8, Reg::Rsp, (self.frame_size() + self.saved_bytes() + self.depth) as i64 + above),
+                };
+                self.emit(Instr::Mov(Reg::R10.q(), Operand::Mem(arg)));
+                self.emit(Instr::Mov(dst, Reg::R10.sized(size)));
+            },
+        }
+    }
+
+    // `.switch` indexes a table in .rodata. Its entries are relative to the table, which needs
+    // no relocations wherever the program is loaded
+    fn switch(&mut self, index: VReg, labels: &[Name], otherwise: &Name) {
+        self.tables += 1;
+        let table = format!(".Ltable{}", self.tables);
+        let index = self.reg(index);
+        let base = if index == Reg::R10 { Reg::R11 } else { Reg::R10 };
+        // negative indexes are huge unsigned ones
+        self.emit(Instr::Cmp(index.q(), Operand::Imm(labels.len() as i64)));
+        self.emit(Instr::J(Cond::Ae, Operand::Label(otherwise.clone())));
+        self.emit(Instr::Lea(base.q(), Operand::Mem(Mem::rip(8, table.clone()))));
+        let entry = Mem {
+            size: 4,
+            base: Some(base),
+            index: Some((index, 4)),
+            disp: 0,
+            label: None,
+        };
+        self.emit(Instr::Movsxd(index.q(), Operand::Mem(entry)));
+        self.emit(Instr::Add(index.q(), base.q()));
+        self.emit(Instr::Jmp(index.q()));
+
+        let entries = labels.iter().map(|l| format!("{} - {}", l, table)).collect::<Vec<_>>();
+        self.rodata.push(format!("    .balign 4\n{}:\n    .long {}", table, entries.join(", ")));
+    }
+
+    // see the calls above. The arguments were pushed before anything is saved for the call, so
+    // they sit below the saved registers
+    fn call(&mut self, callee: &Callee, n: usize, ret: VReg) {
+        let ret = self.reg(ret);
+        let mut saved = self.clobbers(callee, n).into_iter()
+            .filter(|r| self.used.contains(r) && *r != ret)
+            .collect::<Vec<_>>();
+        if ret != Reg::Rax && self.used.contains(&Reg::Rax) {
+            saved.insert(0, Reg::Rax);
+        }
+        for reg in &saved {
+            self.push(reg.q());
+        }
+        let above = 8 * saved.len();
+
+        let regs = match callee {
+            Callee::Sys(_) => &SYSCALL_ARGS,
+            _ => &ARG_REGS,
+        };
+        for (i, reg) in regs.iter().enumerate().take(n) {
+            let arg = Mem::base(8, Reg::Rsp, (above + 8 * (n - 1 - i)) as i64);
+            self.emit(Instr::Mov(reg.q(), Operand::Mem(arg)));
+        }
+        let mut dropped = 0;
+        if let Callee::Sys(name) = callee {
+            let num = syscall(name).unwrap_or_default();
+            self.emit(Instr::Mov(Reg::Rax.sized(RegSize::DWord), Operand::Imm(num as i64)));
+            self.emit(Instr::Syscall);
+        } else {
+            // rsp has to be 16 byte aligned at the call
+            let stacked = n.saturating_sub(ARG_REGS.len()) as u64;
+            let pad = (16 - (self.misalignment() + self.depth + 8 * stacked) % 16) % 16;
+            if pad != 0 {
+                self.emit(Instr::Sub(Reg::Rsp.q(), Operand::Imm(pad as i64)));
+                self.depth += pad;
+            }
+            for i in (ARG_REGS.len()..n).rev() {
+                // every push so far moved the copies of the later arguments one slot further away
+                let offset = above as u64 + 8 * (n - 1 - i) as u64 + pad + 8 * (n - 1 - i) as u64;
+                self.push(Operand::Mem(Mem::base(8, Reg::Rsp, offset as i64)));
+            }
+
+            if let Callee::Extern(_, true) = callee {
+                self.emit(Instr::Xor(Reg::Rax.sized(RegSize::DWord), Reg::Rax.sized(RegSize::DWord)));
+            }
+            self.emit(Instr::Call(match callee {
+                Callee::Native(name) | Callee::SysV(name) => Operand::Label(name.clone()),
+                // through the PLT, wherever the dynamic linker puts it
+                Callee::Extern(name, _) => Operand::Label(format!("{}@PLT", name)),
+                Callee::Addr(reg) => self.reg(*reg).q(),
+                Callee::Sys(_) => unreachable!("made above"),
+            }));
+            dropped = 8 * stacked + pad;
+        }
+
+        if dropped > 0 {
+            self.emit(Instr::Add(Reg::Rsp.q(), Operand::Imm(dropped as i64)));
+            self.depth -= dropped;
+        }
+        if ret != Reg::Rax {
+            self.emit(Instr::Mov(ret.q(), Reg::Rax.q()));
+        }
+        for reg in saved.iter().rev() {
+            self.pop(reg.q());
+        }
+        if n > 0 {
+            self.emit(Instr::Add(Reg::Rsp.q(), Operand::Imm(8 * n as i64)));
+            self.depth -= 8 * n as u64;
+        }
+    }
+
+    // makes a call at the start of `insts` a jump if what follows returns its result, handing
+    // back how many instructions that took. `ret` with a value may move the result first.
+    //
+    // Whoever called this function expects its argument registers overwritten, a System V caller
+    // all of CALLER_SAVED, and the callee mustn't overwrite more: a native one may take no more
+    // arguments than this function, a System V one is only jumped to from another. Parameters are
+    // counted by what the prologue stores, dead code elimination only lowers that. Arguments past
+    // the first six would have to go where the return address is
+    fn tail_call(&mut self, insts: &[Inst]) -> Option<usize> {
+        let [Inst::Call(callee, n, ret), rest @ ..] = insts else { return None };
+        let (func, n) = (self.func(), *n);
+        let enabled = unsafe { ARGS.passes.tail_call.unwrap_or(ARGS.opt_level >= 1) };
+        let len = match rest {
+            [Inst::Ret(None), ..] if *ret == VReg(0) => 2,
+            [Inst::Ret(Some(value)), ..] if *value == ir::Operand::Reg(*ret, 8) => 2,
+            [Inst::Mov(ir::Operand::Reg(copy, 8), ir::Operand::Reg(from, 8)), Inst::Ret(Some(value)), ..]
+                if from == ret && *value == ir::Operand::Reg(*copy, 8) => 3,
+            _ => return None,
+        };
+        let params = func.blocks[0].insts.iter()
+            .filter_map(|inst| match inst {
+                Inst::Param(_, i) => Some(i + 1),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0);
+        let target = match callee {
+            Callee::Native(name) if func.sysv || n <= params => name.clone(),
+            Callee::SysV(name) if func.sysv => name.clone(),
+            Callee::Extern(name, _) if func.sysv => format!("{}@PLT", name),
+            _ => return None,
+        };
+        if !enabled || func.notail || n > ARG_REGS.len() {
+            return None;
+        }
+
+        for (i, reg) in ARG_REGS.iter().enumerate().take(n) {
+            let arg = Mem::base(8, Reg::Rsp, 8 * (n - 1 - i) as i64);
+            self.emit(Instr::Mov(reg.q(), Operand::Mem(arg)));
+        }
+        self.epilogue(&ARG_REGS[..n]);
+        if let Callee::Extern(_, true) = callee {
+            self.emit(Instr::Xor(Reg::Rax.sized(RegSize::DWord), Reg::Rax.sized(RegSize::DWord)));
+        }
+        self.emit(Instr::Jmp(Operand::Label(target)));
+        Some(len)
+    }
+
+    // besides rax, the registers a call may overwrite
+    fn clobbers(&self, callee: &Callee, args: usize) -> Vec<Reg> {
+        match callee {
+            Callee::Sys(_) => SYSCALL_ARGS[..args.min(SYSCALL_ARGS.len())].iter().copied().chain([Reg::Rcx]).collect(),
+            Callee::Native(_) => ARG_REGS[..args.min(ARG_REGS.len())].to_vec(),
+            _ => CALLER_SAVED.to_vec(),
+        }
+    }
+
+    // what the prologue of a function saves: a native one everything it or its calls overwrite
+    // but rax, a System V one only its callee saved registers
+    fn callee_saved(&self) -> Vec<Reg> {
+        if self.func().sysv {
+            let preserved = [Reg::Rbx, Reg::R12, Reg::R13, Reg::R14, Reg::R15];
+            return self.used.iter().copied().filter(|r| preserved.contains(r)).collect();
         }
+
+        let mut clobbered = self.used.clone();
+        for inst in self.func().blocks.iter().flat_map(|b| &b.insts) {
+            if let Inst::Call(callee, args, _) = inst {
+                clobbered.extend(self.clobbers(callee, *args));
+            }
+        }
+        FIXED_REGS.iter().copied().filter(|r| *r != Reg::Rax && clobbered.contains(r)).collect()
+    }
+
+    fn saved_bytes(&self) -> u64 { 8 * self.saved.len() as u64 }
+
+    // how far rsp is off a 16 byte boundary after the prologue, which pushed the return address,
+    // rbp unless it's omitted, and the saved registers
+    fn misalignment(&self) -> u64 {
+        let rbp = if unsafe { ARGS.omit_fp } { 0 } else { 8 };
+        (8 + rbp + self.saved_bytes() + self.frame_size()) % 16
+    }
+
+    // shifts dst by an immediate or by a count in a register, which has to be moved into cl.
+    // rcx is saved around that, so the count mustn't be in TMP for destinations in memory
+    fn shift(&mut self, op: ir::BinOp, dst: &ir::Operand, count: &ir::Operand) {
+        let instr = match op {
+            ir::BinOp::Shl => Instr::Shl,
+            ir::BinOp::Sar => Instr::Sar,
+            _ => Instr::Shr,
+        };
+        let dst = self.operand(dst);
+        let count = match self.operand(count) {
+            Operand::Reg(count, _) => count,
+            count => return self.emit(instr(dst, count)),
+        };
+        let cl = Reg::Rcx.sized(RegSize::ByteLow);
+        match dst {
+            // pushing moves rsp, so the address is taken first
+            Operand::Mem(mem) => {
+                let size = mem.size;
+                self.emit(Instr::Lea(Reg::R10.q(), Operand::Mem(mem)));
+                self.push(Reg::Rcx.q());
+                self.emit(Instr::Mov(Reg::Rcx.q(), count.q()));
+                self.emit(instr(Operand::Mem(Mem::base(size, Reg::R10, 0)), cl));
+                self.pop(Reg::Rcx.q());
+            },
+            // popping rcx would undo the shift, so it's done on a copy
+            Operand::Reg(Reg::Rcx, size) => {
+                self.emit(Instr::Mov(Reg::R10.sized(size), Reg::Rcx.sized(size)));
+                self.push(Reg::Rcx.q());
+                self.emit(Instr::Mov(Reg::Rcx.q(), count.q()));
+                self.emit(instr(Reg::R10.sized(size), cl));
+                self.pop(Reg::Rcx.q());
+                self.emit(Instr::Mov(Reg::Rcx.sized(size), Reg::R10.sized(size)));
+            },
+            dst => {
+                self.push(Reg::Rcx.q());
+                self.emit(Instr::Mov(Reg::Rcx.q(), count.q()));
+                self.emit(instr(dst, cl));
+                self.pop(Reg::Rcx.q());
+            },
+        }
+    }
+
+    // dst = dst / src or dst % src, through rax and rdx which are saved around it
+    fn div(&mut self, dst: VReg, src: VReg, signed: bool, rem: bool) {
+        let (dst, src) = (self.reg(dst), self.reg(src));
+        self.push(Reg::Rax.q());
+        self.push(Reg::Rdx.q());
+        self.emit(Instr::Mov(Reg::Rax.q(), dst.q()));
+        if signed {
+            self.emit(Instr::Cqo);
+            self.emit(Instr::Idiv(src.q()));
+        } else {
+            self.emit(Instr::Xor(Reg::Rdx.sized(RegSize::DWord), Reg::Rdx.sized(RegSize::DWord)));
+            self.emit(Instr::Div(src.q()));
+        }
+        let result = if rem { Reg::Rdx } else { Reg::Rax };
+        self.emit(Instr::Mov(dst.q(), result.q()));
+        self.pop(Reg::Rdx.q());
+        self.pop(Reg::Rax.q());
+    }
+
+    // a single `rep stos`, whose registers are saved around it. The address is taken before
+    // the pushes move rsp
+    fn fill(&mut self, mem: &ir::Mem, value: VReg, n: u64) {
+        let size = mem.size;
+        let mem = Operand::Mem(self.mem(mem));
+        let value = self.reg(value);
+        self.emit(Instr::Lea(Reg::R10.q(), mem));
+        for reg in [Reg::Rax, Reg::Rcx, Reg::Rdi] {
+            self.push(reg.q());
+        }
+        self.emit(Instr::Mov(Reg::Rdi.q(), Reg::R10.q()));
+        self.emit(Instr::Mov(Reg::Rcx.q(), Operand::Imm(n as i64)));
+        self.emit(Instr::Mov(Reg::Rax.q(), value.q()));
+        self.emit(Instr::RepStos(size));
+        for reg in [Reg::Rdi, Reg::Rcx, Reg::Rax] {
+            self.pop(reg.q());
+        }
+    }
+
+    fn push(&mut self, operand: Operand) {
+        self.emit(Instr::Push(operand));
+        self.depth += 8;
+    }
+
+    fn pop(&mut self, operand: Operand) {
+        self.emit(Instr::Pop(operand));
+        self.depth -= 8;
     }
+}
 
-    todo!()
+// the named registers a function reads or writes anywhere, in the order of FIXED_REGS. Call
+// results don't count, r0 only needs saving for a value call when something reads it
+fn regs_used(func: &ir::Function, homes: &Homes) -> Vec<Reg> {
+    let regs = func.blocks.iter()
+        .flat_map(|b| &b.insts)
+        .flat_map(Inst::regs)
+        .map(|reg| homes.reg(reg))
+        .collect::<HashSet<_>>();
+    FIXED_REGS.iter().copied().filter(|r| regs.contains(r)).collect()
+}
+
+fn cond_of(cond: ir::Cond) -> Cond {
+    match cond {
+        ir::Cond::Eq => Cond::E,
+        ir::Cond::Ne => Cond::Ne,
+        ir::Cond::Ult => Cond::B,
+        ir::Cond::Ule => Cond::Be,
+        ir::Cond::Ugt => Cond::A,
+        ir::Cond::Uge => Cond::Ae,
+        ir::Cond::Slt => Cond::L,
+        ir::Cond::Sle => Cond::Le,
+        ir::Cond::Sgt => Cond::G,
+        ir::Cond::Sge => Cond::Ge,
+    }
+}
+
+// the bytes of a string as an assembler string literal
+fn escape(string: &str) -> String {
+    let mut out = String::new();
+    for byte in string.bytes() {
+        match byte {
+            b'"' => out.push_str("\\\""),
+            b'\\' => out.push_str("\\\\"),
+            b'\n' => out.push_str("\\n"),
+            b'\t' => out.push_str("\\t"),
+            0x20..=0x7e => out.push(byte as char),
+            _ => out.push_str(&format!("\\{:03o}", byte)),
+        }
+    }
+    out
 }
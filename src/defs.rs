@@ -1,5 +1,8 @@
 pub const DEFAULT_SYS_LIB: &str = "/usr/share/onyx/";
 
+// the libraries in lib/, for `.use` when the system library doesn't have them
+pub const BUNDLED_LIBS: [(&str, &str); 1] = [("libc", include_str!("../lib/libc.shd"))];
+
 pub enum Arch {
     X86_64, 
 }
@@ -0,0 +1,458 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::ast::*;
+use crate::location::Span;
+use crate::logger::{Log, ERR};
+
+// Calls to |inline| and |macro| functions are replaced by the function's body before the
+// program is laid out, and |ignore|d functions are dropped. Neither kind is compiled on its own.
+//
+// An inlined call evaluates every argument once into a fresh variable, a macro call substitutes
+// the argument expressions wherever a parameter appears, so `'x + 1` in a macro changes the
+// caller's variable. Variables and labels of the body are renamed to `f.N.name` for the N-th
+// splice, which can't clash with anything written by hand. `ret v` sets r0 and jumps past the
+// spliced body, just like a real call leaves its result in r0.
+//
+// A spliced call used as a value is spliced right before its statement and its result kept in
+// a fresh `f.result.N` variable, r0 is kept in `f.r0.N` meanwhile. So unlike real calls, these
+// are made even when `&&` or `||` would skip them.
+
+pub fn expand(program: Program) -> Program {
+    let entry = program.entry.as_deref().unwrap_or("main");
+    for f in &program.functions {
+        let attr = match f.attrs {
+            FnAttrs { ignore: true, .. } => "ignore",
+            FnAttrs { inline: true, .. } => "inline",
+            FnAttrs { macro_: true, .. } => "macro",
+            _ => continue,
+        };
+        if f.name == entry {
+            Log::new(ERR, f.span, format!("The entry `{}` can't be |{}|", f.name, attr), "It has to be compiled on its own").push();
+        }
+    }
+
+    let mut expander = Expander {
+        program: &program,
+        defs: program.functions.iter().map(|f| (f.name.clone(), f)).collect(),
+        stack: Vec::new(),
+        splices: 0,
+    };
+
+    let mut stmts = Vec::new();
+    let mut functions = Vec::new();
+    let mut i = 0;
+    while i < program.stmts.len() {
+        let Some(f) = program.functions.iter().find(|f| f.stmts.start == i) else {
+            expander.emit(program.stmts[i].clone(), &mut stmts);
+            i += 1;
+            continue;
+        };
+
+        i = f.stmts.end;
+        if f.attrs.inline || f.attrs.macro_ || f.attrs.ignore {
+            continue;
+        }
+        let start = stmts.len();
+        for stmt in &program.stmts[f.stmts.clone()] {
+            expander.emit(stmt.clone(), &mut stmts);
+        }
+        functions.push(FnDef {
+            stmts: start..stmts.len(),
+            ..f.clone()
+        });
+    }
+
+    Program {
+        stmts,
+        functions,
+        ..program
+    }
+}
+
+struct Expander<'a> {
+    program: &'a Program,
+    defs: HashMap<Name, &'a FnDef>,
+    stack: Vec<Name>, // functions being spliced, to catch recursion
+    splices: usize,
+}
+
+impl<'a> Expander<'a> {
+    fn spliced(&self, name: &str) -> Option<&'a FnDef> {
+        self.defs.get(name).copied().filter(|f| f.attrs.inline || f.attrs.macro_ || f.attrs.ignore)
+    }
+
+    fn spliced_call(&self, callee: &Callee) -> Option<&'a FnDef> {
+        match callee {
+            Callee::Named(name) => self.spliced(name),
+            Callee::Extern(_) | Callee::Sys(_) | Callee::Addr(_) => None,
+        }
+    }
+
+    // whether the statement has to turn into more than one
+    fn expands(&self, stmt: &Stmt) -> bool {
+        let mut found = false;
+        stmt.walk(&mut |s| {
+            found |= matches!(&s.kind, StmtKind::Call(callee, _) if self.spliced_call(callee).is_some());
+            for expr in s.exprs() {
+                expr.walk(&mut |e| found |= matches!(&e.kind, ExprKind::Call(callee, _) if self.spliced_call(callee).is_some()));
+            }
+        });
+        found
+    }
+
+    fn label(&mut self, kind: &str) -> Name {
+        self.splices += 1;
+        format!("{}.{}", kind, self.splices)
+    }
+
+    fn emit(&mut self, stmt: Stmt, out: &mut Vec<Stmt>) {
+        let span = stmt.span;
+        let kind = match stmt.kind {
+            StmtKind::StackAssign(name, ty, value) => StmtKind::StackAssign(name, ty, self.hoist(value, out)),
+            StmtKind::RegAssign(name, reg, value) => StmtKind::RegAssign(name, reg, self.hoist(value, out)),
+            StmtKind::Mutate(target, op, value) => {
+                let target = self.hoist(target, out);
+                StmtKind::Mutate(target, op, self.hoist(value, out))
+            },
+            StmtKind::Ret(value) => StmtKind::Ret(value.map(|v| self.hoist(v, out))),
+            StmtKind::Call(callee, args) => {
+                let callee = self.hoist_callee(callee, out);
+                StmtKind::Call(callee, args.into_iter().map(|a| self.hoist(a, out)).collect())
+            },
+            StmtKind::Cond(cond, then, otherwise) => StmtKind::Cond(self.hoist(cond, out), then, otherwise),
+            StmtKind::JmpTo(value) => StmtKind::JmpTo(self.hoist(value, out)),
+            StmtKind::End(code) => StmtKind::End(self.hoist(code, out)),
+            StmtKind::Switch(index, labels, otherwise) => StmtKind::Switch(self.hoist(index, out), labels, otherwise),
+            StmtKind::Push(value, ty) => StmtKind::Push(self.hoist(value, out), ty),
+            StmtKind::Pop(target) => StmtKind::Pop(self.hoist(target, out)),
+            StmtKind::Peek(target) => StmtKind::Peek(self.hoist(target, out)),
+            kind => kind,
+        };
+
+        match kind {
+            StmtKind::Call(callee, args) if self.spliced_call(&callee).is_some() => {
+                self.splice(self.spliced_call(&callee).unwrap(), args, span, out)
+            },
+
+            // an arm can only hold one statement, so the then arm is moved behind a label
+            StmtKind::Cond(cond, then, otherwise) if self.expands(&then) || otherwise.as_ref().is_some_and(|o| self.expands(o)) => {
+                let then_label = self.label("then");
+                let end = self.label("endif");
+                let jmp = |label: &Name| Stmt {
+                    kind: StmtKind::Jmp(label.clone()),
+                    span,
+                };
+                let label = |name: Name| Stmt {
+                    kind: StmtKind::Label(name),
+                    span,
+                };
+
+                out.push(Stmt {
+                    kind: StmtKind::Cond(cond, Box::new(jmp(&then_label)), None),
+                    span,
+                });
+                if let Some(otherwise) = otherwise {
+                    self.emit(*otherwise, out);
+                }
+                out.push(jmp(&end));
+                out.push(label(then_label));
+                self.emit(*then, out);
+                out.push(label(end));
+            },
+
+            kind => out.push(Stmt {
+                kind,
+                span,
+            }),
+        }
+    }
+
+    // splices the calls within an expression in front of its statement, left to right
+    fn hoist(&mut self, expr: Expr, out: &mut Vec<Stmt>) -> Expr {
+        let span = expr.span;
+        let kind = match expr.kind {
+            ExprKind::Call(callee, args) => {
+                let callee = self.hoist_callee(callee, out);
+                let args = args.into_iter().map(|a| self.hoist(a, out)).collect::<Vec<_>>();
+                let Some(f) = self.spliced_call(&callee) else {
+                    return Expr {
+                        kind: ExprKind::Call(callee, args),
+                        span,
+                    };
+                };
+                let Some(ty) = f.ret.clone() else {
+                    Log::new(ERR, span, format!("`{}` doesn't return a value", f.name), format!("Defined as {}", f.signature())).push();
+                    return Expr {
+                        kind: ExprKind::Int(0),
+                        span,
+                    };
+                };
+
+                let r0 = |size| Expr {
+                    kind: ExprKind::Register(Register { num: 0, size }),
+                    span,
+                };
+                let saved = self.label(&format!("{}.r0", f.name));
+                out.push(Stmt {
+                    kind: StmtKind::StackAssign(saved.clone(), Type::Size(8), r0(RegSize::QWord)),
+                    span,
+                });
+                self.splice(f, args, span, out);
+
+                let size = match ty {
+                    Type::Size(1) | Type::Signed(1) => RegSize::ByteLow,
+                    Type::Size(2) | Type::Signed(2) => RegSize::Word,
+                    Type::Size(4) | Type::Signed(4) => RegSize::DWord,
+                    _ => RegSize::QWord,
+                };
+                let result = self.label(&format!("{}.result", f.name));
+                out.push(Stmt {
+                    kind: StmtKind::StackAssign(result.clone(), ty, r0(size)),
+                    span,
+                });
+                out.push(Stmt {
+                    kind: StmtKind::Mutate(r0(RegSize::QWord), MutateOp::Set, Expr {
+                        kind: ExprKind::Ident(saved),
+                        span,
+                    }),
+                    span,
+                });
+                ExprKind::Ident(result)
+            },
+            ExprKind::Array(elems) => ExprKind::Array(elems.into_iter().map(|e| self.hoist(e, out)).collect()),
+            ExprKind::ArrIndex(name, index) => ExprKind::ArrIndex(name, Box::new(self.hoist(*index, out))),
+            ExprKind::Deref(addr) => ExprKind::Deref(Box::new(self.hoist(*addr, out))),
+            ExprKind::Not(inner) => ExprKind::Not(Box::new(self.hoist(*inner, out))),
+            ExprKind::Binary(op, lhs, rhs) => {
+                let lhs = self.hoist(*lhs, out);
+                ExprKind::Binary(op, Box::new(lhs), Box::new(self.hoist(*rhs, out)))
+            },
+            kind => kind,
+        };
+        Expr {
+            kind,
+            span,
+        }
+    }
+
+    fn hoist_callee(&mut self, callee: Callee, out: &mut Vec<Stmt>) -> Callee {
+        match callee {
+            Callee::Addr(addr) => Callee::Addr(Box::new(self.hoist(*addr, out))),
+            named => named,
+        }
+    }
+
+    fn splice(&mut self, f: &'a FnDef, args: Vec<Expr>, span: Span, out: &mut Vec<Stmt>) {
+        if f.attrs.ignore {
+            Log::new(ERR, span, format!("`{}` is ignored", f.name), "Functions marked |ignore| aren't compiled").push();
+            return;
+        }
+        if self.stack.contains(&f.name) {
+            Log::new(ERR, span, format!("`{}` is spliced into itself", f.name), "|inline| and |macro| functions can't recurse").push();
+            return;
+        }
+        if args.len() != f.params.len() {
+            Log::new(ERR, span, format!("`{}` takes {} arguments, but {} were given", f.name, f.params.len(), args.len()),
+                format!("Defined as {}", f.signature())).push();
+            return;
+        }
+
+        self.splices += 1;
+        let body = &self.program.stmts[f.stmts.start + 1..f.stmts.end];
+        let mut labels = body.iter()
+            .filter_map(|s| match &s.kind {
+                StmtKind::Label(name) => Some(name.clone()),
+                _ => None,
+            })
+            .collect::<HashSet<_>>();
+        labels.insert(f.name.clone());
+        let renamer = Renamer {
+            prefix: format!("{}.{}", f.name, self.splices),
+            func: &f.name,
+            macro_args: match f.attrs.macro_ {
+                true => f.params.iter().map(|p| p.name.clone()).zip(args.iter().cloned()).collect(),
+                false => HashMap::new(),
+            },
+            labels,
+        };
+
+        if f.attrs.inline {
+            for (param, arg) in f.params.iter().zip(args) {
+                self.emit(Stmt {
+                    kind: StmtKind::StackAssign(renamer.var(&param.name), param.ty.clone(), arg),
+                    span,
+                }, out);
+            }
+        }
+
+        // `ret` sets r0 and leaves, returns from within conditionals go through a trampoline
+        let end = renamer.label("end");
+        let mut stmts = vec![Stmt {
+            kind: StmtKind::Label(renamer.label(&f.name)),
+            span,
+        }];
+        let mut trampolines = Vec::new();
+        for stmt in body {
+            let stmt = renamer.stmt(stmt);
+            match stmt.kind {
+                StmtKind::Ret(value) => stmts.extend(leave(value, &end, stmt.span)),
+                _ => stmts.push(renamer.rets(stmt, &end, &mut trampolines)),
+            }
+        }
+        if trampolines.is_empty() && matches!(stmts.last(), Some(Stmt { kind: StmtKind::Jmp(l), .. }) if *l == end) {
+            stmts.pop();
+        }
+        stmts.extend(trampolines);
+        stmts.push(Stmt {
+            kind: StmtKind::Label(end),
+            span,
+        });
+
+        self.stack.push(f.name.clone());
+        for stmt in stmts {
+            self.emit(stmt, out);
+        }
+        self.stack.pop();
+    }
+}
+
+// `ret value` within a spliced body
+fn leave(value: Option<Expr>, end: &str, span: Span) -> Vec<Stmt> {
+    let mut stmts = Vec::new();
+    if let Some(value) = value {
+        let r0 = Expr {
+            kind: ExprKind::Register(Register {
+                num: 0,
+                size: RegSize::QWord,
+            }),
+            span,
+        };
+        stmts.push(Stmt {
+            kind: StmtKind::Mutate(r0, MutateOp::Set, value),
+            span,
+        });
+    }
+    stmts.push(Stmt {
+        kind: StmtKind::Jmp(end.to_string()),
+        span,
+    });
+    stmts
+}
+
+struct Renamer<'f> {
+    prefix: String,
+    func: &'f str,
+    macro_args: HashMap<Name, Expr>,
+    labels: HashSet<Name>, // defined within the body, jumps elsewhere stay as they are
+}
+
+impl Renamer<'_> {
+    fn var(&self, name: &str) -> Name { format!("{}.{}", self.prefix, name) }
+
+    fn label(&self, name: &str) -> Name { format!("{}.{}", self.prefix, name) }
+
+    fn jump_target(&self, name: &str) -> Name {
+        match self.labels.contains(name) {
+            true => self.label(name),
+            false => name.to_string(),
+        }
+    }
+
+    // a variable that gets indexed, which a macro argument can only be if it is a variable too
+    fn indexed(&self, name: &str, span: Span) -> Name {
+        match self.macro_args.get(name).map(|arg| &arg.kind) {
+            None => self.var(name),
+            Some(ExprKind::Ident(arg)) => arg.clone(),
+            Some(_) => {
+                Log::new(ERR, span, format!("`{}` is indexed within macro `{}`", name, self.func),
+                    "Its argument has to be a variable").push();
+                self.var(name)
+            },
+        }
+    }
+
+    fn stmt(&self, stmt: &Stmt) -> Stmt {
+        let kind = match &stmt.kind {
+            StmtKind::Label(name) => StmtKind::Label(self.label(name)),
+            StmtKind::StackAssign(name, ty, value) => StmtKind::StackAssign(self.var(name), ty.clone(), self.expr(value)),
+            StmtKind::RegAssign(name, reg, value) => StmtKind::RegAssign(self.var(name), *reg, self.expr(value)),
+            StmtKind::Mutate(target, op, value) => StmtKind::Mutate(self.expr(target), *op, self.expr(value)),
+            StmtKind::Jmp(label) => StmtKind::Jmp(self.jump_target(label)),
+            StmtKind::JmpTo(value) => StmtKind::JmpTo(self.expr(value)),
+            StmtKind::End(code) => StmtKind::End(self.expr(code)),
+            StmtKind::Switch(index, labels, otherwise) => StmtKind::Switch(
+                self.expr(index),
+                labels.iter().map(|l| self.jump_target(l)).collect(),
+                otherwise.as_ref().map(|o| self.jump_target(o)),
+            ),
+            StmtKind::Ret(value) => StmtKind::Ret(value.as_ref().map(|v| self.expr(v))),
+            StmtKind::Call(callee, args) => StmtKind::Call(self.callee(callee), args.iter().map(|a| self.expr(a)).collect()),
+            StmtKind::Cond(cond, then, otherwise) => StmtKind::Cond(
+                self.expr(cond),
+                Box::new(self.stmt(then)),
+                otherwise.as_ref().map(|o| Box::new(self.stmt(o))),
+            ),
+            StmtKind::Push(value, ty) => StmtKind::Push(self.expr(value), ty.clone()),
+            StmtKind::Pop(target) => StmtKind::Pop(self.expr(target)),
+            StmtKind::Peek(target) => StmtKind::Peek(self.expr(target)),
+        };
+        Stmt {
+            kind,
+            span: stmt.span,
+        }
+    }
+
+    // replaces `ret` arms by jumps to trampolines that do the returning
+    fn rets(&self, stmt: Stmt, end: &str, trampolines: &mut Vec<Stmt>) -> Stmt {
+        let kind = match stmt.kind {
+            StmtKind::Ret(value) => {
+                let label = self.label(&format!("ret{}", trampolines.len()));
+                trampolines.push(Stmt {
+                    kind: StmtKind::Label(label.clone()),
+                    span: stmt.span,
+                });
+                trampolines.extend(leave(value, end, stmt.span));
+                StmtKind::Jmp(label)
+            },
+            StmtKind::Cond(cond, then, otherwise) => StmtKind::Cond(
+                cond,
+                Box::new(self.rets(*then, end, trampolines)),
+                otherwise.map(|o| Box::new(self.rets(*o, end, trampolines))),
+            ),
+            kind => kind,
+        };
+        Stmt {
+            kind,
+            span: stmt.span,
+        }
+    }
+
+    fn expr(&self, expr: &Expr) -> Expr {
+        let kind = match &expr.kind {
+            ExprKind::Ident(name) => match self.macro_args.get(name) {
+                Some(arg) => return arg.clone(),
+                None => ExprKind::Ident(self.var(name)),
+            },
+            ExprKind::ArrIndex(name, index) => ExprKind::ArrIndex(self.indexed(name, expr.span), Box::new(self.expr(index))),
+            ExprKind::StructIndex(name, field) => ExprKind::StructIndex(self.indexed(name, expr.span), field.clone()),
+            ExprKind::Array(elems) => ExprKind::Array(elems.iter().map(|e| self.expr(e)).collect()),
+            ExprKind::Deref(addr) => ExprKind::Deref(Box::new(self.expr(addr))),
+            ExprKind::Not(inner) => ExprKind::Not(Box::new(self.expr(inner))),
+            ExprKind::Binary(op, lhs, rhs) => ExprKind::Binary(*op, Box::new(self.expr(lhs)), Box::new(self.expr(rhs))),
+            ExprKind::Call(callee, args) => ExprKind::Call(self.callee(callee), args.iter().map(|a| self.expr(a)).collect()),
+            ExprKind::LabelAddr(label) => ExprKind::LabelAddr(self.jump_target(label)),
+            kind => kind.clone(),
+        };
+        Expr {
+            kind,
+            span: expr.span,
+        }
+    }
+
+    fn callee(&self, callee: &Callee) -> Callee {
+        match callee {
+            Callee::Addr(addr) => Callee::Addr(Box::new(self.expr(addr))),
+            callee => callee.clone(),
+        }
+    }
+}
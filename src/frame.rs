@@ -0,0 +1,423 @@
+use std::collections::{HashMap, VecDeque};
+use std::ops::Range;
+
+use crate::args_parser::ARGS;
+use crate::ast::*;
+use crate::location::Span;
+use crate::logger::{Log, ERR, WARN};
+use crate::typeck::Types;
+
+// Besides `@` definitions Shard has no function boundaries, only labels. An `@` function owns
+// the labels within its body. Otherwise a function is the entry label (or any label nothing
+// else reaches) together with every label reachable from it through fallthrough and jumps.
+// All `%` variables and parameters of a function share one stack frame, so a variable declared
+// after `loop:` is just as visible before it.
+
+pub struct Layout {
+    pub blocks: Vec<Block>,
+    pub functions: Vec<Function>,
+    pub labels: HashMap<Name, usize>, // label -> block
+    pub taken: Vec<usize>,            // blocks whose address `&label` takes, where `jmp EXPR` may go
+}
+
+// a label and the statements up to the next one
+pub struct Block {
+    pub label: Name,
+    pub stmts: Range<usize>, // into Program::stmts, without the label itself
+    pub func: usize,
+    pub succs: Vec<usize>, // the blocks jumped or fallen through to
+    pub depth: u64,        // bytes pushed by `^` when the block is entered, see track_pushes
+}
+
+pub struct Function {
+    pub name: Name,
+    pub frame: Frame,
+    pub def: Option<usize>, // into Program::functions, None for plain labels
+}
+
+pub struct Frame {
+    pub slots: Vec<Slot>,
+    pub regs: Vec<(Name, RegVar)>,
+    pub size: u64, // bytes reserved below the saved rbp, or below the return address without one
+}
+
+pub struct Slot {
+    pub name: Name,
+    pub ty: Type,
+    pub size: u64,
+    pub offset: u64, // the slot lives at [rbp - offset]
+    pub span: Span,  // the first declaration
+}
+
+impl Frame {
+    pub fn slot(&self, name: &str) -> Option<&Slot> { self.slots.iter().find(|s| s.name == name) }
+
+    pub fn reg(&self, name: &str) -> Option<RegVar> {
+        self.regs.iter().find(|(n, _)| n == name).map(|(_, r)| *r)
+    }
+
+    fn declare_slot(&mut self, name: &Name, ty: &Type, span: Span) {
+        if self.reg(name).is_some() {
+            Log::new(ERR, span, format!("`{}` is already a register variable", name), "").push();
+            return;
+        }
+        match self.slot(name) {
+            Some(slot) if slot.ty != *ty => {
+                Log::new(ERR, span, format!("Variable `{}` redeclared with a different type", name),
+                    format!("Previously declared as {}", slot.ty)).push();
+            },
+            Some(_) => (),
+            None => self.slots.push(Slot {
+                name: name.clone(),
+                ty: ty.clone(),
+                size: 0,
+                offset: 0,
+                span,
+            }),
+        }
+    }
+
+    fn declare_reg(&mut self, name: &Name, reg: RegVar, stmt: &Stmt) {
+        if self.slot(name).is_some() {
+            Log::new(ERR, stmt.span, format!("`{}` is already a stack variable", name), "").push();
+            return;
+        }
+        match self.reg(name) {
+            Some(r) if r.reg != reg.reg => {
+                Log::new(ERR, stmt.span, format!("Register variable `{}` rebound to another register", name),
+                    format!("Previously bound to r{}", r.reg.num)).push();
+            },
+            Some(r) if r.signed != reg.signed => {
+                Log::new(ERR, stmt.span, format!("Register variable `{}` redeclared with a different sign", name), "").push();
+            },
+            Some(_) => (),
+            None => self.regs.push((name.clone(), reg)),
+        }
+    }
+
+    // assigns naturally aligned offsets in declaration order and rounds the frame so rsp stays
+    // 16 byte aligned after the prologue, as the System V ABI expects at every call
+    fn assign_offsets(&mut self, types: &Types) {
+        let mut top = 0;
+        for slot in &mut self.slots {
+            slot.size = types.size_of(&slot.ty);
+            top = align_up(top + slot.size, types.align_of(&slot.ty));
+            slot.offset = top;
+        }
+        self.round(top);
+    }
+
+    // an 8 byte slot below the others for a register regalloc.rs spilled, named `.spill<n>`,
+    // handing back its offset
+    pub fn spill_slot(&mut self, name: Name, span: Span) -> u64 {
+        if let Some(slot) = self.slot(&name) {
+            return slot.offset;
+        }
+        let top = self.slots.iter().map(|s| s.offset).max().unwrap_or(0);
+        let offset = align_up(top + 8, 8);
+        self.slots.push(Slot {
+            name,
+            ty: Type::Size(8),
+            size: 8,
+            offset,
+            span,
+        });
+        self.round(offset);
+        offset
+    }
+
+    // the frame's size for slots reaching `top` bytes down
+    fn round(&mut self, top: u64) {
+        self.size = match (top, unsafe { ARGS.omit_fp }) {
+            (0, _) => 0,
+            (top, false) => align_up(top, 16),
+            // the return address leaves rsp 8 bytes off, and there's no pushed rbp to fix it
+            (top, true) => align_up(top + 8, 16) - 8,
+        };
+    }
+}
+
+pub fn layout(program: &Program) -> Layout {
+    let (blocks, labels) = split_blocks(program);
+    let mut layout = Layout {
+        blocks,
+        functions: Vec::new(),
+        labels,
+        taken: Vec::new(),
+    };
+
+    let mut taken = Vec::new();
+    for stmt in &program.stmts {
+        stmt.walk(&mut |stmt| {
+            for expr in stmt.exprs() {
+                expr.walk(&mut |expr| {
+                    if let ExprKind::LabelAddr(label) = &expr.kind {
+                        taken.extend(layout.labels.get(label));
+                    }
+                });
+            }
+        });
+    }
+    taken.sort();
+    taken.dedup();
+    layout.taken = taken;
+    let succs = (0..layout.blocks.len())
+        .map(|i| successors(program, &layout, i))
+        .collect::<Vec<_>>();
+
+    // the entry gets claimed first, every leftover label then starts a function of its own
+    let entry = program.entry.as_deref().unwrap_or("main");
+    let mut roots = (0..layout.blocks.len()).collect::<Vec<_>>();
+    match layout.labels.get(entry) {
+        Some(&i) => {
+            roots.retain(|&r| r != i);
+            roots.insert(0, i);
+        },
+        None if program.entry.is_some() => {
+            Log::new(ERR, None, format!("Entry label `{}` is never defined", entry), "").push();
+        },
+        None => (),
+    }
+
+    // `@` functions own their bodies, their parameters come first in the frame
+    let mut owner = vec![None; layout.blocks.len()];
+    for (def, f) in program.functions.iter().enumerate() {
+        let func = layout.functions.len();
+        let mut frame = Frame {
+            slots: Vec::new(),
+            regs: Vec::new(),
+            size: 0,
+        };
+        for param in &f.params {
+            frame.declare_slot(&param.name, &param.ty, param.span);
+        }
+        layout.functions.push(Function {
+            name: f.name.clone(),
+            frame,
+            def: Some(def),
+        });
+        for (i, block) in layout.blocks.iter().enumerate() {
+            if f.stmts.contains(&(block.stmts.start - 1)) {
+                owner[i] = Some(func);
+            }
+        }
+    }
+
+    for root in roots {
+        if owner[root].is_some() {
+            continue;
+        }
+        let func = layout.functions.len();
+        layout.functions.push(Function {
+            name: layout.blocks[root].label.clone(),
+            frame: Frame {
+                slots: Vec::new(),
+                regs: Vec::new(),
+                size: 0,
+            },
+            def: None,
+        });
+
+        let mut queue = VecDeque::from([root]);
+        while let Some(block) = queue.pop_front() {
+            if owner[block].is_some() {
+                continue;
+            }
+            owner[block] = Some(func);
+            queue.extend(succs[block].iter().copied().filter(|&s| owner[s].is_none()));
+        }
+    }
+
+    // variables are collected in source order, no matter how the blocks were reached
+    for ((block, func), succs) in layout.blocks.iter_mut().zip(owner).zip(succs) {
+        block.func = func.unwrap();
+        block.succs = succs;
+        let frame = &mut layout.functions[block.func].frame;
+        for stmt in &program.stmts[block.stmts.clone()] {
+            stmt.walk(&mut |stmt| match &stmt.kind {
+                StmtKind::StackAssign(name, ty, _) => frame.declare_slot(name, ty, stmt.span),
+                StmtKind::RegAssign(name, reg, _) => frame.declare_reg(name, *reg, stmt),
+                _ => (),
+            });
+        }
+    }
+
+    layout
+}
+
+impl Layout {
+    // needs the data blocks and array lengths from the type checker
+    pub fn assign_offsets(&mut self, types: &Types) {
+        for func in &mut self.functions {
+            func.frame.assign_offsets(types);
+        }
+    }
+
+    // `^` pushes stay on the stack across statements, and rsp relative variables and call
+    // alignment depend on how many bytes they hold. That has to be known statically, so every
+    // label must be reached with the same depth and pushes and pops can't be conditional.
+    // A `ret` drops what's still pushed, which is most likely a mistake
+    pub fn track_pushes(&mut self, program: &Program, types: &Types) {
+        let mut entry = vec![None; self.blocks.len()];
+        let roots = self.functions.iter().filter_map(|f| self.labels.get(&f.name).copied());
+
+        for root in roots.chain(0..self.blocks.len()) {
+            if entry[root].is_some() {
+                continue;
+            }
+            entry[root] = Some(0);
+            let mut queue = VecDeque::from([root]);
+            while let Some(i) = queue.pop_front() {
+                let block = &self.blocks[i];
+                let frame = &self.functions[block.func].frame;
+                let mut depth = entry[i].unwrap();
+                let mut edges = Vec::new();
+                for stmt in &program.stmts[block.stmts.clone()] {
+                    track(stmt, &mut depth, false, frame, types, &mut |stmt, depth| {
+                        let targets: Vec<usize> = match &stmt.kind {
+                            StmtKind::Jmp(label) => self.labels.get(label).copied().into_iter().collect(),
+                            StmtKind::Switch(_, labels, otherwise) => labels.iter().chain(otherwise).filter_map(|l| self.labels.get(l).copied()).collect(),
+                            _ => self.taken.clone(),
+                        };
+                        edges.extend(targets.into_iter().map(|to| (to, depth, stmt.span)));
+                    });
+                }
+                let falls_through = !program.stmts[block.stmts.clone()].last().is_some_and(Stmt::diverges);
+                if falls_through && i + 1 < self.blocks.len() {
+                    let label = &program.stmts[self.blocks[i + 1].stmts.start - 1];
+                    edges.push((i + 1, depth, label.span));
+                }
+
+                for (to, depth, span) in edges {
+                    if self.blocks[to].func != block.func {
+                        continue;
+                    }
+                    match entry[to] {
+                        None => {
+                            entry[to] = Some(depth);
+                            queue.push_back(to);
+                        },
+                        Some(known) if known != depth => {
+                            Log::new(WARN, span, format!("`{}` is reached with {} bytes pushed, but also with {}", self.blocks[to].label, depth, known),
+                                format!("Its variables and calls assume {}", known)).push();
+                        },
+                        Some(_) => (),
+                    }
+                }
+            }
+        }
+
+        for (block, depth) in self.blocks.iter_mut().zip(entry) {
+            block.depth = depth.unwrap_or(0);
+        }
+    }
+}
+
+// follows `^` pushes and pops through a statement, `jump` is told about every jump it makes
+fn track(stmt: &Stmt, depth: &mut u64, arm: bool, frame: &Frame, types: &Types, jump: &mut impl FnMut(&Stmt, u64)) {
+    match &stmt.kind {
+        StmtKind::Push(..) | StmtKind::Pop(_) if arm => {
+            Log::new(ERR, stmt.span, "Pushes and pops can't be conditional",
+                "The stack has to be equally deep whichever way the condition goes, jump to a label that does it").push();
+        },
+        StmtKind::Push(_, ty) => *depth += types.size_of(ty),
+        StmtKind::Pop(target) => {
+            let width = types.width(frame, target).unwrap_or(8);
+            if width > *depth {
+                Log::new(WARN, stmt.span, format!("Popping {} bytes with only {} pushed", width, depth),
+                    "The rest comes off the stack frame, or what's above it").push();
+            }
+            *depth = depth.saturating_sub(width);
+        },
+        StmtKind::Ret(_) if *depth > 0 => {
+            Log::new(WARN, stmt.span, format!("Returning with {} bytes still pushed", depth), "They are dropped, pop them first").push();
+        },
+        StmtKind::Jmp(_) | StmtKind::JmpTo(_) | StmtKind::Switch(..) => jump(stmt, *depth),
+        StmtKind::Cond(_, then, otherwise) => {
+            track(then, depth, true, frame, types, jump);
+            if let Some(otherwise) = otherwise {
+                track(otherwise, depth, true, frame, types, jump);
+            }
+        },
+        _ => (),
+    }
+}
+
+fn split_blocks(program: &Program) -> (Vec<Block>, HashMap<Name, usize>) {
+    let mut blocks: Vec<Block> = Vec::new();
+    let mut labels = HashMap::new();
+
+    for (i, stmt) in program.stmts.iter().enumerate() {
+        match &stmt.kind {
+            StmtKind::Label(name) => {
+                if labels.insert(name.clone(), blocks.len()).is_some() {
+                    Log::new(ERR, stmt.span, format!("Label `{}` already defined", name), "").push();
+                }
+                blocks.push(Block {
+                    label: name.clone(),
+                    stmts: i + 1..i + 1,
+                    func: 0,
+                    succs: Vec::new(),
+                    depth: 0,
+                });
+            },
+            _ => match blocks.last_mut() {
+                Some(block) => block.stmts.end = i + 1,
+                None => Log::new(ERR, stmt.span, "Statement outside of a label", "Add a label before it").push(),
+            },
+        }
+    }
+
+    (blocks, labels)
+}
+
+// whether `name` is declared as a variable or parameter anywhere
+fn is_variable(program: &Program, name: &str) -> bool {
+    let mut found = program.functions.iter().any(|f| f.params.iter().any(|p| p.name == name));
+    for stmt in &program.stmts {
+        stmt.walk(&mut |stmt| {
+            if let StmtKind::StackAssign(var, ..) | StmtKind::RegAssign(var, ..) = &stmt.kind {
+                found |= var == name;
+            }
+        });
+    }
+    found
+}
+
+// a computed jump may go to any label whose address is taken
+fn successors(program: &Program, layout: &Layout, block: usize) -> Vec<usize> {
+    let stmts = &program.stmts[layout.blocks[block].stmts.clone()];
+    let mut succs = Vec::new();
+
+    for stmt in stmts {
+        stmt.walk(&mut |stmt| {
+            let targets = match &stmt.kind {
+                StmtKind::Jmp(target) => vec![target],
+                StmtKind::Switch(_, labels, otherwise) => labels.iter().chain(otherwise).collect(),
+                StmtKind::JmpTo(_) => {
+                    succs.extend(&layout.taken);
+                    Vec::new()
+                },
+                _ => Vec::new(),
+            };
+            for target in targets {
+                match layout.labels.get(target) {
+                    Some(&i) => succs.push(i),
+                    None if is_variable(program, target) => {
+                        Log::new(ERR, stmt.span, format!("Unknown label `{}`", target),
+                            format!("`{0}` is a variable, use `jmp ({0})` to jump to an address held in it", target)).push();
+                    },
+                    None => Log::new(ERR, stmt.span, format!("Unknown label `{}`", target), "").push(),
+                }
+            }
+        });
+    }
+
+    let falls_through = !stmts.last().is_some_and(Stmt::diverges);
+    if falls_through && block + 1 < layout.blocks.len() {
+        succs.push(block + 1);
+    }
+    succs
+}
+
+pub fn align_up(n: u64, align: u64) -> u64 { n.div_ceil(align) * align }
@@ -0,0 +1,1134 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::{self, Display, Formatter};
+
+use crate::ast::Name;
+use crate::location::Span;
+use crate::logger::{Log, ERR};
+use crate::regalloc::SCRATCH;
+
+// The IR between the AST and the assembly. It's as low level as the assembly, with two operand
+// instructions on sized registers and memory, but knows nothing about x86_64: registers are
+// virtual, variables live in an abstract frame and calls say what they call rather than how.
+// A function is a list of basic blocks, each of which ends in its only jump, return or branch.
+// A block that doesn't end in a jump falls through to the next one.
+//
+// Registers are written like Shard's, `r3d` is the low 4 bytes of r3 and `t0` the first temporary.
+// Writing 4 bytes of a register clears the upper ones, writing 1 or 2 bytes keeps them.
+//
+// `^` pushes, spills and call arguments go onto the stack, whose depth at every instruction is
+// known statically. A call pops its arguments, the first one was pushed first.
+
+// r0 to r255 are Shard's registers, the ones after them the compiler's temporaries
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct VReg(pub u16);
+
+// expressions are evaluated into ACC, TMP holds the right hand side of binary operators
+pub const ACC: VReg = VReg(256);
+pub const TMP: VReg = VReg(257);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Reg(VReg, u8), // 1 | 2 | 4 | 8 bytes of it
+    Mem(Mem),
+    Imm(i64),
+}
+
+// size bytes at [base + index*scale + disp]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mem {
+    pub size: u8,
+    pub base: Base,
+    pub index: Option<(VReg, u8)>,
+    pub disp: i64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Base {
+    None,
+    Reg(VReg),
+    Frame,     // the variables of the function sit below it, [frame - offset]
+    Stack,     // whatever was pushed last, [stack + 0]
+    Sym(Name), // a label, string or constant array
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Cond {
+    Eq,
+    Ne,
+    Ult,
+    Ule,
+    Ugt,
+    Uge,
+    Slt,
+    Sle,
+    Sgt,
+    Sge,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    And,
+    Or,
+    Xor,
+    Shl, // shifts count with an immediate or the low byte of a register
+    Shr,
+    Sar,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnOp {
+    Not,
+    Inc,
+    Dec,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Callee {
+    Native(Name),       // preserves every register but the one it returns in
+    SysV(Name),         // the entry and |sysv| functions
+    Extern(Name, bool), // `$` functions, true when variadic or undeclared
+    Addr(VReg),         // `<- addr`, called like System V says
+    Sys(Name),          // a system call
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Inst {
+    Mov(Operand, Operand),
+    Ext(Operand, Operand, bool), // a 4 or 8 byte register = a smaller value, sign extended if true
+    Addr(Operand, Mem),          // an 8 byte register = the address of mem
+    Bin(BinOp, Operand, Operand),
+    Un(UnOp, Operand),
+    Div(VReg, VReg, bool, bool), // dst = dst / src of 8 bytes, (signed, remainder instead)
+    Set(Cond, Operand, Operand, Operand), // a byte = 1 if lhs cond rhs, else 0
+    Param(Mem, usize),           // stores the nth argument of the function into its variable
+    Push(Operand),
+    Pop(Operand),                // as many bytes as the operand has
+    Fill(Mem, VReg, u64),        // n elements of mem.size bytes from mem on = the register
+    Call(Callee, usize, VReg),   // pops n arguments and puts the result into the register
+
+    // only at the end of a block
+    Branch(Cond, Operand, Operand, Name), // falls through unless lhs cond rhs
+    Jmp(Name),
+    JmpTo(Operand),                // to any label whose address is taken
+    Switch(VReg, Vec<Name>, Name), // to the nth label, or the last one when out of range
+    Ret(Option<Operand>),
+    Exit(Operand), // ends the process with a 4 byte status
+    Trap,
+}
+
+// how control gets from one block to another
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Edge {
+    Fall, // into the block below
+    Jump,
+    Computed, // a `jmp EXPR`, which may go to any label whose address is taken
+}
+
+pub struct Block {
+    pub label: Name,
+    pub insts: Vec<Inst>,
+    pub depth: Option<u64>, // what's pushed when a Shard label is entered, see Layout::track_pushes
+    pub stmts: Vec<(usize, Span)>, // the statements starting in it and the instruction each starts at
+}
+
+pub struct Function {
+    pub name: Name,
+    pub func: usize, // into Layout::functions
+    pub sysv: bool,
+    pub notail: bool,
+    pub blocks: Vec<Block>,
+}
+
+// an 8 byte word of a constant array
+#[derive(Debug, Clone, PartialEq)]
+pub enum Word {
+    Int(i64),
+    Label(Name),
+}
+
+pub struct Program {
+    pub functions: Vec<Function>,
+    pub data: Vec<(Name, Vec<Word>)>,
+    pub strings: Vec<String>, // .Lstr0 and so on
+    pub taken: Vec<Name>,     // labels whose address is taken, where JmpTo may go
+    pub entry: Option<Name>,  // the function the program starts in, if it has one
+}
+
+impl VReg {
+    pub fn sized(self, size: u8) -> Operand { Operand::Reg(self, size) }
+
+    pub fn q(self) -> Operand { Operand::Reg(self, 8) }
+
+    pub fn is_temp(self) -> bool { self.0 > 255 }
+}
+
+impl Operand {
+    pub fn size(&self) -> Option<u8> {
+        match self {
+            Operand::Reg(_, size) => Some(*size),
+            Operand::Mem(mem) => Some(mem.size),
+            Operand::Imm(_) => None,
+        }
+    }
+
+    // the registers it reads, as a value or as part of an address
+    pub fn regs(&self) -> Vec<VReg> {
+        match self {
+            Operand::Reg(reg, _) => vec![*reg],
+            Operand::Mem(mem) => mem.regs(),
+            Operand::Imm(_) => Vec::new(),
+        }
+    }
+
+    pub fn map_regs(&mut self, f: &mut impl FnMut(VReg) -> VReg) {
+        match self {
+            Operand::Reg(reg, _) => *reg = f(*reg),
+            Operand::Mem(mem) => mem.map_regs(f),
+            Operand::Imm(_) => (),
+        }
+    }
+}
+
+impl Mem {
+    pub fn frame(size: u8, disp: i64) -> Mem {
+        Mem {
+            size,
+            base: Base::Frame,
+            index: None,
+            disp,
+        }
+    }
+
+    pub fn stack(size: u8, disp: i64) -> Mem {
+        Mem {
+            size,
+            base: Base::Stack,
+            index: None,
+            disp,
+        }
+    }
+
+    pub fn sym(size: u8, label: Name) -> Mem {
+        Mem {
+            size,
+            base: Base::Sym(label),
+            index: None,
+            disp: 0,
+        }
+    }
+
+    pub fn regs(&self) -> Vec<VReg> {
+        let base = match self.base {
+            Base::Reg(reg) => Some(reg),
+            _ => None,
+        };
+        base.into_iter().chain(self.index.map(|(reg, _)| reg)).collect()
+    }
+
+    pub fn map_regs(&mut self, f: &mut impl FnMut(VReg) -> VReg) {
+        if let Base::Reg(reg) = &mut self.base {
+            *reg = f(*reg);
+        }
+        if let Some((reg, _)) = &mut self.index {
+            *reg = f(*reg);
+        }
+    }
+}
+
+impl Cond {
+    pub fn negate(self) -> Cond {
+        match self {
+            Cond::Eq => Cond::Ne,
+            Cond::Ne => Cond::Eq,
+            Cond::Ult => Cond::Uge,
+            Cond::Ule => Cond::Ugt,
+            Cond::Ugt => Cond::Ule,
+            Cond::Uge => Cond::Ult,
+            Cond::Slt => Cond::Sge,
+            Cond::Sle => Cond::Sgt,
+            Cond::Sgt => Cond::Sle,
+            Cond::Sge => Cond::Slt,
+        }
+    }
+}
+
+impl Inst {
+    // control never reaches the instruction after it
+    pub fn is_terminator(&self) -> bool {
+        matches!(self, Inst::Jmp(_) | Inst::JmpTo(_) | Inst::Switch(..) | Inst::Ret(_) | Inst::Exit(_) | Inst::Trap)
+    }
+
+    pub fn ends_block(&self) -> bool { self.is_terminator() || matches!(self, Inst::Branch(..)) }
+
+    // how far it moves the stack
+    pub fn stack_effect(&self) -> i64 {
+        match self {
+            Inst::Push(Operand::Imm(_)) => 8,
+            Inst::Push(op) => op.size().unwrap_or(8) as i64,
+            Inst::Pop(op) => -(op.size().unwrap_or(8) as i64),
+            Inst::Call(_, args, _) => -8 * *args as i64,
+            _ => 0,
+        }
+    }
+}
+
+impl Block {
+    pub fn new(label: Name) -> Block {
+        Block {
+            label,
+            insts: Vec::new(),
+            depth: None,
+            stmts: Vec::new(),
+        }
+    }
+
+    // from the first statement to the end of the last one, which may be nested in it
+    pub fn span(&self) -> Option<Span> {
+        let (_, first) = self.stmts.first()?;
+        let (_, last) = self.stmts.iter().max_by_key(|(_, s)| (s.2.line, s.2.column))?;
+        Some(first.extend(last))
+    }
+
+    // keeps the instructions `keep` holds on to, moving the statements along with them. Tells
+    // whether any went
+    pub fn retain(&mut self, mut keep: impl FnMut(&Inst) -> bool) -> bool {
+        let before = self.insts.len();
+        let mut moved = Vec::with_capacity(before + 1); // where each instruction ends up
+        let mut insts = Vec::with_capacity(before);
+        for inst in std::mem::take(&mut self.insts) {
+            moved.push(insts.len());
+            if keep(&inst) {
+                insts.push(inst);
+            }
+        }
+        moved.push(insts.len());
+        for (i, _) in &mut self.stmts {
+            *i = moved[*i];
+        }
+        self.insts = insts;
+        self.insts.len() != before
+    }
+
+    // the statement the nth instruction was lowered from
+    pub fn span_at(&self, inst: usize) -> Option<Span> {
+        self.stmts.iter().rev().find(|(i, _)| *i <= inst).map(|(_, s)| *s)
+    }
+
+    // where control goes after it, `next` being the label of the block below
+    pub fn succs(&self, next: Option<&Name>, taken: &[Name]) -> Vec<(Name, Edge)> {
+        let next = next.map(|n| (n.clone(), Edge::Fall)).into_iter();
+        let jump = |label: &Name| (label.clone(), Edge::Jump);
+        match self.insts.last() {
+            Some(Inst::Jmp(label)) => vec![jump(label)],
+            Some(Inst::Branch(.., label)) => [jump(label)].into_iter().chain(next).collect(),
+            Some(Inst::Switch(_, labels, otherwise)) => labels.iter().chain([otherwise]).map(jump).collect(),
+            Some(Inst::JmpTo(_)) => taken.iter().map(|l| (l.clone(), Edge::Computed)).collect(),
+            Some(Inst::Ret(_) | Inst::Exit(_) | Inst::Trap) => Vec::new(),
+            _ => next.collect(),
+        }
+    }
+}
+
+impl Function {
+    pub fn succs(&self, block: usize, taken: &[Name]) -> Vec<(Name, Edge)> {
+        self.blocks[block].succs(self.blocks.get(block + 1).map(|b| &b.label), taken)
+    }
+
+    // the bytes pushed when each block is entered. Shard labels know theirs, the compiler's blocks
+    // inherit them from their predecessors. Also hands back a compiler block that's reached with
+    // two different depths, which would be a bug
+    pub fn depths(&self, taken: &[Name]) -> (Vec<i64>, Option<Name>) {
+        let index = self.blocks.iter().enumerate().map(|(i, b)| (&b.label, i)).collect::<HashMap<_, _>>();
+        let mut depths = self.blocks.iter().map(|b| b.depth.map(|d| d as i64)).collect::<Vec<_>>();
+        depths[0] = Some(depths[0].unwrap_or(0));
+
+        let mut conflict = None;
+        let mut queue = (0..self.blocks.len()).filter(|&i| depths[i].is_some()).collect::<VecDeque<_>>();
+        while let Some(i) = queue.pop_front() {
+            let depth = depths[i].unwrap() + self.blocks[i].insts.iter().map(Inst::stack_effect).sum::<i64>();
+            for (succ, _) in self.succs(i, taken) {
+                // jumps into other functions
+                let Some(&j) = index.get(&succ) else { continue };
+                match depths[j] {
+                    None => {
+                        depths[j] = Some(depth);
+                        queue.push_back(j);
+                    },
+                    Some(d) if d != depth && self.blocks[j].depth.is_none() => conflict = Some(succ),
+                    Some(_) => (),
+                }
+            }
+        }
+        (depths.into_iter().map(|d| d.unwrap_or(0)).collect(), conflict)
+    }
+}
+
+//
+// verifier
+// checks that a program is well formed, between lowering and each pass that changes it. A problem
+// is a bug in the compiler rather than the program, so it's reported as such
+pub fn verify(program: &Program, after: &str, allocated: bool) {
+    for problem in problems(program, allocated) {
+        Log::new(ERR, None, format!("Malformed IR after {}, this is a compiler bug", after), problem).push();
+    }
+}
+
+// what's wrong with the program. Only register allocation hands out SCRATCH, to load spilled
+// registers into
+fn problems(program: &Program, allocated: bool) -> Vec<String> {
+    let mut labels = HashSet::new();
+    let mut problems = Vec::new();
+    for func in &program.functions {
+        for block in &func.blocks {
+            if !labels.insert(block.label.as_str()) {
+                problems.push(format!("`{}` labels two blocks", block.label));
+            }
+        }
+    }
+    for (label, _) in &program.data {
+        labels.insert(label.as_str());
+    }
+    let strings = (0..program.strings.len()).map(|i| format!(".Lstr{}", i)).collect::<Vec<_>>();
+    labels.extend(strings.iter().map(String::as_str));
+
+    for func in &program.functions {
+        if func.blocks.is_empty() {
+            problems.push(format!("`{}` has no blocks", func.name));
+            continue;
+        }
+        if func.blocks.last().and_then(|b| b.insts.last()).is_none_or(|i| !i.is_terminator()) {
+            problems.push(format!("`{}` falls off its end", func.name));
+        }
+        let (_, conflict) = func.depths(&program.taken);
+        if let Some(label) = conflict {
+            problems.push(format!("`{}` is reached with different stack depths", label));
+        }
+
+        for block in &func.blocks {
+            for (i, inst) in block.insts.iter().enumerate() {
+                let mut problem = |msg: &str| problems.push(format!("{}: `{}`: {}", block.label, inst, msg));
+                if inst.ends_block() && i + 1 != block.insts.len() {
+                    problem("a jump in the middle of a block");
+                }
+                for target in inst.targets() {
+                    if !labels.contains(target.as_str()) {
+                        problem("unknown label");
+                    }
+                }
+                if let Err(msg) = check(inst) {
+                    problem(msg);
+                }
+                if !allocated && inst.regs().into_iter().chain(inst.writes()).any(|r| SCRATCH.contains(&r)) {
+                    problem("a scratch register before register allocation");
+                }
+            }
+        }
+    }
+    for label in &program.taken {
+        if !labels.contains(label.as_str()) {
+            problems.push(format!("the address of unknown label `{}` is taken", label));
+        }
+    }
+    problems
+}
+
+impl Inst {
+    // the labels it jumps to or refers to
+    pub fn targets(&self) -> Vec<&Name> {
+        let mut targets = match self {
+            Inst::Branch(.., label) | Inst::Jmp(label) => vec![label],
+            Inst::Switch(_, labels, otherwise) => labels.iter().chain([otherwise]).collect(),
+            _ => Vec::new(),
+        };
+        let mems = self.operands().into_iter().filter_map(|op| match op {
+            Operand::Mem(mem) => Some(mem),
+            _ => None,
+        });
+        let own = match self {
+            Inst::Addr(_, mem) | Inst::Fill(mem, ..) | Inst::Param(mem, _) => Some(mem),
+            _ => None,
+        };
+        for mem in mems.chain(own) {
+            if let Base::Sym(label) = &mem.base {
+                targets.push(label);
+            }
+        }
+        targets
+    }
+
+    // every register it reads or writes, but the one a call returns in
+    pub fn regs(&self) -> Vec<VReg> {
+        let mut regs = self.operands().into_iter().flat_map(Operand::regs).collect::<Vec<_>>();
+        match self {
+            Inst::Addr(_, mem) | Inst::Param(mem, _) => regs.extend(mem.regs()),
+            Inst::Fill(mem, reg, _) => regs.extend(mem.regs().into_iter().chain([*reg])),
+            Inst::Div(dst, src, ..) => regs.extend([*dst, *src]),
+            Inst::Switch(reg, ..) | Inst::Call(Callee::Addr(reg), ..) => regs.push(*reg),
+            _ => (),
+        }
+        regs
+    }
+
+    // the registers whose value it needs. Writing 1 or 2 bytes of a register keeps the rest of
+    // it, so those need it too. A native call also needs whatever its callee reads, which the
+    // instruction doesn't know
+    pub fn uses(&self) -> Vec<VReg> {
+        let written = match self {
+            Inst::Bin(BinOp::Xor, Operand::Reg(a, 4 | 8), Operand::Reg(b, _)) if a == b => return Vec::new(),
+            Inst::Mov(dst @ Operand::Reg(_, 4 | 8), _)
+            | Inst::Ext(dst, ..)
+            | Inst::Addr(dst, _)
+            | Inst::Pop(dst @ Operand::Reg(_, 4 | 8)) => Some(dst),
+            _ => None,
+        };
+        let mut regs = self.operands().into_iter()
+            .flat_map(|op| match op {
+                Operand::Reg(..) if written.is_some_and(|w| std::ptr::eq(w, op)) => Vec::new(),
+                op => op.regs(),
+            })
+            .collect::<Vec<_>>();
+        match self {
+            Inst::Addr(_, mem) | Inst::Param(mem, _) => regs.extend(mem.regs()),
+            Inst::Fill(mem, reg, _) => regs.extend(mem.regs().into_iter().chain([*reg])),
+            Inst::Div(dst, src, ..) => regs.extend([*dst, *src]),
+            Inst::Switch(reg, ..) | Inst::Call(Callee::Addr(reg), ..) => regs.push(*reg),
+            _ => (),
+        }
+        regs
+    }
+
+    // the register it writes, all of it or a part
+    pub fn writes(&self) -> Option<VReg> {
+        match self {
+            Inst::Mov(Operand::Reg(reg, _), _)
+            | Inst::Ext(Operand::Reg(reg, _), ..)
+            | Inst::Addr(Operand::Reg(reg, _), _)
+            | Inst::Bin(_, Operand::Reg(reg, _), _)
+            | Inst::Un(_, Operand::Reg(reg, _))
+            | Inst::Set(_, Operand::Reg(reg, _), ..)
+            | Inst::Pop(Operand::Reg(reg, _))
+            | Inst::Div(reg, ..)
+            | Inst::Call(.., reg) => Some(*reg),
+            _ => None,
+        }
+    }
+
+    // calls `f` on every register it mentions, putting back what it returns
+    pub fn map_regs(&mut self, f: &mut impl FnMut(VReg) -> VReg) {
+        match self {
+            Inst::Mov(a, b) | Inst::Ext(a, b, _) | Inst::Bin(_, a, b) | Inst::Branch(_, a, b, _) => {
+                a.map_regs(f);
+                b.map_regs(f);
+            },
+            Inst::Set(_, a, b, c) => {
+                a.map_regs(f);
+                b.map_regs(f);
+                c.map_regs(f);
+            },
+            Inst::Addr(a, mem) => {
+                a.map_regs(f);
+                mem.map_regs(f);
+            },
+            Inst::Un(_, a) | Inst::Push(a) | Inst::Pop(a) | Inst::JmpTo(a) | Inst::Exit(a) | Inst::Ret(Some(a)) => a.map_regs(f),
+            Inst::Param(mem, _) => mem.map_regs(f),
+            Inst::Fill(mem, reg, _) => {
+                mem.map_regs(f);
+                *reg = f(*reg);
+            },
+            Inst::Div(a, b, ..) => {
+                *a = f(*a);
+                *b = f(*b);
+            },
+            Inst::Call(callee, _, ret) => {
+                if let Callee::Addr(reg) = callee {
+                    *reg = f(*reg);
+                }
+                *ret = f(*ret);
+            },
+            Inst::Switch(reg, ..) => *reg = f(*reg),
+            Inst::Jmp(_) | Inst::Ret(None) | Inst::Trap => (),
+        }
+    }
+
+    // every operand, read or written
+    pub fn operands(&self) -> Vec<&Operand> {
+        match self {
+            Inst::Mov(a, b) | Inst::Ext(a, b, _) | Inst::Bin(_, a, b) | Inst::Branch(_, a, b, _) => vec![a, b],
+            Inst::Set(_, a, b, c) => vec![a, b, c],
+            Inst::Addr(a, _) | Inst::Un(_, a) | Inst::Push(a) | Inst::Pop(a) | Inst::JmpTo(a) | Inst::Exit(a) => vec![a],
+            Inst::Ret(a) => a.iter().collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    // every memory access or address, the operands' and its own
+    pub fn mems_mut(&mut self) -> Vec<&mut Mem> {
+        let (ops, own): (Vec<&mut Operand>, _) = match self {
+            Inst::Mov(a, b) | Inst::Ext(a, b, _) | Inst::Bin(_, a, b) | Inst::Branch(_, a, b, _) => (vec![a, b], None),
+            Inst::Set(_, a, b, c) => (vec![a, b, c], None),
+            Inst::Addr(a, mem) => (vec![a], Some(mem)),
+            Inst::Un(_, a) | Inst::Push(a) | Inst::Pop(a) | Inst::JmpTo(a) | Inst::Exit(a) | Inst::Ret(Some(a)) => (vec![a], None),
+            Inst::Param(mem, _) | Inst::Fill(mem, ..) => (Vec::new(), Some(mem)),
+            _ => (Vec::new(), None),
+        };
+        let mems = ops.into_iter().filter_map(|op| match op {
+            Operand::Mem(mem) => Some(mem),
+            _ => None,
+        });
+        mems.chain(own).collect()
+    }
+}
+
+fn valid_size(size: u8) -> bool { matches!(size, 1 | 2 | 4 | 8) }
+
+fn fits_i32(n: i64) -> bool { i32::try_from(n).is_ok() }
+
+// immediates of smaller operands may be given signed or unsigned
+fn imm_ok(n: i64, size: Option<u8>) -> bool {
+    match size {
+        Some(size @ (1 | 2 | 4)) => n >= -(1 << (size * 8 - 1)) && n < 1 << (size * 8),
+        _ => fits_i32(n),
+    }
+}
+
+fn mem_ok(mem: &Mem) -> Result<(), &'static str> {
+    if !valid_size(mem.size) {
+        return Err("memory of an odd size");
+    }
+    match mem.index {
+        Some((_, scale)) if !matches!(scale, 1 | 2 | 4 | 8) => Err("an index scale other than 1, 2, 4 or 8"),
+        _ if !fits_i32(mem.disp) => Err("a displacement beyond 32 bits"),
+        _ => Ok(()),
+    }
+}
+
+// the operand rules of a single instruction
+pub fn check(inst: &Inst) -> Result<(), &'static str> {
+    for op in inst.operands() {
+        match op {
+            Operand::Reg(_, size) if !valid_size(*size) => return Err("a register of an odd size"),
+            Operand::Mem(mem) => mem_ok(mem)?,
+            _ => (),
+        }
+    }
+    let mems = inst.operands().iter().filter(|op| matches!(op, Operand::Mem(_))).count();
+    let writes_imm = |dst: &Operand| matches!(dst, Operand::Imm(_));
+    let same_size = |a: &Operand, b: &Operand| match (a.size(), b.size()) {
+        (Some(a), Some(b)) => a == b,
+        _ => true,
+    };
+    match inst {
+        Inst::Mov(dst, src) => {
+            if writes_imm(dst) {
+                return Err("writes an immediate");
+            }
+            if mems > 1 {
+                return Err("two memory operands");
+            }
+            if !same_size(dst, src) {
+                return Err("operands of different sizes");
+            }
+            if let Operand::Imm(n) = src {
+                if !imm_ok(*n, dst.size()) && !matches!(dst, Operand::Reg(_, 8)) {
+                    return Err("an immediate beyond 32 bits outside of an 8 byte register");
+                }
+            }
+        },
+        Inst::Ext(dst, src, _) => {
+            let (Operand::Reg(_, to @ (4 | 8)), Some(from)) = (dst, src.size()) else {
+                return Err("extends into something other than a 4 or 8 byte register");
+            };
+            if from >= *to {
+                return Err("extends to a smaller size");
+            }
+        },
+        Inst::Addr(dst, mem) => {
+            if !matches!(dst, Operand::Reg(_, 8)) {
+                return Err("an address into something other than an 8 byte register");
+            }
+            mem_ok(mem)?;
+        },
+        Inst::Bin(op, dst, src) => {
+            if writes_imm(dst) {
+                return Err("writes an immediate");
+            }
+            if mems > 1 {
+                return Err("two memory operands");
+            }
+            match (op, src) {
+                (BinOp::Shl | BinOp::Shr | BinOp::Sar, Operand::Imm(0..=255) | Operand::Reg(..)) => (),
+                (BinOp::Shl | BinOp::Shr | BinOp::Sar, _) => return Err("a shift count that's no register or byte"),
+                (BinOp::Mul, _) if !matches!(dst, Operand::Reg(_, 2 | 4 | 8)) => {
+                    return Err("a multiplication into something other than a 2, 4 or 8 byte register")
+                },
+                (_, Operand::Imm(n)) if !imm_ok(*n, dst.size()) => return Err("an immediate that doesn't fit"),
+                _ if !same_size(dst, src) => return Err("operands of different sizes"),
+                _ => (),
+            }
+        },
+        Inst::Un(_, dst) | Inst::Pop(dst) if writes_imm(dst) => return Err("writes an immediate"),
+        Inst::Set(_, dst, lhs, rhs) => {
+            if dst.size() != Some(1) || writes_imm(dst) {
+                return Err("sets something other than a byte");
+            }
+            if matches!(lhs, Operand::Imm(_)) || !same_size(lhs, rhs) || matches!((lhs, rhs), (Operand::Mem(_), Operand::Mem(_))) {
+                return Err("compares operands that can't be compared");
+            }
+            if let Operand::Imm(n) = rhs {
+                if !imm_ok(*n, lhs.size()) {
+                    return Err("an immediate that doesn't fit");
+                }
+            }
+        },
+        Inst::Branch(_, lhs, rhs, _) => {
+            if matches!(lhs, Operand::Imm(_)) || !same_size(lhs, rhs) || mems > 1 {
+                return Err("compares operands that can't be compared");
+            }
+            if let Operand::Imm(n) = rhs {
+                if !imm_ok(*n, lhs.size()) {
+                    return Err("an immediate that doesn't fit");
+                }
+            }
+        },
+        Inst::Push(Operand::Imm(n)) if !fits_i32(*n) => return Err("an immediate beyond 32 bits"),
+        Inst::Fill(mem, ..) | Inst::Param(mem, _) => mem_ok(mem)?,
+        Inst::JmpTo(target) if target.size() != Some(8) => return Err("jumps to something other than 8 bytes"),
+        Inst::Exit(code) if code.size() != Some(4) => return Err("an exit status other than 4 bytes"),
+        _ => (),
+    }
+    Ok(())
+}
+
+//
+// the textual form, what --emit=ir writes
+impl Display for VReg {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.is_temp() {
+            true => write!(f, "t{}", self.0 - 256),
+            false => write!(f, "r{}", self.0),
+        }
+    }
+}
+
+fn suffix(size: u8) -> &'static str {
+    match size {
+        1 => "l",
+        2 => "w",
+        4 => "d",
+        _ => "",
+    }
+}
+
+impl Display for Mem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let size = match self.size {
+            1 => "b",
+            2 => "w",
+            4 => "d",
+            _ => "q",
+        };
+        let mut parts = Vec::new();
+        match &self.base {
+            Base::None => (),
+            Base::Reg(reg) => parts.push(reg.to_string()),
+            Base::Frame => parts.push("frame".to_string()),
+            Base::Stack => parts.push("stack".to_string()),
+            Base::Sym(label) => parts.push(label.clone()),
+        }
+        if let Some((index, scale)) = self.index {
+            parts.push(format!("{}*{}", index, scale));
+        }
+        let mut addr = parts.join(" + ");
+        match self.disp {
+            0 if !addr.is_empty() => (),
+            d if addr.is_empty() => addr = d.to_string(),
+            d if d < 0 => addr.push_str(&format!(" - {}", d.unsigned_abs())),
+            d => addr.push_str(&format!(" + {}", d)),
+        }
+        write!(f, "{}[{}]", size, addr)
+    }
+}
+
+impl Display for Operand {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Reg(reg, size) => write!(f, "{}{}", reg, suffix(*size)),
+            Operand::Mem(mem) => write!(f, "{}", mem),
+            Operand::Imm(n) => write!(f, "{}", n),
+        }
+    }
+}
+
+impl Display for Cond {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Cond::Eq => "eq",
+            Cond::Ne => "ne",
+            Cond::Ult => "ult",
+            Cond::Ule => "ule",
+            Cond::Ugt => "ugt",
+            Cond::Uge => "uge",
+            Cond::Slt => "slt",
+            Cond::Sle => "sle",
+            Cond::Sgt => "sgt",
+            Cond::Sge => "sge",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl Display for Callee {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Callee::Native(name) => write!(f, "{}", name),
+            Callee::SysV(name) => write!(f, "sysv {}", name),
+            Callee::Extern(name, true) => write!(f, "${} ...", name),
+            Callee::Extern(name, false) => write!(f, "${}", name),
+            Callee::Addr(reg) => write!(f, "<- {}", reg),
+            Callee::Sys(name) => write!(f, "*{}", name),
+        }
+    }
+}
+
+impl Display for Inst {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Inst::Mov(a, b) => write!(f, "mov {}, {}", a, b),
+            Inst::Ext(a, b, true) => write!(f, "sext {}, {}", a, b),
+            Inst::Ext(a, b, false) => write!(f, "zext {}, {}", a, b),
+            Inst::Addr(a, mem) => write!(f, "addr {}, {}", a, mem),
+            Inst::Bin(op, a, b) => write!(f, "{} {}, {}", format!("{:?}", op).to_lowercase(), a, b),
+            Inst::Un(op, a) => write!(f, "{} {}", format!("{:?}", op).to_lowercase(), a),
+            Inst::Div(a, b, signed, rem) => {
+                let name = match (signed, rem) {
+                    (false, false) => "div",
+                    (true, false) => "idiv",
+                    (false, true) => "rem",
+                    (true, true) => "irem",
+                };
+                write!(f, "{} {}, {}", name, a, b)
+            },
+            Inst::Set(cond, a, b, c) => write!(f, "set.{} {}, {}, {}", cond, a, b, c),
+            Inst::Param(mem, n) => write!(f, "param {}, {}", mem, n),
+            Inst::Push(a) => write!(f, "push {}", a),
+            Inst::Pop(a) => write!(f, "pop {}", a),
+            Inst::Fill(mem, reg, n) => write!(f, "fill {}, {}, {}", mem, reg, n),
+            Inst::Call(callee, args, ret) => write!(f, "call {}, {} -> {}", callee, args, ret),
+            Inst::Branch(cond, a, b, label) => write!(f, "br.{} {}, {}, {}", cond, a, b, label),
+            Inst::Jmp(label) => write!(f, "jmp {}", label),
+            Inst::JmpTo(a) => write!(f, "jmp {}", a),
+            Inst::Switch(reg, labels, otherwise) => write!(f, "switch {}, {{{}}}, {}", reg, labels.join(", "), otherwise),
+            Inst::Ret(Some(a)) => write!(f, "ret {}", a),
+            Inst::Ret(None) => write!(f, "ret"),
+            Inst::Exit(a) => write!(f, "exit {}", a),
+            Inst::Trap => write!(f, "trap"),
+        }
+    }
+}
+
+impl Display for Program {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for func in &self.functions {
+            writeln!(f, "fn {}{} {{", func.name, if func.sysv { " sysv" } else { "" })?;
+            for block in &func.blocks {
+                match block.depth {
+                    Some(depth) if depth > 0 => writeln!(f, "{}:  ; {} bytes pushed", block.label, depth)?,
+                    _ => writeln!(f, "{}:", block.label)?,
+                }
+                for inst in &block.insts {
+                    writeln!(f, "    {}", inst)?;
+                }
+            }
+            writeln!(f, "}}\n")?;
+        }
+        for (label, words) in &self.data {
+            let words = words.iter()
+                .map(|w| match w {
+                    Word::Int(n) => n.to_string(),
+                    Word::Label(label) => label.clone(),
+                })
+                .collect::<Vec<_>>();
+            writeln!(f, "data {}: {}", label, words.join(", "))?;
+        }
+        for (i, string) in self.strings.iter().enumerate() {
+            writeln!(f, "data .Lstr{}: {:?}", i, string)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::location::Location;
+
+    // the program in the textual form, which --emit=ir writes. Labels whose address an `addr`
+    // takes are taken, every instruction comes from a statement of its own
+    pub fn read(text: &str) -> Program {
+        let mut program = Program {
+            functions: Vec::new(),
+            data: Vec::new(),
+            strings: Vec::new(),
+            taken: Vec::new(),
+            entry: None,
+        };
+        for line in text.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            if let Some(head) = line.strip_prefix("fn ") {
+                let head = head.trim_end_matches(" {");
+                let (name, sysv) = match head.strip_suffix(" sysv") {
+                    Some(name) => (name, true),
+                    None => (head, false),
+                };
+                program.functions.push(Function {
+                    name: name.to_string(),
+                    func: program.functions.len(),
+                    sysv,
+                    notail: false,
+                    blocks: Vec::new(),
+                });
+            } else if line == "}" {
+                continue;
+            } else if let Some((label, rest)) = line.split_once(':').filter(|(l, _)| !l.contains(' ')) {
+                let mut block = Block::new(label.to_string());
+                block.depth = rest.trim().strip_prefix("; ").and_then(|d| d.strip_suffix(" bytes pushed")).map(|d| d.parse().unwrap());
+                program.functions.last_mut().unwrap().blocks.push(block);
+            } else {
+                let inst = inst(line);
+                if let Inst::Addr(_, Mem { base: Base::Sym(label), .. }) = &inst {
+                    program.taken.push(label.clone());
+                }
+                let block = program.functions.last_mut().unwrap().blocks.last_mut().unwrap();
+                let at = Location { line: block.insts.len() + 1, column: 1 };
+                block.stmts.push((block.insts.len(), Span("test.ir", at, at)));
+                block.insts.push(inst);
+            }
+        }
+        program.taken.retain(|l| program.functions.iter().flat_map(|f| &f.blocks).any(|b| b.label == *l));
+        program.entry = program.functions.iter().find(|f| f.name == "main").map(|f| f.name.clone());
+        program
+    }
+
+    fn reg(text: &str) -> Option<(VReg, u8)> {
+        let (temp, rest) = match text.as_bytes().first()? {
+            b'r' => (false, &text[1..]),
+            b't' => (true, &text[1..]),
+            _ => return None,
+        };
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        let n = rest[..digits].parse::<u16>().ok()?;
+        let size = match &rest[digits..] {
+            "" => 8,
+            "d" => 4,
+            "w" => 2,
+            "l" => 1,
+            _ => return None,
+        };
+        Some((VReg(if temp { n + 256 } else { n }), size))
+    }
+
+    fn mem(text: &str) -> Option<Mem> {
+        let size = match text.as_bytes().first()? {
+            b'b' => 1,
+            b'w' => 2,
+            b'd' => 4,
+            b'q' => 8,
+            _ => return None,
+        };
+        let addr = text[1..].strip_prefix('[')?.strip_suffix(']')?;
+        let mut mem = Mem { size, base: Base::None, index: None, disp: 0 };
+        let mut sign = 1;
+        for part in addr.split(' ') {
+            match part {
+                "+" => sign = 1,
+                "-" => sign = -1,
+                "frame" => mem.base = Base::Frame,
+                "stack" => mem.base = Base::Stack,
+                part => match (part.parse::<i64>(), part.split_once('*')) {
+                    (Ok(n), _) => mem.disp = sign * n,
+                    (_, Some((index, scale))) => mem.index = Some((reg(index)?.0, scale.parse().ok()?)),
+                    _ => mem.base = reg(part).map_or(Base::Sym(part.to_string()), |(r, _)| Base::Reg(r)),
+                },
+            }
+        }
+        Some(mem)
+    }
+
+    fn operand(text: &str) -> Operand {
+        if let Ok(n) = text.parse() {
+            return Operand::Imm(n);
+        }
+        match (reg(text), mem(text)) {
+            (Some((reg, size)), _) => Operand::Reg(reg, size),
+            (_, Some(mem)) => Operand::Mem(mem),
+            _ => panic!("`{}` is no operand", text),
+        }
+    }
+
+    fn cond(text: &str) -> Cond {
+        [Cond::Eq, Cond::Ne, Cond::Ult, Cond::Ule, Cond::Ugt, Cond::Uge, Cond::Slt, Cond::Sle, Cond::Sgt, Cond::Sge]
+            .into_iter()
+            .find(|c| c.to_string() == text)
+            .unwrap_or_else(|| panic!("`{}` is no condition", text))
+    }
+
+    fn callee(text: &str) -> Callee {
+        if let Some(name) = text.strip_prefix("sysv ") {
+            Callee::SysV(name.to_string())
+        } else if let Some(name) = text.strip_prefix('$') {
+            match name.strip_suffix(" ...") {
+                Some(name) => Callee::Extern(name.to_string(), true),
+                None => Callee::Extern(name.to_string(), false),
+            }
+        } else if let Some(addr) = text.strip_prefix("<- ") {
+            Callee::Addr(reg(addr).unwrap().0)
+        } else if let Some(name) = text.strip_prefix('*') {
+            Callee::Sys(name.to_string())
+        } else {
+            Callee::Native(text.to_string())
+        }
+    }
+
+    fn inst(line: &str) -> Inst {
+        let (op, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let ops = rest.split(", ").filter(|o| !o.is_empty()).collect::<Vec<_>>();
+        let arg = |i: usize| operand(ops[i]);
+        let vreg = |i: usize| reg(ops[i]).unwrap().0;
+        let bin = |op| Inst::Bin(op, arg(0), arg(1));
+        let un = |op| Inst::Un(op, arg(0));
+        match op {
+            "mov" => Inst::Mov(arg(0), arg(1)),
+            "sext" => Inst::Ext(arg(0), arg(1), true),
+            "zext" => Inst::Ext(arg(0), arg(1), false),
+            "addr" => Inst::Addr(arg(0), mem(ops[1]).unwrap()),
+            "add" => bin(BinOp::Add),
+            "sub" => bin(BinOp::Sub),
+            "mul" => bin(BinOp::Mul),
+            "and" => bin(BinOp::And),
+            "or" => bin(BinOp::Or),
+            "xor" => bin(BinOp::Xor),
+            "shl" => bin(BinOp::Shl),
+            "shr" => bin(BinOp::Shr),
+            "sar" => bin(BinOp::Sar),
+            "not" => un(UnOp::Not),
+            "inc" => un(UnOp::Inc),
+            "dec" => un(UnOp::Dec),
+            "div" => Inst::Div(vreg(0), vreg(1), false, false),
+            "idiv" => Inst::Div(vreg(0), vreg(1), true, false),
+            "rem" => Inst::Div(vreg(0), vreg(1), false, true),
+            "irem" => Inst::Div(vreg(0), vreg(1), true, true),
+            "param" => Inst::Param(mem(ops[0]).unwrap(), ops[1].parse().unwrap()),
+            "push" => Inst::Push(arg(0)),
+            "pop" => Inst::Pop(arg(0)),
+            "fill" => Inst::Fill(mem(ops[0]).unwrap(), vreg(1), ops[2].parse().unwrap()),
+            "call" => {
+                let (call, ret) = rest.split_once(" -> ").unwrap();
+                let (name, args) = call.rsplit_once(", ").unwrap();
+                Inst::Call(callee(name), args.parse().unwrap(), reg(ret).unwrap().0)
+            },
+            "jmp" => match (reg(rest), mem(rest)) {
+                (None, None) => Inst::Jmp(rest.to_string()),
+                _ => Inst::JmpTo(operand(rest)),
+            },
+            "switch" => {
+                let (on, rest) = rest.split_once(", {").unwrap();
+                let (labels, otherwise) = rest.split_once("}, ").unwrap();
+                Inst::Switch(reg(on).unwrap().0, labels.split(", ").map(str::to_string).collect(), otherwise.to_string())
+            },
+            "ret" if rest.is_empty() => Inst::Ret(None),
+            "ret" => Inst::Ret(Some(arg(0))),
+            "exit" => Inst::Exit(arg(0)),
+            "trap" => Inst::Trap,
+            _ => match op.split_once('.') {
+                Some(("set", c)) => Inst::Set(cond(c), arg(0), arg(1), arg(2)),
+                Some(("br", c)) => Inst::Branch(cond(c), arg(0), arg(1), ops[2].to_string()),
+                _ => panic!("`{}` is no instruction", line),
+            },
+        }
+    }
+
+    const FIB: &str = "
+fn main sysv {
+main:
+    mov r1, 0
+    mov r2, 1
+    mov r3d, 10
+loop:
+    br.eq r3d, 0, done
+body:
+    mov t0, r1
+    add t0, r2
+    mov r1, r2
+    mov r2, t0
+    dec r3d
+    jmp loop
+done:
+    mov d[frame - 8], r1d
+    addr t0, q[done]
+    call $printf ..., 2 -> r0
+    ret r1
+}
+";
+
+    fn problems_in(text: &str) -> Vec<String> { problems(&read(text), false) }
+
+    #[test]
+    fn reads_what_it_writes() {
+        let program = read(FIB);
+        assert_eq!(program.to_string().trim(), FIB.trim());
+        assert_eq!(program.taken, ["done"]);
+        assert_eq!(problems(&program, false), Vec::<String>::new());
+    }
+
+    #[test]
+    fn unknown_labels() {
+        assert_eq!(problems_in(&FIB.replace("jmp loop", "jmp nowhere")), ["body: `jmp nowhere`: unknown label"]);
+        assert_eq!(problems_in(&FIB.replace("br.eq r3d, 0, done", "br.eq r3d, 0, gone")), ["loop: `br.eq r3d, 0, gone`: unknown label"]);
+        assert_eq!(problems_in(&FIB.replace("q[done]", "q[gone]")), ["done: `addr t0, q[gone]`: unknown label"]);
+
+        let mut program = read(FIB);
+        program.taken.push("gone".to_string());
+        assert_eq!(problems(&program, false), ["the address of unknown label `gone` is taken"]);
+    }
+
+    #[test]
+    fn blocks_end_in_their_only_jump() {
+        assert_eq!(problems_in(&FIB.replace("    ret r1\n", "")), ["`main` falls off its end"]);
+        assert_eq!(problems_in(&FIB.replace("    jmp loop\n", "    jmp loop\n    dec r3d\n")), ["body: `jmp loop`: a jump in the middle of a block"]);
+        assert_eq!(problems_in(&FIB.replace("body:\n", "loop:\n")), ["`loop` labels two blocks"]);
+    }
+
+    #[test]
+    fn operand_sizes() {
+        let bad = [
+            ("mov r1, r2", "mov r1, r2d", "operands of different sizes"),
+            ("add t0, r2", "add t0d, r2", "operands of different sizes"),
+            ("mov r1, 0", "mov 0, r1", "writes an immediate"),
+            ("mov d[frame - 8], r1d", "mov d[frame - 8], d[frame - 16]", "two memory operands"),
+            ("mov r3d, 10", "mov r3d, 4294967296", "an immediate beyond 32 bits outside of an 8 byte register"),
+            ("dec r3d", "dec 3", "writes an immediate"),
+        ];
+        for (good, bad, problem) in bad {
+            let found = problems_in(&FIB.replace(good, bad));
+            assert_eq!(found.len(), 1, "{}: {:?}", bad, found);
+            assert!(found[0].ends_with(&format!("`{}`: {}", bad, problem)), "{}: {:?}", bad, found);
+        }
+        // 8 byte registers take any immediate
+        assert_eq!(problems_in(&FIB.replace("mov r1, 0", "mov r1, 4294967296")), Vec::<String>::new());
+    }
+
+    #[test]
+    fn scratch_registers_come_with_spilling() {
+        let spilled = FIB.replace("mov t0, r1", "mov t2, r1");
+        assert_eq!(problems_in(&spilled), ["body: `mov t2, r1`: a scratch register before register allocation"]);
+        assert_eq!(problems(&read(&spilled), true), Vec::<String>::new());
+    }
+
+    #[test]
+    fn pushes_balance_at_labels() {
+        let uneven = FIB.replace("    dec r3d\n", "    dec r3d\n    push r1\n");
+        assert_eq!(problems_in(&uneven), ["`loop` is reached with different stack depths"]);
+    }
+}
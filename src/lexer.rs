@@ -147,34 +147,33 @@ impl Lexer {
                 }
                 'a'..='z' | 'A'..='Z' | '_' => {
                     match c {
-                        'r' => {
+                        'r' if matches!(self.peek(), Some('0'..='9')) => {
                             self.advance();
+                            let mut text = String::new();
+                            self.lex_number(&mut text, Base::RDecimal);
                             let tmp = self.loc();
-                            if let Some('0'..='9') = self.cur() {
-                                let mut text = String::new();
-                                self.lex_number(&mut text, Base::RDecimal);
-                                let tmp = self.loc();
-                                let size: u8 = match self.cur() {
-                                        Some(' ' | '\t' | '\r') | None => 0,
-                                        Some('l') => 1,
-                                        Some('h') => 2,
-                                        Some('w') => 3,
-                                        Some('d') => 4,
-                                        Some('q') => 5,
-                                        _ => {
-                                            Log::new(ERR, self.span(tmp, self.loc()), format!("Unexpected character: '{}'", self.cur().unwrap()), "Expected register size").push();
-                                            0
+                            let ends_word = !matches!(self.peek(), Some('a'..='z' | 'A'..='Z' | '_' | '0'..='9'));
+                            let size: u8 = match self.cur() {
+                                    Some('l') if ends_word => 1,
+                                    Some('h') if ends_word => 2,
+                                    Some('w') if ends_word => 3,
+                                    Some('d') if ends_word => 4,
+                                    Some('q') if ends_word => 5,
+                                    Some(c @ ('a'..='z' | 'A'..='Z' | '_')) => {
+                                        Log::new(ERR, self.span(tmp, self.loc()), format!("Unexpected character: '{}'", c), "Expected register size").push();
+                                        while let Some('a'..='z' | 'A'..='Z' | '_' | '0'..='9') = self.cur() {
+                                            self.advance();
                                         }
-                                };
-                                if size != 0 {
-                                    self.advance();
-                                }
-                                let mut token = Token::new(TokenKind::Register, self.span(start, self.loc()), text);
-                                token.set_register_size(size);
-                                self.push(&mut tokens, token);
-                            } else {
-                                Log::new(ERR, self.span(tmp, self.loc()), format!("Unexpected character: '{}'", self.cur().unwrap()), "Expected register number").push();
+                                        0
+                                    }
+                                    _ => 0,
+                            };
+                            if size != 0 {
+                                self.advance();
                             }
+                            let mut token = Token::new(TokenKind::Register, self.span(start, self.loc()), text);
+                            token.set_register_size(size);
+                            self.push(&mut tokens, token);
                         }
                         _ => {
                             let mut ident = String::new();
@@ -195,14 +194,21 @@ impl Lexer {
                     }
                 }
 
-                '&' => self.push_simple(&mut tokens, TokenKind::Ampersand, 1),
+                '&' => match self.peek() {
+                    Some('&') => self.push_simple(&mut tokens, TokenKind::AmpersandAmpersand, 2),
+                    _ => self.push_simple(&mut tokens, TokenKind::Ampersand, 1),
+                },
+                '\'' => self.push_simple(&mut tokens, TokenKind::Apostrophe, 1),
                 '@' => self.push_simple(&mut tokens, TokenKind::At, 1),
                 '\\' => self.push_simple(&mut tokens, TokenKind::Backslash, 1),
                 '!' => match self.peek() {
                     Some('=') => self.push_simple(&mut tokens, TokenKind::NotEquals, 2),
                     _ => self.push_simple(&mut tokens, TokenKind::Bang, 1),
                 },
-                '^' => self.push_simple(&mut tokens, TokenKind::Caret, 1),
+                '^' => match self.peek() {
+                    Some('^') => self.push_simple(&mut tokens, TokenKind::CaretCaret, 2),
+                    _ => self.push_simple(&mut tokens, TokenKind::Caret, 1),
+                },
                 ':' => self.push_simple(&mut tokens, TokenKind::Colon, 1),
                 ',' => self.push_simple(&mut tokens, TokenKind::Comma, 1),
                 '$' => self.push_simple(&mut tokens, TokenKind::Dollar, 1),
@@ -229,7 +235,10 @@ impl Lexer {
                     _ => self.push_simple(&mut tokens, TokenKind::Minus, 1),
                 },
                 '%' => self.push_simple(&mut tokens, TokenKind::Percent, 1),
-                '|' => self.push_simple(&mut tokens, TokenKind::Pipe, 1),
+                '|' => match self.peek() {
+                    Some('|') => self.push_simple(&mut tokens, TokenKind::PipePipe, 2),
+                    _ => self.push_simple(&mut tokens, TokenKind::Pipe, 1),
+                },
                 '+' => match self.peek() {
                     Some('+') => self.push_simple(&mut tokens, TokenKind::PlusPlus, 2),
                     _ => self.push_simple(&mut tokens, TokenKind::Plus, 1),
@@ -267,7 +276,10 @@ impl Lexer {
                     _ => self.push_simple(&mut tokens, TokenKind::Slash, 1),
                 },
                 '*' => self.push_simple(&mut tokens, TokenKind::Star, 1),
-                '~' => self.push_simple(&mut tokens, TokenKind::Tilde, 1),
+                '~' => match self.peek() {
+                    Some('=') => self.push_simple(&mut tokens, TokenKind::TildeEquals, 2),
+                    _ => self.push_simple(&mut tokens, TokenKind::Tilde, 1),
+                },
 
                 _ => {
                     let level = match c.to_string().into_bytes().len() {
@@ -289,13 +301,13 @@ impl Lexer {
                 (Base::Decimal | Base::RDecimal, '0'..='9')
                 | (Base::Binary, '0' | '1')
                 | (Base::Octal, '0'..='7')
-                | (Base::Hexadecimal, '0'..='9' | 'a'..='f') => {
+                | (Base::Hexadecimal, '0'..='9' | 'a'..='f' | 'A'..='F') => {
                     num.push(c);
                     self.advance();
                 },
                 (_, '_') => self.advance(),
                 (Base::RDecimal, _) => break,
-                (_, '0'..='9' | 'a'..='f') => {
+                (_, '0'..='9' | 'a'..='f' | 'A'..='F') => {
                     Log::new(ERR, self.span(self.loc(), self.loc()), format!("Unexpected character for base {}: '{}'", base, c), "").push();
                     break;
                 },
@@ -320,6 +332,7 @@ impl Lexer {
                     match self.cur() {
                         Some('n') => text.push('\n'),
                         Some('t') => text.push('\t'),
+                        Some('0') => text.push('\0'),
                         Some('\\') => text.push('\\'),
                         Some('\'') => text.push('\''),
                         Some(quote) => text.push(quote),
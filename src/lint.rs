@@ -0,0 +1,244 @@
+use std::collections::HashSet;
+
+use crate::args_parser::ARGS;
+use crate::ast::{self, ExprKind, MutateOp, Name, StmtKind};
+use crate::cfg::Cfg;
+use crate::frame::Layout;
+use crate::ir::{BinOp, Callee, Inst, Operand, VReg, Word};
+use crate::location::Span;
+use crate::logger::{Log, WARN};
+
+// Warnings about code that can't run or doesn't matter, drawn from the control-flow graph of the
+// IR and from what the statements read and write. --no-warn turns each of them off
+
+pub fn check(program: &ast::Program, layout: &Layout, cfg: &Cfg) {
+    let reachable = cfg.reachable();
+    let warn = unsafe { ARGS.warn };
+    if warn.unreachable {
+        dead_code(cfg, &reachable);
+    }
+    if warn.unused_label {
+        unused_labels(cfg, &reachable);
+    }
+    if warn.unused_var {
+        unused_vars(program, layout);
+    }
+    if warn.uninit {
+        uninit(cfg, &reachable);
+    }
+}
+
+// code that nothing can get to, once per run of dead blocks. Blocks without statements are the
+// compiler's own and only joined into a run
+fn dead_code(cfg: &Cfg, reachable: &[bool]) {
+    for func in &cfg.program.functions {
+        let start = cfg.index[func.blocks[0].label.as_str()];
+        let mut after = None; // the last instruction that was reached
+        let mut run: Option<(usize, Span)> = None;
+        for (i, block) in func.blocks.iter().enumerate().map(|(b, block)| (start + b, block)) {
+            if reachable[i] {
+                if let Some((first, span)) = run.take() {
+                    report_dead(cfg, first, after, span);
+                }
+                after = block.insts.last();
+                continue;
+            }
+            let Some(span) = block.span() else { continue };
+            run = Some(match run {
+                Some((first, whole)) => (first, whole.extend(&span)),
+                None => (i, span),
+            });
+        }
+        if let Some((first, span)) = run {
+            report_dead(cfg, first, after, span);
+        }
+    }
+}
+
+fn report_dead(cfg: &Cfg, first: usize, after: Option<&Inst>, span: Span) {
+    let what = match after {
+        Some(Inst::Ret(_)) => "`ret`",
+        Some(Inst::Jmp(_) | Inst::JmpTo(_)) => "`jmp`",
+        Some(Inst::Switch(..)) => "`.switch`",
+        Some(Inst::Exit(_)) => "`end`",
+        _ => "this point",
+    };
+    let label = &cfg.block(first).label;
+    let note = match after {
+        _ if !label.starts_with('.') => format!("Nothing jumps to `{}`", label),
+        Some(Inst::Exit(_)) => "The program has exited by then, add a label to jump here".to_string(),
+        _ => "Add a label to jump here".to_string(),
+    };
+    Log::new(WARN, span, format!("Unreachable code after {}", what), note).push();
+}
+
+// labels that control only falls into: nothing jumps to or calls them, and their address isn't
+// taken. Ones nothing reaches at all are dead code. Labels with a dot are the compiler's, made
+// by the lowering or by splicing in a function
+fn unused_labels(cfg: &Cfg, reachable: &[bool]) {
+    let ir = cfg.program;
+    let insts = ir.functions.iter().flat_map(|f| f.blocks.iter().flat_map(|b| &b.insts));
+    let mut used = HashSet::new();
+    for inst in insts {
+        used.extend(inst.targets());
+        if let Inst::Call(Callee::Native(name) | Callee::SysV(name), ..) = inst {
+            used.insert(name);
+        }
+    }
+    used.extend(&ir.taken);
+    for (_, words) in &ir.data {
+        used.extend(words.iter().filter_map(|w| match w {
+            Word::Label(label) => Some(label),
+            Word::Int(_) => None,
+        }));
+    }
+
+    for func in &ir.functions {
+        // the first block is the function's own label
+        for block in &func.blocks[1..] {
+            if block.label.contains('.') || used.contains(&block.label) || !reachable[cfg.index[block.label.as_str()]] {
+                continue;
+            }
+            let span = block.stmts.first().map(|(_, span)| *span);
+            Log::new(WARN, span, format!("Unused label `{}`", block.label),
+                "Nothing jumps to it, control only falls in from above").push();
+        }
+    }
+}
+
+// `%` and `;` variables that are assigned but never read. Changing one with `'` doesn't count
+// as reading it, and parameters are left alone. Registers are shared with every function the
+// program calls or jumps to, so a register variable counts as read when its register is read
+// anywhere. r0 is what functions return in. Names with a dot hold the arguments of a spliced
+// function
+fn unused_vars(program: &ast::Program, layout: &Layout) {
+    let mut regs_read = HashSet::new();
+    let mut funcs = Vec::new();
+    for (f, func) in layout.functions.iter().enumerate() {
+        let mut declared: Vec<(&Name, Span)> = Vec::new();
+        let mut read = HashSet::new();
+        for block in layout.blocks.iter().filter(|b| b.func == f) {
+            for stmt in &program.stmts[block.stmts.clone()] {
+                stmt.walk(&mut |stmt| {
+                    if let StmtKind::StackAssign(name, ..) | StmtKind::RegAssign(name, ..) = &stmt.kind {
+                        if !declared.iter().any(|(n, _)| *n == name) {
+                            declared.push((name, stmt.span));
+                        }
+                    }
+                    // what a mutation, pop or peek writes isn't read, what indexes it or points to it
+                    // is, like the address a store goes through
+                    let target = match &stmt.kind {
+                        StmtKind::Mutate(_, MutateOp::Store, _) => None,
+                        StmtKind::Mutate(target, ..) | StmtKind::Pop(target) | StmtKind::Peek(target) => Some(target),
+                        _ => None,
+                    };
+                    for expr in stmt.exprs() {
+                        expr.walk(&mut |expr| {
+                            if target.is_some_and(|t| std::ptr::eq(t, expr)) {
+                                return;
+                            }
+                            match &expr.kind {
+                                ExprKind::Ident(name) | ExprKind::ArrIndex(name, _) | ExprKind::StructIndex(name, _) => {
+                                    read.insert(name.as_str());
+                                    regs_read.extend(func.frame.reg(name).map(|var| var.reg.num));
+                                },
+                                ExprKind::Register(reg) => {
+                                    regs_read.insert(reg.num);
+                                },
+                                _ => (),
+                            }
+                        });
+                    }
+                });
+            }
+        }
+        funcs.push((func, declared, read));
+    }
+
+    for (func, declared, read) in funcs {
+        let params = func.def.map_or(&[][..], |def| &program.functions[def].params);
+        for (name, span) in declared.into_iter().filter(|(name, _)| !name.contains('.')) {
+            let used = match func.frame.reg(name) {
+                Some(var) => var.reg.num == 0 || regs_read.contains(&var.reg.num),
+                None => read.contains(name.as_str()) || params.iter().any(|p| p.name == *name),
+            };
+            if !used {
+                Log::new(WARN, span, format!("`{}` is assigned but never read", name), "").push();
+            }
+        }
+    }
+}
+
+// registers the entry reads before anything wrote them, on some path from its start. Other
+// functions get theirs from whoever called or jumped there, so they start out written. r0 is
+// left out, spliced calls keep it around whether it was written or not
+fn uninit(cfg: &Cfg, reachable: &[bool]) {
+    let ir = cfg.program;
+    let Some(&entry) = ir.entry.as_deref().and_then(|e| cfg.index.get(e)) else { return };
+
+    // the registers that may be unwritten when each block is entered
+    let mut unset = cfg.nodes.iter().map(|&(_, b)| (b == 0).then_some([false; 256])).collect::<Vec<_>>();
+    unset[entry] = Some([true; 256]);
+    let mut queue = (0..cfg.nodes.len()).filter(|&i| unset[i].is_some()).collect::<Vec<_>>();
+    while let Some(i) = queue.pop() {
+        let mut regs = unset[i].unwrap();
+        for reg in cfg.block(i).insts.iter().filter_map(Inst::writes).filter(|r| !r.is_temp()) {
+            regs[reg.0 as usize] = false;
+        }
+        for &(j, _) in &cfg.succs[i] {
+            let merged = match unset[j] {
+                Some(old) => std::array::from_fn(|r| old[r] || regs[r]),
+                None => regs,
+            };
+            if unset[j] != Some(merged) {
+                unset[j] = Some(merged);
+                queue.push(j);
+            }
+        }
+    }
+
+    let name = &ir.functions[cfg.nodes[entry].0].name;
+    let mut reported = [false; 256];
+    for i in (0..cfg.nodes.len()).filter(|&i| reachable[i]) {
+        let Some(mut regs) = unset[i] else { continue };
+        let block = cfg.block(i);
+        for (n, inst) in block.insts.iter().enumerate() {
+            for reg in value_reads(inst).into_iter().filter(|r| !r.is_temp() && r.0 != 0) {
+                let r = reg.0 as usize;
+                if regs[r] && !reported[r] {
+                    reported[r] = true;
+                    Log::new(WARN, block.span_at(n), format!("`{}` may be read before anything writes it", reg),
+                        format!("Nothing writes it on some path from the start of `{}`", name)).push();
+                }
+            }
+            if let Some(reg) = inst.writes().filter(|r| !r.is_temp()) {
+                regs[reg.0 as usize] = false;
+            }
+        }
+    }
+}
+
+// the registers whose value an instruction reads. Writing part of a register keeps the rest of
+// it, which doesn't count, and neither does zeroing one or clearing its high byte to write it
+fn value_reads(inst: &Inst) -> Vec<VReg> {
+    let written = match inst {
+        Inst::Bin(BinOp::Xor, Operand::Reg(a, _), Operand::Reg(b, _)) if a == b => return Vec::new(),
+        Inst::Bin(BinOp::And, Operand::Reg(_, 8), Operand::Imm(mask)) if *mask == !0xff00 => return Vec::new(),
+        Inst::Mov(dst, _) | Inst::Ext(dst, ..) | Inst::Addr(dst, _) | Inst::Set(_, dst, ..) | Inst::Pop(dst) => Some(dst),
+        _ => None,
+    };
+    let mut regs = inst.operands().into_iter()
+        .flat_map(|op| match op {
+            Operand::Reg(..) if written.is_some_and(|w| std::ptr::eq(w, op)) => Vec::new(),
+            op => op.regs(),
+        })
+        .collect::<Vec<_>>();
+    match inst {
+        Inst::Addr(_, mem) | Inst::Param(mem, _) => regs.extend(mem.regs()),
+        Inst::Fill(mem, reg, _) => regs.extend(mem.regs().into_iter().chain([*reg])),
+        Inst::Div(dst, src, ..) => regs.extend([*dst, *src]),
+        Inst::Switch(reg, ..) | Inst::Call(Callee::Addr(reg), ..) => regs.push(*reg),
+        _ => (),
+    }
+    regs
+}
@@ -0,0 +1,1139 @@
+use crate::args_parser::ARGS;
+use crate::ast::*;
+use crate::frame::{Frame, Layout};
+use crate::ir::{self, Base, Cond, Inst, Mem, Operand, UnOp, VReg, Word, ACC, TMP};
+use crate::location::Span;
+use crate::logger::{Log, ERR};
+use crate::regalloc;
+use crate::typeck::{reg_bytes, Types};
+
+// Lowers the AST into the IR, see ir.rs. Every function of the layout becomes an IR function
+// with its blocks in program order. Expressions are evaluated into ACC with TMP for the right
+// hand side of binary operators, anything else in flight is pushed.
+
+struct Lower<'a> {
+    program: &'a Program,
+    layout: &'a Layout,
+    types: &'a Types,
+    out: ir::Program,
+    blocks: Vec<ir::Block>, // of the function being lowered
+    func: usize,
+    labels: usize, // compiler generated labels so far
+    width: u64,    // bytes a deref loads, see Types::width
+    pending: Vec<Span>, // statements whose first instruction is yet to come, see ir::Block::stmts
+}
+
+// [base + index*scale + disp], where at most one part has to be computed at runtime
+struct Address<'e> {
+    regs: Vec<(VReg, u8)>,
+    disp: i64,
+    runtime: Option<(&'e Expr, u8)>,
+}
+
+pub fn lower(program: &Program, layout: &Layout, types: &Types) -> ir::Program {
+    let entry = program.entry.as_deref().unwrap_or("main");
+    let mut l = Lower {
+        program,
+        layout,
+        types,
+        out: ir::Program {
+            functions: Vec::new(),
+            data: Vec::new(),
+            strings: Vec::new(),
+            taken: layout.taken.iter().map(|&b| layout.blocks[b].label.clone()).collect(),
+            entry: layout.labels.contains_key(entry).then(|| entry.to_string()),
+        },
+        blocks: Vec::new(),
+        func: 0,
+        labels: 0,
+        width: 8,
+        pending: Vec::new(),
+    };
+
+    for func in 0..layout.functions.len() {
+        l.func = func;
+        let blocks = (0..layout.blocks.len()).filter(|&b| layout.blocks[b].func == func).collect::<Vec<_>>();
+        for (i, &block) in blocks.iter().enumerate() {
+            l.block(block);
+            // the block below may belong to another function, or be missing at the end
+            if l.falls_through() && blocks.get(i + 1) != Some(&(block + 1)) {
+                match layout.blocks.get(block + 1) {
+                    Some(next) => l.emit(Inst::Jmp(next.label.clone())),
+                    None => l.emit(Inst::Trap),
+                }
+            }
+        }
+        let name = layout.functions[func].name.clone();
+        l.out.functions.push(ir::Function {
+            sysv: l.is_sysv(&name),
+            notail: l.def().is_some_and(|def| def.attrs.notail),
+            name,
+            func,
+            blocks: std::mem::take(&mut l.blocks),
+        });
+    }
+    l.out
+}
+
+impl<'a> Lower<'a> {
+    fn frame(&self) -> &Frame { &self.layout.functions[self.func].frame }
+
+    // appends to the current block, starting a new one after a jump
+    fn emit(&mut self, inst: Inst) {
+        if self.blocks.last().is_none_or(|b| b.insts.last().is_some_and(Inst::ends_block)) {
+            let label = self.label("next");
+            self.blocks.push(ir::Block::new(label));
+        }
+        let block = self.blocks.last_mut().unwrap();
+        block.stmts.extend(self.pending.drain(..).map(|span| (block.insts.len(), span)));
+        block.insts.push(inst);
+    }
+
+    fn place(&mut self, label: Name) { self.blocks.push(ir::Block::new(label)); }
+
+    fn label(&mut self, kind: &str) -> String {
+        self.labels += 1;
+        format!(".L{}{}", kind, self.labels)
+    }
+
+    fn falls_through(&self) -> bool {
+        self.blocks.last().and_then(|b| b.insts.last()).is_none_or(|i| !i.is_terminator())
+    }
+
+    fn block(&mut self, block: usize) {
+        let block = &self.layout.blocks[block];
+        self.blocks.push(ir::Block {
+            depth: Some(block.depth),
+            stmts: vec![(0, self.program.stmts[block.stmts.start - 1].span)],
+            ..ir::Block::new(block.label.clone())
+        });
+
+        // parameters live in stack slots like any other variable
+        if self.layout.functions[self.func].name == block.label {
+            if let Some(def) = self.def() {
+                for (i, param) in def.params.iter().enumerate() {
+                    let Some(mem) = self.var_mem(&param.name, param.span) else { continue };
+                    self.emit(Inst::Param(mem, i));
+                }
+            }
+        }
+
+        self.pending.clear();
+        for stmt in &self.program.stmts[block.stmts.clone()] {
+            self.stmt(stmt);
+        }
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        self.pending.push(stmt.span);
+        match &stmt.kind {
+            StmtKind::Label(_) => unreachable!("labels start blocks"),
+
+            StmtKind::StackAssign(name, _, value) => {
+                let Some(ty) = self.frame().slot(name).map(|s| s.ty.clone()) else { return };
+                let Some(mem) = self.var_mem(name, stmt.span) else { return };
+                self.init(mem, &ty, value);
+            },
+
+            StmtKind::RegAssign(_, var, value) => {
+                self.expr_as(value, reg_bytes(var.reg.size) as u64);
+                self.write_reg(var.reg, stmt.span);
+            },
+
+            StmtKind::Mutate(target, op, value) => self.mutate(target, *op, value, stmt.span),
+
+            StmtKind::Jmp(label) => self.emit(Inst::Jmp(label.clone())),
+
+            StmtKind::JmpTo(target) => {
+                self.expr_as(target, 8);
+                self.emit(Inst::JmpTo(ACC.q()));
+            },
+
+            StmtKind::Switch(index, labels, otherwise) => {
+                self.expr_as(index, 8);
+                let skip = self.label("default");
+                self.emit(Inst::Switch(ACC, labels.clone(), otherwise.clone().unwrap_or(skip.clone())));
+                if otherwise.is_none() {
+                    self.place(skip);
+                }
+            },
+
+            StmtKind::Ret(value) => {
+                let Some(value) = value else { return self.emit(Inst::Ret(None)) };
+                let ret = self.def().and_then(|f| f.ret.as_ref());
+                let width = ret.map_or(8, |ty| self.types.size_of(ty));
+                self.expr_as(value, width);
+                // callers get the full register, as the return type says
+                let signed = matches!(ret, Some(Type::Signed(_)));
+                let fits = self.types.width(self.frame(), value).is_some_and(|w| w <= width)
+                    && self.types.is_signed(self.frame(), value) == signed;
+                if width < 8 && !fits {
+                    self.extend(width, signed);
+                }
+                self.emit(Inst::Ret(Some(ACC.q())));
+            },
+
+            StmtKind::Call(callee, args) => self.call(callee, args, false),
+
+            StmtKind::End(code) => {
+                self.expr_as(code, 4);
+                self.emit(Inst::Exit(ACC.sized(4)));
+            },
+
+            StmtKind::Cond(cond, then, otherwise) => self.cond(cond, then, otherwise.as_deref()),
+
+            StmtKind::Push(value, ty) => {
+                let size = self.types.size_of(ty);
+                match (size, &value.kind) {
+                    (8, ExprKind::Int(n)) if i32::try_from(*n).is_ok() => self.emit(Inst::Push(Operand::Imm(*n as i64))),
+                    // smaller values take only their own bytes
+                    (size, _) => {
+                        self.expr_as(value, size);
+                        self.emit(Inst::Push(ACC.sized(size as u8)));
+                    },
+                }
+            },
+
+            StmtKind::Pop(target) | StmtKind::Peek(target) => {
+                let width = self.types.width(self.frame(), target).unwrap_or(8);
+                let signed = self.types.is_signed(self.frame(), target);
+                match stmt.kind {
+                    StmtKind::Pop(_) => {
+                        self.emit(Inst::Pop(ACC.sized(width as u8)));
+                        if width < 8 && (width != 4 || signed) {
+                            self.extend(width, signed);
+                        }
+                    },
+                    _ => self.load(ACC, Mem::stack(width as u8, 0), signed),
+                }
+                self.assign_acc(target, width, stmt.span);
+            },
+        }
+    }
+
+    // stores ACC into a register, variable, field or deref of `width` bytes, like a `'target = `
+    // whose value is already evaluated
+    fn assign_acc(&mut self, target: &Expr, width: u64, span: Span) {
+        if let Some(reg) = self.reg_of(target) {
+            return self.write_reg(reg, span);
+        }
+        if self.runtime_part(target).is_some() {
+            self.emit(Inst::Push(ACC.q()));
+            self.index(target);
+            self.emit(Inst::Mov(TMP.q(), ACC.q()));
+            self.emit(Inst::Pop(ACC.q()));
+        }
+        self.width = width;
+        let Some((mem, ty)) = self.lvalue(target, TMP) else { return };
+        self.store(mem, bits_of(&ty));
+    }
+
+    // `cond => then | otherwise`. A jump in either arm becomes the conditional jump itself,
+    // anything else is branched around
+    fn cond(&mut self, cond: &Expr, then: &Stmt, otherwise: Option<&Stmt>) {
+        if let StmtKind::Jmp(label) = &then.kind {
+            self.branch(cond, true, label);
+            if let Some(otherwise) = otherwise {
+                self.stmt(otherwise);
+            }
+            return;
+        }
+        if let Some(Stmt { kind: StmtKind::Jmp(label), .. }) = otherwise {
+            self.branch(cond, false, label);
+            return self.stmt(then);
+        }
+
+        let skip = self.label(if otherwise.is_some() { "else" } else { "endif" });
+        self.branch(cond, false, &skip);
+        self.stmt(then);
+        match otherwise {
+            Some(otherwise) if then.diverges() => {
+                self.place(skip);
+                self.stmt(otherwise);
+            },
+            Some(otherwise) => {
+                let end = self.label("endif");
+                self.emit(Inst::Jmp(end.clone()));
+                self.place(skip);
+                self.stmt(otherwise);
+                self.place(end);
+            },
+            None => self.place(skip),
+        }
+    }
+
+    // jumps to `to` if `cond` is `when`, without turning it into a 0 or 1 first. Comparisons
+    // branch on themselves, && and || skip the right side once the left one decides
+    fn branch(&mut self, cond: &Expr, when: bool, to: &str) {
+        let pick = |cc: Cond| if when { cc } else { cc.negate() };
+        match &cond.kind {
+            ExprKind::Int(n) => {
+                if (*n != 0) == when {
+                    self.emit(Inst::Jmp(to.to_string()));
+                }
+            },
+            ExprKind::Binary(op, lhs, rhs) if op.is_comparison() => {
+                let signed = self.types.is_signed(self.frame(), lhs) || self.types.is_signed(self.frame(), rhs);
+                let lhs_width = self.types.width(self.frame(), rhs).unwrap_or(8);
+                let rhs_width = self.types.width(self.frame(), lhs).unwrap_or(8);
+                let cc = pick(cond_of(*op, signed));
+                // registers and memory are compared as they are when the other side allows it
+                match (self.simple_operand(lhs, lhs_width), self.simple_operand(rhs, rhs_width)) {
+                    (Some(lhs @ (Operand::Reg(..) | Operand::Mem(_))), Some(rhs @ Operand::Imm(_)))
+                    | (Some(lhs @ Operand::Reg(..)), Some(rhs)) => self.emit(Inst::Branch(cc, lhs, rhs, to.to_string())),
+                    _ => {
+                        let rhs = self.operands(lhs, rhs);
+                        self.emit(Inst::Branch(cc, ACC.q(), rhs, to.to_string()));
+                    },
+                }
+            },
+            ExprKind::Binary(BinOp::LogAnd, lhs, rhs) if !when => {
+                self.branch(lhs, false, to);
+                self.branch(rhs, false, to);
+            },
+            ExprKind::Binary(BinOp::LogOr, lhs, rhs) if when => {
+                self.branch(lhs, true, to);
+                self.branch(rhs, true, to);
+            },
+            // the left side alone can only decide the other way
+            ExprKind::Binary(op @ (BinOp::LogAnd | BinOp::LogOr), lhs, rhs) => {
+                let skip = self.label("skip");
+                self.branch(lhs, *op == BinOp::LogOr, &skip);
+                self.branch(rhs, when, to);
+                self.place(skip);
+            },
+            _ => {
+                self.expr_as(cond, 8);
+                self.emit(Inst::Branch(pick(Cond::Ne), ACC.q(), Operand::Imm(0), to.to_string()));
+            },
+        }
+    }
+
+    // `#name args` and `<- addr args`. Arguments are evaluated onto the stack in order, the address
+    // of `<- addr` into ACC after them. A statement leaves the result in r0, a value in ACC
+    fn call(&mut self, callee: &Callee, args: &[Expr], value: bool) {
+        let program = self.program;
+        let ext = match callee {
+            Callee::Extern(name) => program.externs.iter().find(|e| e.name == *name),
+            _ => None,
+        };
+        let params = match callee {
+            Callee::Named(name) => program.functions.iter().find(|f| f.name == *name)
+                .map_or(Vec::new(), |f| f.params.iter().map(|p| &p.ty).collect()),
+            Callee::Extern(_) => ext.map_or(Vec::new(), |e| e.params.iter().collect()),
+            Callee::Sys(_) | Callee::Addr(_) => Vec::new(),
+        };
+        for (i, arg) in args.iter().enumerate() {
+            self.expr_as(arg, params.get(i).map_or(8, |ty| self.types.size_of(ty)));
+            self.emit(Inst::Push(ACC.q()));
+        }
+        if let Callee::Addr(addr) = callee {
+            self.expr_as(addr, 8);
+        }
+
+        let callee = match callee {
+            Callee::Named(name) if self.is_sysv(name) => ir::Callee::SysV(name.clone()),
+            Callee::Named(name) => ir::Callee::Native(name.clone()),
+            Callee::Extern(name) => ir::Callee::Extern(name.clone(), ext.is_none_or(|e| e.variadic)),
+            Callee::Sys(name) => ir::Callee::Sys(name.clone()),
+            Callee::Addr(_) => ir::Callee::Addr(ACC),
+        };
+        self.emit(Inst::Call(callee, args.len(), if value { ACC } else { VReg(0) }));
+
+        // C doesn't care about the upper bits of an int, so they're extended as `.extern` says
+        if let (true, Some(ty @ (Type::Size(1 | 2 | 4) | Type::Signed(1 | 2 | 4)))) = (value, ext.and_then(|e| e.ret.as_ref())) {
+            self.extend(self.types.size_of(ty), matches!(ty, Type::Signed(_)));
+        }
+    }
+
+    fn is_sysv(&self, name: &str) -> bool {
+        name == self.program.entry.as_deref().unwrap_or("main")
+            || self.program.functions.iter().any(|f| f.name == name && f.attrs.sysv)
+    }
+
+    fn def(&self) -> Option<&'a FnDef> { self.layout.functions[self.func].def.map(|d| &self.program.functions[d]) }
+
+    //
+    // variables and registers
+    fn var_mem(&self, name: &str, span: Span) -> Option<Mem> {
+        let Some(slot) = self.frame().slot(name) else {
+            Log::new(ERR, span, format!("Unknown variable `{}`", name), "").push();
+            return None;
+        };
+        Some(Mem::frame(slot.size.min(8) as u8, -(slot.offset as i64)))
+    }
+
+    // 'target op value. Numbers in registers and memory are changed in place, bit fields and the
+    // operators with no in place form are recomputed as `target = (target op value)`
+    fn mutate(&mut self, target: &Expr, op: MutateOp, value: &Expr, span: Span) {
+        if op == MutateOp::Store {
+            let deref = Expr {
+                kind: ExprKind::Deref(Box::new(target.clone())),
+                span: target.span,
+            };
+            return self.mutate(&deref, MutateOp::Set, value, span);
+        }
+        let signed = self.types.is_signed(self.frame(), target);
+
+        if let Some(reg) = self.reg_of(target) {
+            let width = reg_bytes(reg.size) as u64;
+            let in_place = match op {
+                MutateOp::Div | MutateOp::Mod => false,
+                MutateOp::Mul => !matches!(reg.size, RegSize::ByteLow | RegSize::ByteHigh),
+                _ => reg.size != RegSize::ByteHigh,
+            };
+            if !in_place {
+                self.new_value(target, op, value, width, signed);
+                return self.write_reg(reg, span);
+            }
+            let Some(vreg) = self.vreg(reg, span) else { return };
+            let src = self.source(op, value, width);
+            return self.in_place(op, vreg.sized(width as u8), src, width as u8, signed);
+        }
+
+        // a deref target is as wide as the value written to it, shift counts don't count
+        self.width = match op {
+            MutateOp::Shl | MutateOp::Shr => 8,
+            _ => self.types.width(self.frame(), value).unwrap_or(8),
+        };
+        let Some((mem, ty)) = self.lvalue(target, TMP) else { return };
+        let runtime_index = self.runtime_part(target).is_some();
+        if matches!(ty, Type::Named(_) | Type::Array(..)) {
+            if runtime_index {
+                Log::new(ERR, target.span, "Array elements that are data blocks need a constant index to be assigned", "").push();
+                return;
+            }
+            return self.init(mem, &ty, value);
+        }
+
+        let bits = bits_of(&ty);
+        let in_place = bits.is_none() && !matches!(op, MutateOp::Mul | MutateOp::Div | MutateOp::Mod);
+        let width = mem.size as u64;
+        let src = match in_place {
+            true => self.source(op, value, width),
+            false => {
+                self.new_value(target, op, value, width, signed);
+                ACC.q()
+            },
+        };
+
+        if runtime_index {
+            // immediates leave ACC free
+            let live = !matches!(src, Operand::Imm(_));
+            if live {
+                self.emit(Inst::Push(ACC.q()));
+            }
+            self.index(target);
+            self.emit(Inst::Mov(TMP.q(), ACC.q()));
+            if live {
+                self.emit(Inst::Pop(ACC.q()));
+            }
+        }
+        self.width = width;
+        let Some((mem, _)) = self.lvalue(target, TMP) else { return };
+        match in_place {
+            true => self.in_place(op, Operand::Mem(mem), src, width as u8, signed),
+            false => self.store(mem, bits),
+        }
+    }
+
+    // the right hand side of an in place mutation, small literals are used as immediates and
+    // everything else is evaluated into ACC
+    fn source(&mut self, op: MutateOp, value: &Expr, width: u64) -> Operand {
+        match (op, &value.kind) {
+            (MutateOp::Shl | MutateOp::Shr, ExprKind::Int(n @ 0..=255)) => Operand::Imm(*n as i64),
+            (MutateOp::Shl | MutateOp::Shr, _) => {
+                self.expr_as(value, 8);
+                ACC.q()
+            },
+            (_, ExprKind::Int(n)) if imm_fits(*n, width as u8) => Operand::Imm(truncate(*n, width as u8)),
+            _ => {
+                self.expr_as(value, width);
+                ACC.sized(width as u8)
+            },
+        }
+    }
+
+    // dst op= src, for `size` byte registers and memory
+    fn in_place(&mut self, op: MutateOp, dst: Operand, src: Operand, size: u8, signed: bool) {
+        match op {
+            MutateOp::Set | MutateOp::Clear => self.emit(Inst::Mov(dst, src)),
+            MutateOp::Add | MutateOp::Sub | MutateOp::And | MutateOp::Or | MutateOp::Xor => {
+                self.emit(Inst::Bin(in_place_op(op), dst, src));
+            },
+            MutateOp::Inc => self.emit(Inst::Un(UnOp::Inc, dst)),
+            MutateOp::Dec => self.emit(Inst::Un(UnOp::Dec, dst)),
+            MutateOp::AndNot => match src {
+                Operand::Imm(n) => self.emit(Inst::Bin(ir::BinOp::And, dst, Operand::Imm(truncate(!n as i128, size)))),
+                src => {
+                    self.emit(Inst::Un(UnOp::Not, src.clone()));
+                    self.emit(Inst::Bin(ir::BinOp::And, dst, src));
+                },
+            },
+            // only registers of 16 bits and up get here
+            MutateOp::Mul => self.emit(Inst::Bin(ir::BinOp::Mul, dst, src)),
+            MutateOp::Shl | MutateOp::Shr => self.emit(Inst::Bin(shift_op(op, signed), dst, src)),
+            MutateOp::SetIfZero => {
+                let skip = self.label("set");
+                self.emit(Inst::Branch(Cond::Ne, dst.clone(), Operand::Imm(0), skip.clone()));
+                self.emit(Inst::Mov(dst, src));
+                self.place(skip);
+            },
+            MutateOp::Div | MutateOp::Mod | MutateOp::Store => unreachable!("no in place form"),
+        }
+    }
+
+    // ACC = the value `target op value` leaves in the target
+    fn new_value(&mut self, target: &Expr, op: MutateOp, value: &Expr, width: u64, signed: bool) {
+        match (op, op.binop()) {
+            (MutateOp::Set | MutateOp::Clear, _) => self.expr_as(value, width),
+            (MutateOp::SetIfZero, _) => {
+                let skip = self.label("set");
+                self.expr_as(target, width);
+                self.emit(Inst::Branch(Cond::Ne, ACC.q(), Operand::Imm(0), skip.clone()));
+                self.expr_as(value, width);
+                self.place(skip);
+            },
+            (MutateOp::Shl | MutateOp::Shr, _) => match value.kind {
+                ExprKind::Int(n @ 0..=255) => {
+                    self.expr_as(target, width);
+                    self.emit(Inst::Bin(shift_op(op, signed), ACC.q(), Operand::Imm(n as i64)));
+                },
+                _ => {
+                    self.expr_as(value, 8);
+                    self.emit(Inst::Push(ACC.q()));
+                    self.expr_as(target, width);
+                    self.emit(Inst::Pop(TMP.q()));
+                    self.emit(Inst::Bin(shift_op(op, signed), ACC.q(), TMP.q()));
+                },
+            },
+            (_, Some(binop)) => {
+                let applied = Expr {
+                    kind: ExprKind::Binary(binop, Box::new(target.clone()), Box::new(value.clone())),
+                    span: value.span,
+                };
+                self.expr_as(&applied, width);
+            },
+            (_, None) => unreachable!("stores are rewritten to derefs"),
+        }
+    }
+
+    fn reg_of(&self, expr: &Expr) -> Option<Register> {
+        match &expr.kind {
+            ExprKind::Ident(name) => self.frame().reg(name).map(|var| var.reg),
+            ExprKind::Register(reg) => Some(*reg),
+            _ => None,
+        }
+    }
+
+    // the memory behind a stack variable, one of its fields, an array element or a deref, along
+    // with its type. The runtime part of its address has to be in `index` already, see `index()`
+    fn lvalue(&self, expr: &Expr, index: VReg) -> Option<(Mem, Type)> {
+        match &expr.kind {
+            ExprKind::Deref(addr) => {
+                let address = self.address(addr);
+                let mut regs = address.regs;
+                if let Some((_, scale)) = address.runtime {
+                    regs.push((index, scale));
+                }
+                // only one part can be scaled, address() made sure of that
+                let (base, index) = match regs[..] {
+                    [] => (Base::None, None),
+                    [(reg, 1)] => (Base::Reg(reg), None),
+                    [scaled] => (Base::None, Some(scaled)),
+                    [(base, 1), scaled] | [scaled, (base, 1)] => (Base::Reg(base), Some(scaled)),
+                    _ => unreachable!("at most two registers with one scaled"),
+                };
+                let mem = Mem {
+                    size: self.width as u8,
+                    base,
+                    index,
+                    disp: address.disp,
+                };
+                Some((mem, Type::Size(self.width as u8)))
+            },
+            ExprKind::Ident(name) => {
+                let ty = self.frame().slot(name).map(|s| s.ty.clone());
+                Some((self.var_mem(name, expr.span)?, ty?))
+            },
+            ExprKind::StructIndex(name, field) => {
+                let mem = self.var_mem(name, expr.span)?;
+                let Some(Type::Named(block)) = self.frame().slot(name).map(|s| &s.ty) else { return None };
+                let block = self.types.block(block)?;
+                let i = self.types.field_index(block, field)?;
+                let ty = block.fields[i].ty.clone();
+                Some((offset_mem(&mem, self.types.offsets(block)[i], self.types.size_of(&ty)), ty))
+            },
+            ExprKind::ArrIndex(name, i) => {
+                let mem = self.var_mem(name, expr.span)?;
+                let Some(Type::Array(elem, _)) = self.frame().slot(name).map(|s| &s.ty) else { return None };
+                let size = self.types.size_of(elem);
+                let mem = match i.kind {
+                    ExprKind::Int(i) => offset_mem(&mem, i as u64 * size, size),
+                    _ => Mem {
+                        index: Some((index, scale_of(size))),
+                        ..offset_mem(&mem, 0, size)
+                    },
+                };
+                Some((mem, (**elem).clone()))
+            },
+            _ => {
+                Log::new(ERR, expr.span, "Only variables and fields are supported by the code generator yet", "").push();
+                None
+            },
+        }
+    }
+
+    // splits an address into the parts of a memory operand. Sums of registers, constants and
+    // register * 1, 2, 4 or 8 map onto it directly, one more term may be computed at runtime.
+    // Anything else is computed as a whole and dereferenced as [scratch]
+    fn address<'e>(&self, addr: &'e Expr) -> Address<'e> {
+        let mut terms = Vec::new();
+        let mut disp = 0;
+        terms_of(addr, &mut terms, &mut disp);
+
+        let mut regs = Vec::new();
+        let mut runtime = Vec::new();
+        for (term, scale) in terms {
+            match self.qword_reg(term) {
+                Some(reg) => regs.push((reg, scale)),
+                None => runtime.push((term, scale)),
+            }
+        }
+
+        let parts = regs.len() + runtime.len();
+        let scaled = regs.iter().map(|r| r.1).chain(runtime.iter().map(|r| r.1)).filter(|&s| s > 1).count();
+        match i32::try_from(disp) {
+            Ok(disp) if runtime.len() <= 1 && parts <= 2 && scaled <= 1 => Address {
+                regs,
+                disp: disp as i64,
+                runtime: runtime.pop(),
+            },
+            _ => Address {
+                regs: Vec::new(),
+                disp: 0,
+                runtime: Some((addr, 1)),
+            },
+        }
+    }
+
+    // a full width register that can be used in an address as it is
+    fn qword_reg(&self, expr: &Expr) -> Option<VReg> {
+        let reg = match &expr.kind {
+            ExprKind::Ident(name) => self.frame().reg(name)?.reg,
+            ExprKind::Register(reg) => *reg,
+            _ => return None,
+        };
+        match reg.size {
+            RegSize::QWord if (reg.num as usize) < regalloc::available() => Some(VReg(reg.num as u16)),
+            _ => None,
+        }
+    }
+
+    // the part of an address that has to be computed before it can be used, see index()
+    fn runtime_part<'e>(&self, expr: &'e Expr) -> Option<&'e Expr> {
+        match &expr.kind {
+            ExprKind::ArrIndex(_, index) if !matches!(index.kind, ExprKind::Int(_)) => Some(index),
+            ExprKind::Deref(addr) => self.address(addr).runtime.map(|(term, _)| term),
+            _ => None,
+        }
+    }
+
+    // evaluates the runtime part of an address into ACC. Array indexes are multiplied by the
+    // element size when no addressing mode scale can do that
+    fn index(&mut self, expr: &Expr) {
+        let Some(index) = self.runtime_part(expr) else { return };
+        let ExprKind::ArrIndex(name, _) = &expr.kind else {
+            return self.expr_as(index, 8);
+        };
+        let Some(Type::Array(elem, len)) = self.frame().slot(name).map(|s| s.ty.clone()) else { return };
+
+        self.expr_as(index, 8);
+        if let (true, Some(len)) = (unsafe { ARGS.bounds_check }, len) {
+            let ok = self.label("inbounds");
+            self.emit(Inst::Branch(Cond::Ult, ACC.q(), Operand::Imm(len as i64), ok.clone()));
+            self.emit(Inst::Trap);
+            self.place(ok);
+        }
+
+        let size = self.types.size_of(&elem);
+        if scale_of(size) as u64 != size {
+            self.emit(Inst::Bin(ir::BinOp::Mul, ACC.q(), Operand::Imm(size as i64)));
+        }
+    }
+
+    // writes `value` into memory of type `ty`, data blocks field by field
+    fn init(&mut self, mem: Mem, ty: &Type, value: &Expr) {
+        let types = self.types;
+        match (ty, &value.kind) {
+            (ty, ExprKind::Fill(n)) => self.fill(mem, ty, *n, value.span),
+            (Type::Named(name), ExprKind::Array(elems)) => {
+                let Some(block) = types.block(name) else { return };
+                for ((field, offset), elem) in block.fields.iter().zip(types.offsets(block)).zip(elems) {
+                    self.init(offset_mem(&mem, offset, types.size_of(&field.ty)), &field.ty, elem);
+                }
+            },
+            // elements past the end of the literal are left as they are
+            (Type::Array(elem_ty, _), ExprKind::Array(elems)) => {
+                let size = types.size_of(elem_ty);
+                for (i, elem) in elems.iter().enumerate() {
+                    self.init(offset_mem(&mem, i as u64 * size, size), elem_ty, elem);
+                }
+            },
+            (Type::Named(_) | Type::Array(..), _) => {
+                self.index(value);
+                let Some((src, _)) = self.lvalue(value, ACC) else { return };
+                self.copy(mem, src, types.size_of(ty));
+            },
+            (ty, ExprKind::Int(n)) if bits_of(ty).is_none() && imm_fits(*n, mem.size) => {
+                self.emit(Inst::Mov(Operand::Mem(mem.clone()), Operand::Imm(truncate(*n, mem.size))));
+            },
+            (ty, _) => {
+                self.expr_as(value, mem.size as u64);
+                self.store(mem, bits_of(ty));
+            },
+        }
+    }
+
+    // writes `n` into every number within `ty`, arrays of plain numbers in one go
+    fn fill(&mut self, mem: Mem, ty: &Type, n: i128, span: Span) {
+        let types = self.types;
+        match ty {
+            Type::Named(name) => {
+                let Some(block) = types.block(name) else { return };
+                for (field, offset) in block.fields.iter().zip(types.offsets(block)) {
+                    self.fill(offset_mem(&mem, offset, types.size_of(&field.ty)), &field.ty, n, span);
+                }
+            },
+            Type::Array(elem, Some(len)) if matches!(**elem, Type::Size(_) | Type::Signed(_)) => {
+                self.load_imm(n);
+                self.emit(Inst::Fill(offset_mem(&mem, 0, types.size_of(elem)), ACC, *len));
+            },
+            Type::Array(elem, len) => {
+                let size = types.size_of(elem);
+                for i in 0..len.unwrap_or(0) {
+                    self.fill(offset_mem(&mem, i * size, size), elem, n, span);
+                }
+            },
+            ty => self.init(mem, ty, &Expr {
+                kind: ExprKind::Int(n),
+                span,
+            }),
+        }
+    }
+
+    // copies `size` bytes through TMP, in the widest moves that fit
+    fn copy(&mut self, dst: Mem, src: Mem, size: u64) {
+        let mut done = 0;
+        while done < size {
+            let chunk = [8, 4, 2, 1].into_iter().find(|&c| c <= size - done).unwrap();
+            self.emit(Inst::Mov(TMP.sized(chunk as u8), Operand::Mem(offset_mem(&src, done, chunk))));
+            self.emit(Inst::Mov(Operand::Mem(offset_mem(&dst, done, chunk)), TMP.sized(chunk as u8)));
+            done += chunk;
+        }
+    }
+
+    // stores ACC into memory, truncated to its size. Bit fields keep their range within the unit
+    fn store(&mut self, mem: Mem, bits: Option<(u8, u8)>) {
+        match bits {
+            Some((lo, hi)) if hi - lo < 64 => self.store_bits(mem, lo, hi),
+            _ => {
+                let size = mem.size;
+                self.emit(Inst::Mov(Operand::Mem(mem), ACC.sized(size)));
+            },
+        }
+    }
+
+    // read-modify-write of bits lo..hi of a unit, done on the memory itself so an index register
+    // in `mem` stays intact. The rest of the unit keeps its bits
+    fn store_bits(&mut self, mem: Mem, lo: u8, hi: u8) {
+        let width = hi - lo;
+
+        // ACC = (ACC & mask) << lo, the shifts drop the bits that don't fit
+        self.emit(Inst::Bin(ir::BinOp::Shl, ACC.q(), Operand::Imm(64 - width as i64)));
+        if hi < 64 {
+            self.emit(Inst::Bin(ir::BinOp::Shr, ACC.q(), Operand::Imm(64 - hi as i64)));
+        }
+
+        // clear the range, an 8 byte unit whose mask doesn't fit an imm32 one half at a time
+        let keep = !(((1u64 << width) - 1) << lo);
+        match (mem.size, i32::try_from(keep as i64)) {
+            (8, Err(_)) => {
+                for half in 0..2 {
+                    let part = (keep >> (half * 32)) as u32;
+                    if part != u32::MAX {
+                        let dst = Operand::Mem(offset_mem(&mem, half * 4, 4));
+                        self.emit(Inst::Bin(ir::BinOp::And, dst, Operand::Imm(part as i32 as i64)));
+                    }
+                }
+            },
+            (size, _) => {
+                let keep = Operand::Imm(truncate(keep as i128, size));
+                self.emit(Inst::Bin(ir::BinOp::And, Operand::Mem(mem.clone()), keep));
+            },
+        }
+
+        let size = mem.size;
+        self.emit(Inst::Bin(ir::BinOp::Or, Operand::Mem(mem), ACC.sized(size)));
+    }
+
+    // ACC = bits lo..hi of ACC, shifted down to bit 0
+    fn extract_bits(&mut self, lo: u8, hi: u8) {
+        if hi < 64 {
+            self.emit(Inst::Bin(ir::BinOp::Shl, ACC.q(), Operand::Imm(64 - hi as i64)));
+        }
+        if hi - lo < 64 {
+            self.emit(Inst::Bin(ir::BinOp::Shr, ACC.q(), Operand::Imm(64 - (hi - lo) as i64)));
+        }
+    }
+
+    // the fixed mapping has only as many registers as the target
+    fn vreg(&self, reg: Register, span: Span) -> Option<VReg> {
+        if reg.num as usize >= regalloc::available() {
+            Log::new(ERR, span, format!("r{} has no x86_64 register", reg.num),
+                format!("Only r0 to r{} are available with -R", regalloc::available() - 1)).push();
+            return None;
+        }
+        Some(VReg(reg.num as u16))
+    }
+
+    // loads a register into ACC, sign or zero extended
+    fn read_reg(&mut self, reg: Register, signed: bool, span: Span) {
+        let Some(vreg) = self.vreg(reg, span) else { return };
+        let size = reg_bytes(reg.size);
+        match (reg.size, signed) {
+            (RegSize::QWord, _) => self.emit(Inst::Mov(ACC.q(), vreg.q())),
+            (RegSize::DWord, false) => self.emit(Inst::Mov(ACC.sized(4), vreg.sized(4))),
+            (RegSize::Word | RegSize::ByteLow, false) => self.emit(Inst::Ext(ACC.sized(4), vreg.sized(size), false)),
+            (RegSize::DWord | RegSize::Word | RegSize::ByteLow, true) => self.emit(Inst::Ext(ACC.q(), vreg.sized(size), true)),
+            // bits 8 to 15, shifted down
+            (RegSize::ByteHigh, false) => {
+                self.emit(Inst::Mov(ACC.q(), vreg.q()));
+                self.emit(Inst::Bin(ir::BinOp::Shr, ACC.q(), Operand::Imm(8)));
+                self.emit(Inst::Ext(ACC.sized(4), ACC.sized(1), false));
+            },
+            (RegSize::ByteHigh, true) => {
+                self.emit(Inst::Mov(ACC.q(), vreg.q()));
+                self.emit(Inst::Bin(ir::BinOp::Shl, ACC.q(), Operand::Imm(48)));
+                self.emit(Inst::Bin(ir::BinOp::Sar, ACC.q(), Operand::Imm(56)));
+            },
+        }
+    }
+
+    // stores ACC into a register, truncated to the register's size
+    fn write_reg(&mut self, reg: Register, span: Span) {
+        let Some(vreg) = self.vreg(reg, span) else { return };
+        match reg.size {
+            RegSize::ByteHigh => {
+                self.emit(Inst::Ext(ACC.sized(4), ACC.sized(1), false));
+                self.emit(Inst::Bin(ir::BinOp::Shl, ACC.sized(4), Operand::Imm(8)));
+                self.emit(Inst::Bin(ir::BinOp::And, vreg.q(), Operand::Imm(!0xff00)));
+                self.emit(Inst::Bin(ir::BinOp::Or, vreg.q(), ACC.q()));
+            },
+            size => {
+                let size = reg_bytes(size);
+                self.emit(Inst::Mov(vreg.sized(size), ACC.sized(size)));
+            },
+        }
+    }
+
+    //
+    // expressions
+    // evaluates an expression into ACC with derefs loading `width` bytes
+    fn expr_as(&mut self, expr: &Expr, width: u64) {
+        let old = std::mem::replace(&mut self.width, width);
+        self.expr(expr);
+        self.width = old;
+    }
+
+    // evaluates an expression into ACC, sign or zero extended to 64 bits by its type
+    fn expr(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Int(n) => self.load_imm(*n),
+
+            ExprKind::Ident(_) | ExprKind::StructIndex(..) | ExprKind::ArrIndex(..) | ExprKind::Deref(_) => {
+                if let ExprKind::Ident(name) = &expr.kind {
+                    if let Some(var) = self.frame().reg(name) {
+                        self.read_reg(var.reg, var.signed, expr.span);
+                        return;
+                    }
+                }
+                self.index(expr);
+                let Some((mem, ty)) = self.lvalue(expr, ACC) else { return };
+                if matches!(ty, Type::Named(_) | Type::Array(..)) {
+                    Log::new(ERR, expr.span, format!("A `{}` doesn't fit into a register", ty), "").push();
+                    return;
+                }
+                let signed = self.types.is_signed(self.frame(), expr);
+                self.load(ACC, mem, signed);
+                if let Some((lo, hi)) = bits_of(&ty) {
+                    self.extract_bits(lo, hi);
+                }
+            },
+
+            ExprKind::Register(reg) => self.read_reg(*reg, false, expr.span),
+
+            ExprKind::SizeOf(ty) => self.load_imm(self.types.size_of(ty) as i128),
+
+            ExprKind::OffsetOf(name, field) => {
+                let Some(block) = self.types.block(name) else { return };
+                let Some(i) = self.types.field_index(block, field) else { return };
+                self.load_imm(self.types.offsets(block)[i] as i128);
+            },
+
+            // constant arrays outside of initializers live in .data, `{&a, &b}` makes a jump table
+            ExprKind::Array(elems) => {
+                let label = self.label("array");
+                let words = elems.iter()
+                    .map(|e| match e.kind {
+                        ExprKind::Int(n) => Word::Int(truncate(n, 8)),
+                        ExprKind::LabelAddr(ref label) => Word::Label(label.clone()),
+                        _ => unreachable!("checked by typeck"),
+                    })
+                    .collect();
+                self.out.data.push((label.clone(), words));
+                self.emit(Inst::Addr(ACC.q(), Mem::sym(8, label)));
+            },
+
+            // equal strings share their bytes
+            ExprKind::Str(string) => {
+                let i = match self.out.strings.iter().position(|s| s == string) {
+                    Some(i) => i,
+                    None => {
+                        self.out.strings.push(string.clone());
+                        self.out.strings.len() - 1
+                    },
+                };
+                self.emit(Inst::Addr(ACC.q(), Mem::sym(8, format!(".Lstr{}", i))));
+            },
+
+            ExprKind::LabelAddr(label) => self.emit(Inst::Addr(ACC.q(), Mem::sym(8, label.clone()))),
+
+            ExprKind::Fill(_) => unreachable!("fills only initialize"),
+
+            ExprKind::Not(inner) => {
+                self.expr(inner);
+                self.emit(Inst::Un(UnOp::Not, ACC.q()));
+            },
+
+            ExprKind::Binary(op, lhs, rhs) => {
+                let signed = self.types.is_signed(self.frame(), lhs) || self.types.is_signed(self.frame(), rhs);
+                let rhs = self.operands(lhs, rhs);
+                self.binop(*op, rhs, signed);
+            },
+
+            ExprKind::Call(callee, args) => self.call(callee, args, true),
+        }
+    }
+
+    // ACC = its low `size` bytes, sign or zero extended
+    fn extend(&mut self, size: u64, signed: bool) {
+        let part = ACC.sized(size as u8);
+        match (size, signed) {
+            (4, false) => self.emit(Inst::Mov(part.clone(), part)),
+            (_, false) => self.emit(Inst::Ext(ACC.sized(4), part, false)),
+            (_, true) => self.emit(Inst::Ext(ACC.q(), part, true)),
+        }
+    }
+
+    // evaluates the left operand into ACC and hands back the right one, which is either simple
+    // or evaluated into TMP. A deref on one side is as wide as the other side
+    fn operands(&mut self, lhs: &Expr, rhs: &Expr) -> Operand {
+        let lhs_width = self.types.width(self.frame(), rhs).unwrap_or(self.width);
+        let rhs_width = self.types.width(self.frame(), lhs).unwrap_or(self.width);
+        self.expr_as(lhs, lhs_width);
+        match self.simple_operand(rhs, rhs_width) {
+            Some(rhs) => rhs,
+            None => {
+                self.emit(Inst::Push(ACC.q()));
+                self.expr_as(rhs, rhs_width);
+                self.emit(Inst::Mov(TMP.q(), ACC.q()));
+                self.emit(Inst::Pop(ACC.q()));
+                TMP.q()
+            },
+        }
+    }
+
+    fn load_imm(&mut self, n: i128) {
+        match u32::try_from(n) {
+            Ok(n) => self.emit(Inst::Mov(ACC.sized(4), Operand::Imm(n as i64))),
+            Err(_) => self.emit(Inst::Mov(ACC.q(), Operand::Imm(n as i64))),
+        }
+    }
+
+    fn load(&mut self, dst: VReg, mem: Mem, signed: bool) {
+        match (mem.size, signed) {
+            (8, _) => self.emit(Inst::Mov(dst.q(), Operand::Mem(mem))),
+            (4, false) => self.emit(Inst::Mov(dst.sized(4), Operand::Mem(mem))),
+            (_, false) => self.emit(Inst::Ext(dst.sized(4), Operand::Mem(mem), false)),
+            (_, true) => self.emit(Inst::Ext(dst.q(), Operand::Mem(mem), true)),
+        }
+    }
+
+    // an operand that can be used as is, without evaluating it into a register first
+    fn simple_operand(&self, expr: &Expr, width: u64) -> Option<Operand> {
+        match &expr.kind {
+            ExprKind::Deref(_) if width == 8 && self.runtime_part(expr).is_none() => {
+                self.lvalue(expr, ACC).map(|(mem, _)| Operand::Mem(Mem { size: 8, ..mem }))
+            },
+            ExprKind::Int(n) if i32::try_from(*n).is_ok() => Some(Operand::Imm(*n as i64)),
+            ExprKind::Ident(name) => match self.frame().reg(name) {
+                Some(var) if var.reg.size == RegSize::QWord => self.vreg(var.reg, expr.span).map(VReg::q),
+                Some(_) => None,
+                None => self.frame().slot(name)
+                    .filter(|slot| slot.size == 8 && matches!(slot.ty, Type::Size(_) | Type::Signed(_)))
+                    .and_then(|_| self.var_mem(name, expr.span))
+                    .map(Operand::Mem),
+            },
+            ExprKind::Register(reg) if reg.size == RegSize::QWord => self.vreg(*reg, expr.span).map(VReg::q),
+            _ => None,
+        }
+    }
+
+    // ACC = ACC op rhs
+    fn binop(&mut self, op: BinOp, rhs: Operand, signed: bool) {
+        let acc = ACC.q();
+        match op {
+            BinOp::Add => self.emit(Inst::Bin(ir::BinOp::Add, acc, rhs)),
+            BinOp::Sub => self.emit(Inst::Bin(ir::BinOp::Sub, acc, rhs)),
+            BinOp::And => self.emit(Inst::Bin(ir::BinOp::And, acc, rhs)),
+            BinOp::Or => self.emit(Inst::Bin(ir::BinOp::Or, acc, rhs)),
+            BinOp::Xor => self.emit(Inst::Bin(ir::BinOp::Xor, acc, rhs)),
+            BinOp::Mul => {
+                self.load_tmp(rhs);
+                self.emit(Inst::Bin(ir::BinOp::Mul, acc, TMP.q()));
+            },
+            BinOp::AndNot => {
+                self.load_tmp(rhs);
+                self.emit(Inst::Un(UnOp::Not, TMP.q()));
+                self.emit(Inst::Bin(ir::BinOp::And, acc, TMP.q()));
+            },
+            BinOp::Div | BinOp::Mod => {
+                self.load_tmp(rhs);
+                self.emit(Inst::Div(ACC, TMP, signed, op == BinOp::Mod));
+            },
+            BinOp::Gt | BinOp::Lt | BinOp::Ge | BinOp::Le | BinOp::Eq | BinOp::Ne => {
+                self.set_acc(cond_of(op, signed), acc, rhs);
+            },
+            BinOp::LogAnd | BinOp::LogXor => {
+                self.load_tmp(rhs);
+                self.emit(Inst::Set(Cond::Ne, TMP.sized(1), TMP.q(), Operand::Imm(0)));
+                self.set_acc(Cond::Ne, acc, Operand::Imm(0));
+                let op = match op {
+                    BinOp::LogAnd => ir::BinOp::And,
+                    _ => ir::BinOp::Xor,
+                };
+                self.emit(Inst::Bin(op, ACC.sized(1), TMP.sized(1)));
+            },
+            BinOp::LogOr => {
+                self.emit(Inst::Bin(ir::BinOp::Or, acc.clone(), rhs));
+                self.set_acc(Cond::Ne, acc, Operand::Imm(0));
+            },
+        }
+    }
+
+    // ACC = lhs cond rhs ? 1 : 0
+    fn set_acc(&mut self, cond: Cond, lhs: Operand, rhs: Operand) {
+        self.emit(Inst::Set(cond, ACC.sized(1), lhs, rhs));
+        self.emit(Inst::Ext(ACC.sized(4), ACC.sized(1), false));
+    }
+
+    // moves an operand into TMP unless it already is there
+    fn load_tmp(&mut self, operand: Operand) {
+        if operand != TMP.q() {
+            self.emit(Inst::Mov(TMP.q(), operand));
+        }
+    }
+}
+
+fn cond_of(op: BinOp, signed: bool) -> Cond {
+    match (op, signed) {
+        (BinOp::Gt, false) => Cond::Ugt,
+        (BinOp::Lt, false) => Cond::Ult,
+        (BinOp::Ge, false) => Cond::Uge,
+        (BinOp::Le, false) => Cond::Ule,
+        (BinOp::Gt, true) => Cond::Sgt,
+        (BinOp::Lt, true) => Cond::Slt,
+        (BinOp::Ge, true) => Cond::Sge,
+        (BinOp::Le, true) => Cond::Sle,
+        (BinOp::Eq, _) => Cond::Eq,
+        (BinOp::Ne, _) => Cond::Ne,
+        _ => unreachable!(),
+    }
+}
+
+fn bits_of(ty: &Type) -> Option<(u8, u8)> {
+    match ty {
+        Type::Bits(lo, hi) => Some((*lo, *hi)),
+        _ => None,
+    }
+}
+
+// `size` bytes at `offset` into `mem`
+fn offset_mem(mem: &Mem, offset: u64, size: u64) -> Mem {
+    Mem {
+        size: size.min(8) as u8,
+        disp: mem.disp + offset as i64,
+        ..mem.clone()
+    }
+}
+
+// flattens a sum into (term, scale) pairs and a constant displacement
+fn terms_of<'e>(expr: &'e Expr, terms: &mut Vec<(&'e Expr, u8)>, disp: &mut i128) {
+    match &expr.kind {
+        ExprKind::Int(n) => *disp += n,
+        ExprKind::Binary(BinOp::Add, lhs, rhs) => {
+            terms_of(lhs, terms, disp);
+            terms_of(rhs, terms, disp);
+        },
+        ExprKind::Binary(BinOp::Sub, lhs, rhs) if matches!(rhs.kind, ExprKind::Int(_)) => {
+            terms_of(lhs, terms, disp);
+            let ExprKind::Int(n) = rhs.kind else { unreachable!() };
+            *disp -= n;
+        },
+        ExprKind::Binary(BinOp::Mul, lhs, rhs) => match (&lhs.kind, &rhs.kind) {
+            (_, ExprKind::Int(s @ (1 | 2 | 4 | 8))) => terms.push((lhs, *s as u8)),
+            (ExprKind::Int(s @ (1 | 2 | 4 | 8)), _) => terms.push((rhs, *s as u8)),
+            _ => terms.push((expr, 1)),
+        },
+        _ => terms.push((expr, 1)),
+    }
+}
+
+// element sizes an addressing mode can scale by on its own
+fn scale_of(size: u64) -> u8 {
+    match size {
+        1 | 2 | 4 | 8 => size as u8,
+        _ => 1,
+    }
+}
+
+fn in_place_op(op: MutateOp) -> ir::BinOp {
+    match op {
+        MutateOp::Add => ir::BinOp::Add,
+        MutateOp::Sub => ir::BinOp::Sub,
+        MutateOp::And => ir::BinOp::And,
+        MutateOp::Or => ir::BinOp::Or,
+        MutateOp::Xor => ir::BinOp::Xor,
+        _ => unreachable!("not a two operand instruction"),
+    }
+}
+
+fn shift_op(op: MutateOp, signed: bool) -> ir::BinOp {
+    match (op, signed) {
+        (MutateOp::Shl, _) => ir::BinOp::Shl,
+        (_, true) => ir::BinOp::Sar,
+        (_, false) => ir::BinOp::Shr,
+    }
+}
+
+// whether `n` can be stored into `size` bytes of memory as an immediate
+fn imm_fits(n: i128, size: u8) -> bool { size < 8 || i32::try_from(n).is_ok() }
+
+// the bits of `n` that end up in `size` bytes, as the assembler expects them
+fn truncate(n: i128, size: u8) -> i64 {
+    match size {
+        8 => n as i64,
+        s => (n & ((1 << (s * 8)) - 1)) as i64,
+    }
+}
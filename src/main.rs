@@ -2,17 +2,30 @@ mod args_parser;
 mod logger;
 mod utils;
 mod defs;
-// mod parser;
-// mod compiler;
+mod parser;
+mod compiler;
 mod location;
 mod token;
 mod lexer;
+mod ast;
+mod expand;
+mod frame;
+mod ir;
+mod cfg;
+mod lint;
+mod lower;
+mod opt;
+mod peephole;
+mod regalloc;
+mod typeck;
+mod x86_64;
 
 
 pub use logger::{Log, Level, WARN, DEBUG, OK, ERR, FATAL};
 pub use location::Location;
 use lexer::Lexer;
-use args_parser::ARGS;
+use parser::Parser;
+use args_parser::{ARGS, Emit};
 // use defs::TEMP_FILE;
 
 fn main() {
@@ -31,23 +44,58 @@ fn main() {
         Log::new(DEBUG, None, "", format!("{}", token)).print();
     }
 
-    // let output = compiler::compiler(token_stream);
-    //
-    // unsafe{logger::check_err();}
+    let program = Parser::new(token_stream).parse();
+    Log::print_all();
+
+    let program = expand::expand(program);
+    Log::print_all();
 
+    let mut layout = frame::layout(&program);
+    Log::print_all();
 
+    let types = typeck::check(&program, &mut layout);
+    layout.track_pushes(&program, &types);
+    Log::print_all();
+    layout.assign_offsets(&types);
 
+    let mut ir = lower::lower(&program, &layout, &types);
+    Log::print_all();
+    ir::verify(&ir, "lowering", false);
+    Log::print_all();
+
+    lint::check(&program, &layout, &cfg::Cfg::new(&ir));
+    Log::print_all();
+
+    opt::optimize(&mut ir);
+    Log::print_all();
+
+    if unsafe{ARGS.emit} == Some(Emit::Ir) {
+        utils::writer(unsafe{ARGS.outfile}, &ir.to_string());
+        log!(OK, "IR written to `{}`", unsafe{ARGS.outfile}).print();
+        std::process::exit(0);
+    }
+    if unsafe{ARGS.emit} == Some(Emit::Cfg) {
+        utils::writer(unsafe{ARGS.outfile}, &cfg::Cfg::new(&ir).dot());
+        log!(OK, "CFG written to `{}`", unsafe{ARGS.outfile}).print();
+        std::process::exit(0);
+    }
+
+    let homes = regalloc::allocate(&mut ir, &mut layout);
+    ir::verify(&ir, "register allocation", true);
+    Log::print_all();
+
+    let output = compiler::compiler(&ir, &layout, &homes);
+    Log::print_all();
+
+    log!(DEBUG, "asm output:\n{}", &output).print();
+
+    if unsafe{ARGS.asm} {
+        utils::writer(unsafe{ARGS.outfile}, &output);
+        log!(OK, "Asm output written to `{}`", unsafe{ARGS.outfile}).print();
+        std::process::exit(0);
+    }
 
-    // log!(DEBUG, "asm output:\n{:?}", &output);
-    //
-    // if unsafe{ARGS.asm} {
-    //     utils::writer(unsafe{ARGS.outfile}, &output);
-    //     Log::print_all();
-    //     log!(NOW, OK, "Asm output written to `{}`", unsafe{ARGS.outfile});
-    //     std::process::exit(0);
-    // }
-    //
-    // log!(FATAL, "assembler not yet implemented");
+    log!(FATAL, "assembler not yet implemented").print();
 
     Log::print_all();
 }
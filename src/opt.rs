@@ -0,0 +1,1035 @@
+use std::collections::HashMap;
+
+use crate::args_parser::{Passes, ARGS};
+use crate::ast::Name;
+use crate::cfg::{self, Cfg};
+use crate::ir::{self, Base, BinOp, Cond, Inst, Mem, Operand, Program, UnOp, VReg, TMP};
+use crate::regalloc::SCRATCH;
+
+// Passes over the IR between the lint and register allocation. -O picks which of them run, --opt
+// and --no-opt turn single ones on or off. -O2 goes over them again while any changes anything.
+// The verifier checks the IR after each one.
+//
+//   const-prop   registers known to hold a constant are read as immediates where an instruction
+//                takes one
+//   fold         instructions whose result is known become moves of it, branches that always
+//                or never go become a jump or nothing, and no-ops such as `add r, 0` go
+//   copy-prop    after `mov a, b` reads of a are made of b, until either is written
+//   dse          stores into variables and symbols the block overwrites before anything may
+//                read them go
+//   dce          blocks nothing reaches and writes to registers nothing reads go
+//   jump-thread  jumps to a block that only jumps on or falls through go where it leads, and
+//                ones to the block below go
+//
+// Registers are shared by the whole program, so whatever looks at more than a block follows the
+// control-flow graph through calls and jumps into other functions. A register is known to hold
+// a constant where every way to it leaves the same one there. A call changes only the register
+// it returns in, the backend saves the rest around it.
+
+struct Pass {
+    name: &'static str,
+    level: u8, // the lowest -O it runs at
+    toggle: fn(&Passes) -> Option<bool>,
+    run: fn(&mut Program) -> bool, // whether it changed anything
+}
+
+const PASSES: [Pass; 6] = [
+    Pass { name: "const-prop", level: 1, toggle: |p| p.const_prop, run: const_prop },
+    Pass { name: "fold", level: 1, toggle: |p| p.fold, run: fold },
+    Pass { name: "copy-prop", level: 2, toggle: |p| p.copy_prop, run: copy_prop },
+    Pass { name: "dse", level: 2, toggle: |p| p.dse, run: dse },
+    Pass { name: "dce", level: 1, toggle: |p| p.dce, run: dce },
+    Pass { name: "jump-thread", level: 1, toggle: |p| p.jump_thread, run: jump_thread },
+];
+
+// how often -O2 goes over the passes at most
+const ROUNDS: usize = 8;
+
+pub fn optimize(program: &mut Program) {
+    let (level, toggles) = unsafe { (ARGS.opt_level, ARGS.passes) };
+    let passes = PASSES.iter()
+        .filter(|p| (p.toggle)(&toggles).unwrap_or(level >= p.level))
+        .collect::<Vec<_>>();
+    let rounds = if level >= 2 { ROUNDS } else { 1 };
+    for _ in 0..rounds {
+        let mut changed = false;
+        for pass in &passes {
+            changed |= (pass.run)(program);
+            ir::verify(program, &format!("the {} pass", pass.name), false);
+        }
+        if !changed {
+            break;
+        }
+    }
+}
+
+//
+// constants
+// the registers known to hold a constant in their low 1, 2, 4 or all 8 bytes
+type Consts = HashMap<VReg, (i64, u8)>;
+
+fn mask(size: u8) -> i64 {
+    match size {
+        8 => -1,
+        size => (1 << (8 * size)) - 1,
+    }
+}
+
+fn zext(n: i64, size: u8) -> i64 { n & mask(size) }
+
+fn sext(n: i64, size: u8) -> i64 {
+    let shift = 64 - 8 * size as u32;
+    (n << shift) >> shift
+}
+
+fn value(op: &Operand, known: &Consts) -> Option<i64> {
+    match op {
+        Operand::Reg(reg, size) => known.get(reg).filter(|(_, bytes)| bytes >= size).map(|&(n, _)| zext(n, *size)),
+        Operand::Imm(n) => Some(*n),
+        Operand::Mem(_) => None,
+    }
+}
+
+// what's known of a register after writing n into size bytes of it, given what was before
+fn written(old: Option<(i64, u8)>, n: i64, size: u8) -> (i64, u8) {
+    match (size, old) {
+        (8, _) => (n, 8),
+        (4, _) => (zext(n, 4), 8),
+        (_, Some((old, bytes))) if bytes >= size => (old & !mask(size) | zext(n, size), bytes),
+        _ => (zext(n, size), size),
+    }
+}
+
+fn compare(cond: Cond, a: i64, b: i64, size: u8) -> bool {
+    let (ua, ub) = (zext(a, size) as u64, zext(b, size) as u64);
+    let (sa, sb) = (sext(a, size), sext(b, size));
+    match cond {
+        Cond::Eq => ua == ub,
+        Cond::Ne => ua != ub,
+        Cond::Ult => ua < ub,
+        Cond::Ule => ua <= ub,
+        Cond::Ugt => ua > ub,
+        Cond::Uge => ua >= ub,
+        Cond::Slt => sa < sb,
+        Cond::Sle => sa <= sb,
+        Cond::Sgt => sa > sb,
+        Cond::Sge => sa >= sb,
+    }
+}
+
+// like x86_64 does it, shift counts are masked to 5 bits below 8 bytes
+fn binary(op: BinOp, a: i64, b: i64, size: u8) -> i64 {
+    let count = (b & if size == 8 { 63 } else { 31 }) as u32;
+    match op {
+        BinOp::Add => a.wrapping_add(b),
+        BinOp::Sub => a.wrapping_sub(b),
+        BinOp::Mul => a.wrapping_mul(b),
+        BinOp::And => a & b,
+        BinOp::Or => a | b,
+        BinOp::Xor => a ^ b,
+        BinOp::Shl => a << count,
+        BinOp::Shr => ((zext(a, size) as u64) >> count) as i64,
+        BinOp::Sar => sext(a, size) >> count,
+    }
+}
+
+// what's known of the register an instruction writes after it
+fn eval(inst: &Inst, known: &Consts) -> Option<(i64, u8)> {
+    let old = inst.writes().and_then(|reg| known.get(&reg).copied());
+    let (n, size) = match inst {
+        Inst::Mov(Operand::Reg(_, size), src) => (value(src, known)?, *size),
+        Inst::Ext(Operand::Reg(_, size), src, signed) => {
+            let (n, from) = (value(src, known)?, src.size()?);
+            (if *signed { sext(n, from) } else { zext(n, from) }, *size)
+        },
+        Inst::Bin(BinOp::Sub | BinOp::Xor, Operand::Reg(a, size), Operand::Reg(b, _)) if a == b => (0, *size),
+        Inst::Bin(BinOp::And | BinOp::Mul, Operand::Reg(_, size), src) if value(src, known).is_some_and(|n| zext(n, *size) == 0) => (0, *size),
+        Inst::Bin(op, dst @ Operand::Reg(_, size), src) => (binary(*op, value(dst, known)?, value(src, known)?, *size), *size),
+        Inst::Un(op, dst @ Operand::Reg(_, size)) => {
+            let n = value(dst, known)?;
+            let n = match op {
+                UnOp::Not => !n,
+                UnOp::Inc => n.wrapping_add(1),
+                UnOp::Dec => n.wrapping_sub(1),
+            };
+            (n, *size)
+        },
+        Inst::Set(cond, Operand::Reg(_, size), lhs, rhs) => {
+            let (a, b) = (value(lhs, known)?, value(rhs, known)?);
+            (compare(*cond, a, b, lhs.size().unwrap_or(8)) as i64, *size)
+        },
+        // dividing by 0 or the overflowing i64::MIN / -1 is left to trap
+        Inst::Div(dst, src, signed, rem) => {
+            let (a, b) = (value(&dst.q(), known)?, value(&src.q(), known)?);
+            let n = match (signed, rem) {
+                (true, false) => a.checked_div(b)?,
+                (true, true) => a.checked_rem(b)?,
+                (false, false) => (a as u64).checked_div(b as u64)? as i64,
+                (false, true) => (a as u64).checked_rem(b as u64)? as i64,
+            };
+            (n, 8)
+        },
+        _ => return None,
+    };
+    Some(written(old, n, size))
+}
+
+fn step(inst: &Inst, known: &mut Consts) {
+    if let Some(reg) = inst.writes() {
+        match eval(inst, known) {
+            Some(n) => known.insert(reg, n),
+            None => known.remove(&reg),
+        };
+    }
+}
+
+// what's known where each block is entered, from every way into it, in the order of the
+// graph's nodes. Nothing is known where control comes from outside the graph, and blocks nothing
+// reaches get None
+fn entry_consts(program: &Program) -> Vec<Option<Consts>> {
+    let cfg = Cfg::new(program);
+    let mut entry: Vec<Option<Consts>> = vec![None; cfg.nodes.len()];
+    let mut queue = cfg.roots();
+    for &root in &queue {
+        entry[root] = Some(Consts::new());
+    }
+    while let Some(i) = queue.pop() {
+        let mut known = entry[i].clone().unwrap_or_default();
+        for inst in &cfg.block(i).insts {
+            step(inst, &mut known);
+        }
+        for &(j, _) in &cfg.succs[i] {
+            let merged = match &entry[j] {
+                Some(old) => old.iter().filter(|(reg, n)| known.get(reg) == Some(n)).map(|(r, n)| (*r, *n)).collect(),
+                None => known.clone(),
+            };
+            if entry[j].as_ref() != Some(&merged) {
+                entry[j] = Some(merged);
+                queue.push(j);
+            }
+        }
+    }
+    entry
+}
+
+//
+// const-prop
+fn const_prop(program: &mut Program) -> bool {
+    let entry = entry_consts(program);
+    let mut changed = false;
+    let blocks = program.functions.iter_mut().flat_map(|f| &mut f.blocks);
+    for (block, known) in blocks.zip(entry) {
+        let Some(mut known) = known else { continue };
+        for inst in &mut block.insts {
+            changed |= substitute(inst, &known);
+            step(inst, &mut known);
+        }
+    }
+    changed
+}
+
+// known indexes become part of the displacement, and a known register an instruction reads
+// becomes an immediate where it takes one
+fn substitute(inst: &mut Inst, known: &Consts) -> bool {
+    let mut changed = false;
+    for mem in inst.mems_mut() {
+        let Some((reg, scale)) = mem.index else { continue };
+        let disp = value(&reg.q(), known)
+            .and_then(|n| n.checked_mul(scale as i64))
+            .and_then(|n| n.checked_add(mem.disp))
+            .filter(|&d| i32::try_from(d).is_ok());
+        if let (Some(disp), false) = (disp, mem.base == Base::None) {
+            mem.index = None;
+            mem.disp = disp;
+            changed = true;
+        }
+    }
+
+    // pushes and returns take 32 bit immediates, sign extended
+    let narrow = matches!(inst, Inst::Push(_) | Inst::Ret(_));
+    let mut new = inst.clone();
+    let src = match &mut new {
+        Inst::Mov(_, src) | Inst::Bin(_, _, src) | Inst::Set(_, _, _, src) | Inst::Branch(_, _, src, _) => src,
+        Inst::Push(src @ Operand::Reg(_, 8)) | Inst::Ret(Some(src @ Operand::Reg(_, 8))) => src,
+        _ => return changed,
+    };
+    let Some(n) = value(src, known).filter(|_| matches!(src, Operand::Reg(..))) else { return changed };
+    if narrow && i32::try_from(n).is_err() {
+        return changed;
+    }
+    *src = Operand::Imm(n);
+    if ir::check(&new).is_ok() {
+        *inst = new;
+        changed = true;
+    }
+    changed
+}
+
+//
+// fold
+enum Fold {
+    Same,
+    Into(Inst),
+    Gone,
+}
+
+fn fold(program: &mut Program) -> bool {
+    let entry = entry_consts(program);
+    let mut changed = false;
+    let blocks = program.functions.iter_mut().flat_map(|f| &mut f.blocks);
+    for (block, known) in blocks.zip(entry) {
+        let Some(mut known) = known else { continue };
+        let mut keep = Vec::new();
+        for inst in &mut block.insts {
+            let fold = fold_inst(inst, &known);
+            keep.push(!matches!(fold, Fold::Gone));
+            match fold {
+                Fold::Same => (),
+                Fold::Into(folded) => {
+                    *inst = folded;
+                    changed = true;
+                },
+                Fold::Gone => changed = true,
+            }
+            step(inst, &mut known);
+        }
+        let mut keep = keep.into_iter();
+        block.retain(|_| keep.next().unwrap_or(true));
+    }
+    changed
+}
+
+fn fold_inst(inst: &Inst, known: &Consts) -> Fold {
+    match inst {
+        Inst::Branch(cond, lhs, rhs, label) => match (value(lhs, known), value(rhs, known)) {
+            (Some(a), Some(b)) if compare(*cond, a, b, lhs.size().unwrap_or(8)) => Fold::Into(Inst::Jmp(label.clone())),
+            (Some(_), Some(_)) => Fold::Gone,
+            _ => Fold::Same,
+        },
+        // negative indexes are huge unsigned ones
+        Inst::Switch(reg, labels, otherwise) => match value(&reg.q(), known) {
+            Some(n) => {
+                let label = usize::try_from(n).ok().and_then(|i| labels.get(i)).unwrap_or(otherwise);
+                Fold::Into(Inst::Jmp(label.clone()))
+            },
+            None => Fold::Same,
+        },
+        Inst::Set(cond, dst @ Operand::Mem(_), lhs, rhs) => match (value(lhs, known), value(rhs, known)) {
+            (Some(a), Some(b)) => Fold::Into(Inst::Mov(dst.clone(), Operand::Imm(compare(*cond, a, b, lhs.size().unwrap_or(8)) as i64))),
+            _ => Fold::Same,
+        },
+        Inst::Bin(op, dst, Operand::Imm(n)) if is_noop(*op, dst, *n) => Fold::Gone,
+        // writing 4 bytes clears the upper ones
+        Inst::Mov(Operand::Reg(a, size), Operand::Reg(b, _)) if a == b && *size != 4 => Fold::Gone,
+        Inst::Mov(_, Operand::Imm(_)) => Fold::Same,
+        // a part of the register may be all that's known
+        _ => match (inst.writes(), eval(inst, known), inst.operands().first().and_then(|op| op.size())) {
+            (Some(reg), Some((n, 8)), _) => Fold::Into(load(reg, n)),
+            (Some(reg), Some((n, _)), Some(size)) => Fold::Into(Inst::Mov(reg.sized(size), Operand::Imm(zext(n, size)))),
+            _ => Fold::Same,
+        },
+    }
+}
+
+// leaves its destination as it was, which writing 4 bytes of a register doesn't
+fn is_noop(op: BinOp, dst: &Operand, n: i64) -> bool {
+    if matches!(dst, Operand::Reg(_, 4)) {
+        return false;
+    }
+    let size = dst.size().unwrap_or(8);
+    match op {
+        BinOp::Add | BinOp::Sub | BinOp::Or | BinOp::Xor => zext(n, size) == 0,
+        BinOp::Shl | BinOp::Shr | BinOp::Sar => n == 0,
+        BinOp::And => zext(n, size) == mask(size),
+        BinOp::Mul => n == 1,
+    }
+}
+
+// the shortest move of a constant into a whole register
+fn load(reg: VReg, n: i64) -> Inst {
+    match u32::try_from(n) {
+        Ok(_) => Inst::Mov(reg.sized(4), Operand::Imm(n)),
+        Err(_) => Inst::Mov(reg.q(), Operand::Imm(n)),
+    }
+}
+
+//
+// copy-prop
+// within a block. TMP isn't read in place of another register, the backend uses its home as
+// scratch, and neither is a register spilling would need a fourth scratch register for
+fn copy_prop(program: &mut Program) -> bool {
+    let mut changed = false;
+    for block in program.functions.iter_mut().flat_map(|f| &mut f.blocks) {
+        let mut copies: Vec<(VReg, VReg)> = Vec::new();
+        for inst in &mut block.insts {
+            if !copies.is_empty() {
+                let mut new = inst.clone();
+                map_reads(&mut new, &mut |r| copies.iter().find(|(a, _)| *a == r).map_or(r, |&(_, b)| b));
+                if new != *inst && shard_regs(&new) <= SCRATCH.len() {
+                    *inst = new;
+                    changed = true;
+                }
+            }
+            if let Some(reg) = inst.writes() {
+                copies.retain(|&(a, b)| a != reg && b != reg);
+            }
+            if let Inst::Mov(Operand::Reg(a, 8), Operand::Reg(b, 8)) = inst {
+                if a != b && *b != TMP {
+                    copies.push((*a, *b));
+                }
+            }
+        }
+    }
+    changed
+}
+
+// calls `f` on the registers an instruction only reads. Those of divisions, fills, switches and
+// calls through an address are left alone, the backend moves them around fixed registers
+fn map_reads(inst: &mut Inst, f: &mut impl FnMut(VReg) -> VReg) {
+    let addr = |op: &mut Operand, f: &mut dyn FnMut(VReg) -> VReg| {
+        if let Operand::Mem(mem) = op {
+            mem.map_regs(&mut |r| f(r));
+        }
+    };
+    match inst {
+        Inst::Mov(dst, src) | Inst::Ext(dst, src, _) | Inst::Bin(_, dst, src) => {
+            addr(dst, f);
+            src.map_regs(f);
+        },
+        Inst::Set(_, dst, lhs, rhs) => {
+            addr(dst, f);
+            lhs.map_regs(f);
+            rhs.map_regs(f);
+        },
+        Inst::Branch(_, lhs, rhs, _) => {
+            lhs.map_regs(f);
+            rhs.map_regs(f);
+        },
+        Inst::Un(_, dst) | Inst::Pop(dst) => addr(dst, f),
+        Inst::Addr(_, mem) | Inst::Param(mem, _) | Inst::Fill(mem, ..) => mem.map_regs(f),
+        Inst::Push(src) | Inst::JmpTo(src) | Inst::Exit(src) | Inst::Ret(Some(src)) => src.map_regs(f),
+        _ => (),
+    }
+}
+
+// the Shard registers an instruction mentions
+fn shard_regs(inst: &Inst) -> usize {
+    let mut regs = inst.regs().into_iter().chain(inst.writes()).filter(|r| !r.is_temp()).collect::<Vec<_>>();
+    regs.sort();
+    regs.dedup();
+    regs.len()
+}
+
+//
+// dse
+// within a block, from its end up. Memory through a register may be anything, and so may what
+// a call reads
+fn dse(program: &mut Program) -> bool {
+    let mut changed = false;
+    for block in program.functions.iter_mut().flat_map(|f| &mut f.blocks) {
+        let mut overwritten: Vec<Mem> = Vec::new();
+        let mut keep = vec![true; block.insts.len()];
+        for (i, inst) in block.insts.iter().enumerate().rev() {
+            let store = match inst {
+                Inst::Mov(Operand::Mem(mem), _) if mem.index.is_none() && matches!(mem.base, Base::Frame | Base::Sym(_)) => Some(mem),
+                _ => None,
+            };
+            if store.is_some_and(|mem| overwritten.iter().any(|o| covers(o, mem))) {
+                keep[i] = false;
+                continue;
+            }
+
+            let reads = inst.operands().into_iter().filter_map(|op| match op {
+                Operand::Mem(mem) if store.is_none_or(|s| !std::ptr::eq(s, mem)) => Some(mem),
+                _ => None,
+            });
+            for mem in reads.collect::<Vec<_>>() {
+                match mem.base {
+                    Base::Stack => (),
+                    Base::Frame | Base::Sym(_) => overwritten.retain(|o| !overlaps(o, mem)),
+                    Base::None | Base::Reg(_) => overwritten.clear(),
+                }
+            }
+            if let Inst::Call(..) = inst {
+                overwritten.clear();
+            }
+            overwritten.extend(store.cloned());
+        }
+        let mut keep = keep.into_iter();
+        changed |= block.retain(|_| keep.next().unwrap_or(true));
+    }
+    changed
+}
+
+fn covers(outer: &Mem, inner: &Mem) -> bool {
+    outer.base == inner.base && outer.disp <= inner.disp && inner.disp + inner.size as i64 <= outer.disp + outer.size as i64
+}
+
+// an index may reach anywhere in the variables or the symbol
+fn overlaps(a: &Mem, b: &Mem) -> bool {
+    a.base == b.base && (a.index.is_some() || b.index.is_some() || (a.disp < b.disp + b.size as i64 && b.disp < a.disp + a.size as i64))
+}
+
+//
+// dce
+fn dce(program: &mut Program) -> bool {
+    let (reachable, keep) = {
+        let cfg = Cfg::new(program);
+        let (ins, outs) = cfg.liveness();
+        let keep = (0..cfg.nodes.len())
+            .map(|i| {
+                let insts = &cfg.block(i).insts;
+                let mut live = outs[i];
+                let mut keep = vec![true; insts.len()];
+                for (k, inst) in insts.iter().enumerate().rev() {
+                    if pure(inst) && inst.writes().is_some_and(|reg| !cfg::contains(&live, reg)) {
+                        keep[k] = false;
+                        continue;
+                    }
+                    live = cfg.live_before(&ins, inst, live);
+                }
+                keep
+            })
+            .collect::<Vec<_>>();
+        (cfg.reachable(), keep)
+    };
+
+    let mut changed = false;
+    let mut node = 0;
+    for func in &mut program.functions {
+        let mut blocks = Vec::new();
+        for mut block in std::mem::take(&mut func.blocks) {
+            node += 1;
+            if !reachable[node - 1] {
+                changed = true;
+                continue;
+            }
+            let mut keep = keep[node - 1].iter();
+            changed |= block.retain(|_| *keep.next().unwrap_or(&true));
+            blocks.push(block);
+        }
+        func.blocks = blocks;
+    }
+    changed
+}
+
+// changes nothing but the register it writes. A load that would fault may go too
+fn pure(inst: &Inst) -> bool {
+    matches!(inst, Inst::Mov(Operand::Reg(..), _)
+        | Inst::Ext(..)
+        | Inst::Addr(..)
+        | Inst::Bin(_, Operand::Reg(..), _)
+        | Inst::Un(_, Operand::Reg(..))
+        | Inst::Set(_, Operand::Reg(..), ..))
+}
+
+//
+// jump-thread
+// within a function. A block leads on when it's only a jump, or empty and falls into the next
+// one. Jumps and branches go to where a chain of those ends, or stop in a loop of them
+fn jump_thread(program: &mut Program) -> bool {
+    let mut changed = false;
+    for func in &mut program.functions {
+        let blocks = &func.blocks;
+        let index = blocks.iter().enumerate().map(|(i, b)| (&b.label, i)).collect::<HashMap<_, _>>();
+        let leads = |i: usize| match blocks[i].insts.as_slice() {
+            [Inst::Jmp(label)] => Some(label),
+            [] => blocks.get(i + 1).map(|b| &b.label),
+            _ => None,
+        };
+        let mut ends: HashMap<Name, Name> = HashMap::new();
+        for (i, block) in blocks.iter().enumerate() {
+            let (mut at, mut seen) = (i, vec![i]);
+            let mut end = &block.label;
+            while let Some(next) = leads(at) {
+                end = next;
+                match index.get(next) {
+                    Some(&j) if !seen.contains(&j) => {
+                        seen.push(j);
+                        at = j;
+                    },
+                    _ => break,
+                }
+            }
+            if *end != block.label {
+                ends.insert(block.label.clone(), end.clone());
+            }
+        }
+
+        let labels = func.blocks.iter().map(|b| b.label.clone()).collect::<Vec<_>>();
+        for (i, block) in func.blocks.iter_mut().enumerate() {
+            let targets = match block.insts.last_mut() {
+                Some(Inst::Jmp(label) | Inst::Branch(.., label)) => vec![label],
+                Some(Inst::Switch(_, labels, otherwise)) => labels.iter_mut().chain([otherwise]).collect(),
+                _ => Vec::new(),
+            };
+            for target in targets {
+                if let Some(end) = ends.get(target) {
+                    *target = end.clone();
+                    changed = true;
+                }
+            }
+
+            // going where falling through goes anyway
+            let Some(next) = labels.get(i + 1) else { continue };
+            let falls = ends.get(next).unwrap_or(next);
+            let redundant = match block.insts.last() {
+                Some(Inst::Jmp(label)) => label == next,
+                Some(Inst::Branch(.., label)) => label == next || label == falls,
+                _ => false,
+            };
+            if redundant {
+                block.insts.pop();
+                changed = true;
+            }
+        }
+    }
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::tests::read;
+
+    // each case is the body of `main` before and after the pass, the same text where it mustn't
+    // change anything
+    fn check(pass: fn(&mut Program) -> bool, cases: &[(&str, &str)]) {
+        for &(before, after) in cases {
+            let mut program = read(&format!("fn main sysv {{{}}}", before));
+            let changed = pass(&mut program);
+            assert_eq!(program.to_string().trim(), format!("fn main sysv {{{}}}", after).trim(), "from{}", before);
+            assert_eq!(changed, before != after, "from{}", before);
+        }
+    }
+
+    #[test]
+    fn const_prop() {
+        check(super::const_prop, &[
+            ("
+main:
+    mov r1, 5
+    add r2, r1
+    mov q[r3 + r1*8], r2
+    ret r2
+", "
+main:
+    mov r1, 5
+    add r2, 5
+    mov q[r3 + 40], r2
+    ret r2
+"),
+            // the same constant from both ways in
+            ("
+main:
+    br.eq r2, 0, other
+one:
+    mov r1, 7
+    jmp join
+other:
+    mov r1, 7
+join:
+    ret r1
+", "
+main:
+    br.eq r2, 0, other
+one:
+    mov r1, 7
+    jmp join
+other:
+    mov r1, 7
+join:
+    ret 7
+"),
+            // different ones
+            ("
+main:
+    br.eq r2, 0, other
+one:
+    mov r1, 7
+    jmp join
+other:
+    mov r1, 8
+join:
+    ret r1
+", "
+main:
+    br.eq r2, 0, other
+one:
+    mov r1, 7
+    jmp join
+other:
+    mov r1, 8
+join:
+    ret r1
+"),
+            // control may come into a label whose address is taken from anywhere
+            ("
+main:
+    mov r1, 5
+    addr t0, q[there]
+    jmp t0
+there:
+    ret r1
+", "
+main:
+    mov r1, 5
+    addr t0, q[there]
+    jmp t0
+there:
+    ret r1
+"),
+            // what was known of r1 is gone once it's loaded from memory
+            ("
+main:
+    mov r1, 5
+    mov r1, q[frame - 8]
+    ret r1
+", "
+main:
+    mov r1, 5
+    mov r1, q[frame - 8]
+    ret r1
+"),
+        ]);
+    }
+
+    #[test]
+    fn fold() {
+        check(super::fold, &[
+            ("
+main:
+    mov r1, 6
+    mul r1, 7
+    add r2, 0
+    ret r1
+", "
+main:
+    mov r1, 6
+    mov r1d, 42
+    ret r1
+"),
+            ("
+main:
+    mov r1, 1
+    br.eq r1, 1, done
+next:
+    br.ne r1, 1, done
+last:
+    ret 0
+done:
+    ret 1
+", "
+main:
+    mov r1, 1
+    jmp done
+next:
+last:
+    ret 0
+done:
+    ret 1
+"),
+            // a comparison reads its operands at their size, signed or not
+            ("
+main:
+    mov r1l, 255
+    br.slt r1l, 0, done
+next:
+    br.ult r1l, 0, done
+last:
+    ret 0
+done:
+    ret 1
+", "
+main:
+    mov r1l, 255
+    jmp done
+next:
+last:
+    ret 0
+done:
+    ret 1
+"),
+            // what it compares against isn't known
+            ("
+main:
+    mov r1, 3
+    set.eq r2l, r1, r3
+    br.ult r1, r3, done
+next:
+    ret r2
+done:
+    ret 1
+", "
+main:
+    mov r1, 3
+    set.eq r2l, r1, r3
+    br.ult r1, r3, done
+next:
+    ret r2
+done:
+    ret 1
+"),
+            // writing 4 bytes clears the upper ones
+            ("
+main:
+    add r1d, 0
+    mov r2d, r2d
+    ret r1
+", "
+main:
+    add r1d, 0
+    mov r2d, r2d
+    ret r1
+"),
+        ]);
+    }
+
+    #[test]
+    fn copy_prop() {
+        check(super::copy_prop, &[
+            // memory can't change a register
+            ("
+main:
+    mov r1, r2
+    mov q[frame - 8], r3
+    add r3, r1
+    ret r3
+", "
+main:
+    mov r1, r2
+    mov q[frame - 8], r3
+    add r3, r2
+    ret r3
+"),
+            // the copy is gone once either side is written, here loaded from memory
+            ("
+main:
+    mov r1, r2
+    pop r2
+    add r3, r1
+    mov r4, r3
+    mov r3, q[frame - 8]
+    add r5, r4
+    ret r5
+", "
+main:
+    mov r1, r2
+    pop r2
+    add r3, r1
+    mov r4, r3
+    mov r3, q[frame - 8]
+    add r5, r4
+    ret r5
+"),
+            // TMP is the backend's scratch
+            ("
+main:
+    mov r1, t1
+    add r2, r1
+    ret r2
+", "
+main:
+    mov r1, t1
+    add r2, r1
+    ret r2
+"),
+            // only within a block
+            ("
+main:
+    mov r1, r2
+next:
+    add r3, r1
+    ret r3
+", "
+main:
+    mov r1, r2
+next:
+    add r3, r1
+    ret r3
+"),
+        ]);
+    }
+
+    #[test]
+    fn dse() {
+        check(super::dse, &[
+            ("
+main:
+    mov q[frame - 8], r1
+    mov d[counter + 4], 1
+    mov q[frame - 8], r2
+    mov q[counter], 0
+    ret
+", "
+main:
+    mov q[frame - 8], r2
+    mov q[counter], 0
+    ret
+"),
+            // read in between, directly, through an index or through a register, or by a call
+            ("
+main:
+    mov q[frame - 8], r1
+    mov r3d, d[frame - 4]
+    mov q[frame - 8], r2
+    mov q[frame - 16], r1
+    mov r3, q[frame + r4*8 - 32]
+    mov q[frame - 16], r2
+    mov q[frame - 24], r1
+    mov r3, q[r4]
+    mov q[frame - 24], r2
+    mov q[counter], r1
+    call $tick, 0 -> r0
+    mov q[counter], r2
+    ret
+", "
+main:
+    mov q[frame - 8], r1
+    mov r3d, d[frame - 4]
+    mov q[frame - 8], r2
+    mov q[frame - 16], r1
+    mov r3, q[frame + r4*8 - 32]
+    mov q[frame - 16], r2
+    mov q[frame - 24], r1
+    mov r3, q[r4]
+    mov q[frame - 24], r2
+    mov q[counter], r1
+    call $tick, 0 -> r0
+    mov q[counter], r2
+    ret
+"),
+            // only partly overwritten, or in another block
+            ("
+main:
+    mov q[frame - 8], r1
+    mov d[frame - 8], r2
+    mov q[frame - 16], r1
+next:
+    mov q[frame - 16], r2
+    ret
+", "
+main:
+    mov q[frame - 8], r1
+    mov d[frame - 8], r2
+    mov q[frame - 16], r1
+next:
+    mov q[frame - 16], r2
+    ret
+"),
+        ]);
+    }
+
+    #[test]
+    fn dce() {
+        check(super::dce, &[
+            ("
+main:
+    mov r1, 5
+    add r2, r1
+    mov r3, r1
+    ret r3
+dead:
+    ret 1
+", "
+main:
+    mov r1, 5
+    mov r3, r1
+    ret r3
+"),
+            // stores, calls and flags set for a branch stay, and so does a block whose address is
+            // taken
+            ("
+main:
+    mov q[frame - 8], r1
+    call $tick, 0 -> r2
+    set.eq r3l, r1, 0
+    br.ne r3l, 0, done
+next:
+    addr t0, q[done]
+    mov q[frame - 16], t0
+    ret
+done:
+    ret
+", "
+main:
+    mov q[frame - 8], r1
+    call $tick, 0 -> r2
+    set.eq r3l, r1, 0
+    br.ne r3l, 0, done
+next:
+    addr t0, q[done]
+    mov q[frame - 16], t0
+    ret
+done:
+    ret
+"),
+        ]);
+    }
+
+    #[test]
+    fn jump_thread() {
+        check(super::jump_thread, &[
+            ("
+main:
+    br.eq r1, 0, a
+next:
+    mov r2, 1
+    jmp b
+a:
+    jmp c
+b:
+c:
+    ret
+", "
+main:
+    br.eq r1, 0, c
+next:
+    mov r2, 1
+    jmp c
+a:
+    jmp c
+b:
+c:
+    ret
+"),
+            ("
+main:
+    jmp next
+next:
+    ret
+", "
+main:
+next:
+    ret
+"),
+            // a loop of jumps is left as it is, and the block whose address is taken stays there
+            // to jump to
+            ("
+main:
+    addr t0, q[a]
+    br.eq r1, 0, a
+next:
+    jmp t0
+a:
+    jmp b
+out:
+    ret
+b:
+    jmp a
+", "
+main:
+    addr t0, q[a]
+    br.eq r1, 0, a
+next:
+    jmp t0
+a:
+    jmp b
+out:
+    ret
+b:
+    jmp a
+"),
+        ]);
+    }
+}
@@ -1,3 +1,1032 @@
-use crate::location::{Location, Span};
-use crate::token::TokenKind;
+use std::num::IntErrorKind;
 
+use crate::args_parser::ARGS;
+use crate::ast::*;
+use crate::defs::BUNDLED_LIBS;
+use crate::lexer::Lexer;
+use crate::location::Span;
+use crate::logger::{Log, ERR, WARN};
+use crate::token::{Token, TokenKind};
+
+pub struct Parser {
+    tokens: Vec<Token>,
+    current_index: usize,
+}
+
+impl Parser {
+    pub fn new(tokens: Vec<Token>) -> Parser {
+        Parser {
+            tokens,
+            current_index: 0,
+        }
+    }
+
+    // the lexer always ends the stream with an EOF token, so this never runs out
+    fn cur(&self) -> &Token { &self.tokens[self.current_index] }
+
+    fn peek(&self) -> &Token { self.peek_n(1) }
+
+    fn peek_n(&self, n: usize) -> &Token { &self.tokens[(self.current_index + n).min(self.tokens.len() - 1)] }
+
+    fn advance(&mut self) {
+        if self.cur().kind != TokenKind::EOF {
+            self.current_index += 1;
+        }
+    }
+
+    fn at(&self, kind: TokenKind) -> bool { self.cur().kind == kind }
+
+    fn expect<W: std::fmt::Display>(&mut self, kind: TokenKind, notes: W) -> Option<Token> {
+        if self.at(kind) {
+            let token = self.cur().clone();
+            self.advance();
+            return Some(token);
+        }
+        self.unexpected(notes);
+        None
+    }
+
+    fn unexpected<W: std::fmt::Display>(&self, notes: W) {
+        let token = self.cur();
+        Log::new(ERR, token.span, format!("Unexpected token: {}", describe(token)), notes).push();
+    }
+
+    fn at_line_end(&self) -> bool { matches!(self.cur().kind, TokenKind::Newline | TokenKind::EOF) }
+
+    // skips the rest of a broken statement
+    fn recover(&mut self) {
+        while !self.at_line_end() {
+            self.advance();
+        }
+    }
+
+    // span from `start` up to the last consumed token
+    fn span_from(&self, start: Span) -> Span {
+        match self.current_index {
+            0 => start,
+            i => start.extend(&self.tokens[i - 1].span),
+        }
+    }
+
+    pub fn parse(&mut self) -> Program {
+        let mut program = Program {
+            stmts: Vec::new(),
+            data: Vec::new(),
+            functions: Vec::new(),
+            externs: Vec::new(),
+            entry: None,
+        };
+
+        while !self.at(TokenKind::EOF) {
+            if self.at(TokenKind::Newline) {
+                self.advance();
+                continue;
+            }
+            self.parse_line(&mut program);
+        }
+
+        program
+    }
+
+    fn parse_line(&mut self, program: &mut Program) {
+        let Some(stmt) = self.parse_stmt(program) else {
+            self.recover();
+            return;
+        };
+
+        // labels may share their line with the statement that follows them
+        let is_label = matches!(stmt.kind, StmtKind::Label(_));
+        program.stmts.push(stmt);
+        if !is_label && !self.at_line_end() {
+            self.unexpected("Expected end of line");
+            self.recover();
+        }
+    }
+
+    // '@' IDENT (IDENT Type (',' IDENT Type)*)? ('->' Type)? '{' NL STMT* '}'
+    fn parse_function(&mut self, program: &mut Program, attrs: Vec<Token>) -> Option<Stmt> {
+        let attrs = fn_attrs(attrs);
+        let start = self.cur().span;
+        self.advance();
+        let name = self.expect(TokenKind::Identifier, "Expected a function name")?;
+
+        let mut params = Vec::new();
+        while self.at(TokenKind::Identifier) {
+            let param = self.cur().clone();
+            self.advance();
+            params.push(Param {
+                name: param.text,
+                ty: self.parse_type()?,
+                span: self.span_from(param.span),
+            });
+            if !self.at(TokenKind::Comma) {
+                break;
+            }
+            self.advance();
+        }
+        let ret = match self.at(TokenKind::TinyArrowRight) {
+            true => {
+                self.advance();
+                Some(self.parse_type()?)
+            },
+            false => None,
+        };
+        let span = self.span_from(start);
+        self.expect(TokenKind::LeftBrace, "Expected '{'")?;
+
+        let first = program.stmts.len();
+        program.stmts.push(Stmt {
+            kind: StmtKind::Label(name.text.clone()),
+            span: name.span,
+        });
+        loop {
+            match self.cur().kind {
+                TokenKind::Newline => self.advance(),
+                TokenKind::RightBrace => break,
+                TokenKind::EOF => {
+                    Log::new(ERR, span, format!("Unterminated function `{}`", name.text), "Expected '}'").push();
+                    return None;
+                },
+                TokenKind::At => {
+                    Log::new(ERR, self.cur().span, "Functions can't be nested", "").push();
+                    self.recover();
+                },
+                _ => self.parse_line(program),
+            }
+        }
+
+        // running off the end returns
+        if !program.stmts[first..].last().is_some_and(Stmt::diverges) {
+            program.stmts.push(Stmt {
+                kind: StmtKind::Ret(None),
+                span: self.cur().span,
+            });
+        }
+        self.advance();
+
+        program.functions.push(FnDef {
+            name: name.text,
+            params,
+            ret,
+            attrs,
+            stmts: first..program.stmts.len(),
+            span,
+        });
+        None
+    }
+
+    fn parse_stmt(&mut self, program: &mut Program) -> Option<Stmt> {
+        let start = self.cur().span;
+        let kind = match self.cur().kind {
+            // loop:
+            TokenKind::Identifier if self.peek().kind == TokenKind::Colon => {
+                let name = self.cur().text.clone();
+                self.advance();
+                self.advance();
+                if self.at(TokenKind::LeftBrace) {
+                    let block = self.parse_datablock(name, start, Vec::new())?;
+                    program.data.push(block);
+                    return None;
+                }
+                StmtKind::Label(name)
+            },
+
+            // |packed|
+            TokenKind::Pipe => {
+                let attrs = self.parse_attrs()?;
+                while self.at(TokenKind::Newline) {
+                    self.advance();
+                }
+                if self.at(TokenKind::At) {
+                    return self.parse_function(program, attrs);
+                }
+                let start = self.cur().span;
+                if !(self.at(TokenKind::Identifier) && self.peek().kind == TokenKind::Colon) {
+                    self.unexpected("Expected a data block or function after the attributes");
+                    return None;
+                }
+                let name = self.cur().text.clone();
+                self.advance();
+                self.advance();
+                if !self.at(TokenKind::LeftBrace) {
+                    self.unexpected("Expected '{'");
+                    return None;
+                }
+                let block = self.parse_datablock(name, start, attrs)?;
+                program.data.push(block);
+                return None;
+            },
+
+            // %n 2 = 9
+            TokenKind::Percent => {
+                self.advance();
+                let name = self.expect(TokenKind::Identifier, "Expected a variable name")?.text;
+                let ty = self.parse_type()?;
+                self.expect(TokenKind::Equals, "Expected '='")?;
+                StmtKind::StackAssign(name, ty, self.parse_expr()?)
+            },
+
+            // ;temp r3 = 0
+            TokenKind::Semicolon => {
+                self.advance();
+                let name = self.expect(TokenKind::Identifier, "Expected a variable name")?.text;
+                let reg = self.expect(TokenKind::Register, "Expected a register")?;
+                let reg = self.register(&reg)?;
+                let signed = match self.cur().text.as_str() {
+                    "s" | "u" if self.at(TokenKind::Identifier) => {
+                        let signed = self.cur().text == "s";
+                        self.advance();
+                        signed
+                    },
+                    _ => false,
+                };
+                self.expect(TokenKind::Equals, "Expected '='")?;
+                StmtKind::RegAssign(name, RegVar { reg, signed }, self.parse_expr()?)
+            },
+
+            // 'n + 1
+            TokenKind::Apostrophe => {
+                self.advance();
+                let target = self.parse_expr()?;
+                let op = match self.cur().kind {
+                    TokenKind::Equals => MutateOp::Set,
+                    TokenKind::Plus => MutateOp::Add,
+                    TokenKind::Minus => MutateOp::Sub,
+                    TokenKind::Star => MutateOp::Mul,
+                    TokenKind::Slash => MutateOp::Div,
+                    TokenKind::Percent => MutateOp::Mod,
+                    TokenKind::Tilde => MutateOp::AndNot,
+                    TokenKind::Pipe => MutateOp::Or,
+                    TokenKind::Ampersand => MutateOp::And,
+                    TokenKind::Caret => MutateOp::Xor,
+                    TokenKind::GreaterThan => MutateOp::Shr,
+                    TokenKind::LessThan => MutateOp::Shl,
+                    TokenKind::Colon => MutateOp::Store,
+                    TokenKind::PlusPlus => MutateOp::Inc,
+                    TokenKind::MinusMinus => MutateOp::Dec,
+                    TokenKind::Question => MutateOp::SetIfZero,
+                    TokenKind::Underscore => MutateOp::Clear,
+                    _ => {
+                        self.unexpected("Expected a mutation operator");
+                        return None;
+                    },
+                };
+                let op_span = self.cur().span;
+                self.advance();
+                // 'n ++ is 'n + 1, 'n _ is 'n = 0
+                let value = match op.is_unary() {
+                    true => Expr {
+                        kind: ExprKind::Int((op != MutateOp::Clear) as i128),
+                        span: op_span,
+                    },
+                    false => self.parse_expr()?,
+                };
+                StmtKind::Mutate(target, op, value)
+            },
+
+            // jmp loop, or jmp r3 to whatever address the expression evaluates to
+            TokenKind::Jmp => {
+                self.advance();
+                let ends = matches!(self.peek().kind, TokenKind::Newline | TokenKind::EOF | TokenKind::Pipe);
+                match self.at(TokenKind::Identifier) && ends {
+                    true => StmtKind::Jmp(self.expect(TokenKind::Identifier, "Expected a label")?.text),
+                    false => StmtKind::JmpTo(self.parse_expr()?),
+                }
+            },
+
+            TokenKind::End => {
+                self.advance();
+                StmtKind::End(self.parse_expr()?)
+            },
+
+            TokenKind::Ret => {
+                self.advance();
+                match self.at_line_end() || self.at(TokenKind::Pipe) {
+                    true => StmtKind::Ret(None),
+                    false => StmtKind::Ret(Some(self.parse_expr()?)),
+                }
+            },
+
+            // #add 1, n or $puts "Hi\0" or *write 1, msg, 3 or <- r3 1, n
+            TokenKind::Pound | TokenKind::Dollar | TokenKind::Star | TokenKind::TinyArrowLeft => {
+                let (callee, args) = self.parse_call()?;
+                StmtKind::Call(callee, args)
+            },
+
+            // ^n 2 pushes, ^> r3 pops and ^? r3 peeks
+            TokenKind::Caret => {
+                self.advance();
+                match self.cur().kind {
+                    TokenKind::GreaterThan | TokenKind::Question => {
+                        let pop = self.at(TokenKind::GreaterThan);
+                        self.advance();
+                        let target = self.parse_expr()?;
+                        match pop {
+                            true => StmtKind::Pop(target),
+                            false => StmtKind::Peek(target),
+                        }
+                    },
+                    _ => {
+                        let value = self.parse_expr()?;
+                        StmtKind::Push(value, self.parse_type()?)
+                    },
+                }
+            },
+
+            TokenKind::At => return self.parse_function(program, Vec::new()),
+
+            TokenKind::Dot => return self.parse_directive(program),
+
+            // (n > 0) => jmp loop | ret
+            kind if starts_expr(kind) => {
+                let cond = self.parse_expr()?;
+                self.expect(TokenKind::FatArrow, "Expected '=>'")?;
+                let then = self.parse_arm(program)?;
+                let otherwise = match self.at(TokenKind::Pipe) {
+                    true => {
+                        self.advance();
+                        Some(Box::new(self.parse_arm(program)?))
+                    },
+                    false => None,
+                };
+                StmtKind::Cond(cond, Box::new(then), otherwise)
+            },
+
+            _ => {
+                self.unexpected("Expected a statement");
+                return None;
+            },
+        };
+
+        Some(Stmt {
+            kind,
+            span: self.span_from(start),
+        })
+    }
+
+    // '#' IDENT ARGS | '$' IDENT ARGS | '*' IDENT ARGS | '<-' EXPR ARGS. The arguments are single operands, so within parentheses
+    // `(#f 1 + 2)` adds 2 to the result
+    fn parse_call(&mut self) -> Option<(Callee, Vec<Expr>)> {
+        let callee = match self.cur().kind {
+            TokenKind::Pound => {
+                self.advance();
+                Callee::Named(self.expect(TokenKind::Identifier, "Expected a function name")?.text)
+            },
+            TokenKind::Dollar => {
+                self.advance();
+                Callee::Extern(self.expect(TokenKind::Identifier, "Expected an external function name")?.text)
+            },
+            TokenKind::Star => {
+                self.advance();
+                Callee::Sys(self.expect(TokenKind::Identifier, "Expected a system call name")?.text)
+            },
+            _ => {
+                self.advance();
+                Callee::Addr(Box::new(self.parse_expr()?))
+            },
+        };
+
+        let mut args = Vec::new();
+        if !starts_arg(self.cur()) {
+            return Some((callee, args));
+        }
+        loop {
+            args.push(self.parse_expr()?);
+            if !self.at(TokenKind::Comma) {
+                break;
+            }
+            self.advance();
+        }
+        Some((callee, args))
+    }
+
+    // a statement within a conditional, which can't start a block or carry a directive
+    fn parse_arm(&mut self, program: &mut Program) -> Option<Stmt> {
+        let is_label = self.at(TokenKind::Identifier) && self.peek().kind == TokenKind::Colon;
+        if is_label || matches!(self.cur().kind, TokenKind::Dot | TokenKind::Pipe | TokenKind::At | TokenKind::Newline | TokenKind::EOF) {
+            self.unexpected("Expected a statement");
+            return None;
+        }
+        self.parse_stmt(program)
+    }
+
+    // directives only update the program metadata, so they yield no statement. `.switch` is the
+    // exception, it jumps like one
+    fn parse_directive(&mut self, program: &mut Program) -> Option<Stmt> {
+        let start = self.cur().span;
+        self.advance();
+        let dir = self.expect(TokenKind::Identifier, "Expected a directive name")?;
+
+        match dir.text.as_str() {
+            "ent" | "entry" => {
+                let name = self.expect(TokenKind::Identifier, "Expected the entry label")?;
+                if program.entry.is_some() {
+                    Log::new(ERR, self.span_from(dir.span), "Entry already defined", "").push();
+                }
+                program.entry = Some(name.text);
+            },
+            "extern" => {
+                let ext = self.parse_extern(dir.span)?;
+                match program.externs.iter().find(|e| e.name == ext.name) {
+                    Some(prev) if prev.params != ext.params || prev.variadic != ext.variadic || prev.ret != ext.ret => {
+                        Log::new(ERR, ext.span, format!("`{}` is declared differently before", ext.name),
+                            format!("As {}", prev.signature())).push();
+                    },
+                    Some(_) => (),
+                    None => program.externs.push(ext),
+                }
+            },
+            // .use libc, the prototypes shipped in the system library
+            "use" => {
+                let name = self.expect(TokenKind::Identifier, "Expected the name of a system library file")?;
+                self.use_sys_lib(program, &name);
+            },
+            // .switch i {zero, one, two} | other
+            "switch" => {
+                let index = self.parse_expr()?;
+                self.expect(TokenKind::LeftBrace, "Expected '{' and the labels to jump to")?;
+                let mut labels = Vec::new();
+                while !self.at(TokenKind::RightBrace) {
+                    labels.push(self.expect(TokenKind::Identifier, "Expected a label")?.text);
+                    if !self.at(TokenKind::RightBrace) {
+                        self.expect(TokenKind::Comma, "Expected ',' or '}'")?;
+                    }
+                }
+                self.advance();
+                let otherwise = match self.at(TokenKind::Pipe) {
+                    true => {
+                        self.advance();
+                        Some(self.expect(TokenKind::Identifier, "Expected a label")?.text)
+                    },
+                    false => None,
+                };
+                return Some(Stmt {
+                    kind: StmtKind::Switch(index, labels, otherwise),
+                    span: self.span_from(start),
+                });
+            },
+            d => Log::new(ERR, dir.span, format!("Unknown Directive: {}", d), "").push(),
+        }
+
+        self.recover();
+        None
+    }
+
+    // NAME '(' (Type (',' Type)* (',' '...')? | '...')? ')' ('->' Type)?
+    fn parse_extern(&mut self, start: Span) -> Option<ExternDef> {
+        let name = self.expect(TokenKind::Identifier, "Expected the name of an external function")?.text;
+        self.expect(TokenKind::LeftParen, "Expected '('")?;
+        let mut params = Vec::new();
+        let mut variadic = false;
+        while !self.at(TokenKind::RightParen) {
+            if self.at(TokenKind::Dot) {
+                for _ in 0..3 {
+                    self.expect(TokenKind::Dot, "Expected '...'")?;
+                }
+                variadic = true;
+                if !self.at(TokenKind::RightParen) {
+                    self.unexpected("Expected ')', '...' has to come last");
+                    return None;
+                }
+                break;
+            }
+            params.push(self.parse_type()?);
+            if !self.at(TokenKind::RightParen) {
+                self.expect(TokenKind::Comma, "Expected ',' or ')'")?;
+            }
+        }
+        self.advance();
+
+        let ret = match self.at(TokenKind::TinyArrowRight) {
+            true => {
+                self.advance();
+                Some(self.parse_type()?)
+            },
+            false => None,
+        };
+        Some(ExternDef {
+            name,
+            params,
+            variadic,
+            ret,
+            span: self.span_from(start),
+        })
+    }
+
+    // reads `<sys lib>/name.shd`, or the copy built into the compiler when there's none. It may
+    // only hold directives
+    fn use_sys_lib(&mut self, program: &mut Program, name: &Token) {
+        let path = format!("{}/{}.shd", unsafe { ARGS.sys_lib }.trim_end_matches('/'), name.text);
+        let (source, path) = match std::fs::read_to_string(&path) {
+            Ok(source) => (source, path),
+            Err(_) => match BUNDLED_LIBS.iter().find(|(lib, _)| *lib == name.text) {
+                Some((_, source)) => (source.to_string(), format!("<built-in>/{}.shd", name.text)),
+                None => {
+                    Log::new(ERR, name.span, format!("Can't read `{}`", path), "Pass the system library with --sys-lib").push();
+                    return;
+                },
+            },
+        };
+        let tokens = Lexer::new(source, Box::leak(path.into_boxed_str())).lex();
+        let lib = Parser::new(tokens).parse();
+        if let Some(stmt) = lib.stmts.first() {
+            Log::new(ERR, stmt.span, "System library files can only hold directives", "").push();
+        }
+        for ext in lib.externs {
+            if !program.externs.iter().any(|e| e.name == ext.name) {
+                program.externs.push(ext);
+            }
+        }
+    }
+
+    // '|' ATTRNAME (',' ATTRNAME)* '|'
+    fn parse_attrs(&mut self) -> Option<Vec<Token>> {
+        self.advance();
+        let mut attrs = Vec::new();
+        loop {
+            attrs.push(self.expect(TokenKind::Identifier, "Expected an attribute")?);
+            if !self.at(TokenKind::Comma) {
+                break;
+            }
+            self.advance();
+        }
+        self.expect(TokenKind::Pipe, "Expected '|'")?;
+        Some(attrs)
+    }
+
+    // data blocks, like directives, live outside the statement stream
+    fn parse_datablock(&mut self, name: Name, start: Span, attrs: Vec<Token>) -> Option<DataBlock> {
+        let mut packed = false;
+        for attr in attrs {
+            match attr.text.as_str() {
+                "packed" => packed = true,
+                a => Log::new(ERR, attr.span, format!("Unknown attribute `{}` for a data block", a), "Expected packed").push(),
+            }
+        }
+
+        self.advance();
+        self.expect(TokenKind::Newline, "Expected a new line after '{'")?;
+
+        let mut fields = Vec::new();
+        while !self.at(TokenKind::RightBrace) {
+            if self.at(TokenKind::EOF) {
+                self.unexpected("Expected '}'");
+                return None;
+            }
+
+            let field_start = self.cur().span;
+            let name = match (self.cur().kind, self.peek().kind) {
+                (TokenKind::Identifier, TokenKind::Colon) => {
+                    let name = self.cur().text.clone();
+                    self.advance();
+                    self.advance();
+                    Some(name)
+                },
+                _ => None,
+            };
+            let ty = self.parse_type()?;
+            let value = match self.at(TokenKind::Equals) {
+                true => {
+                    self.advance();
+                    Some(self.parse_expr()?)
+                },
+                false => None,
+            };
+            fields.push(Field {
+                name,
+                ty,
+                value,
+                span: self.span_from(field_start),
+            });
+            self.expect(TokenKind::Newline, "Expected end of line")?;
+        }
+        self.advance();
+
+        Some(DataBlock {
+            name,
+            fields,
+            packed,
+            span: self.span_from(start),
+        })
+    }
+
+    // ('s' | 'u')? SIZE | (DECNUM? ':' DECNUM) | IDENT | '[' Type (',' DECNUM)? ']'
+    fn parse_type(&mut self) -> Option<Type> {
+        let token = self.cur().clone();
+        match token.kind {
+            TokenKind::DecLiteral if self.peek().kind == TokenKind::Colon => {
+                self.advance();
+                self.advance();
+                let hi = self.expect(TokenKind::DecLiteral, "Expected the upper bit")?;
+                let hi = self.bit(&hi)?;
+                Some(Type::Bits(self.bit(&token)?, hi))
+            },
+            TokenKind::DecLiteral => {
+                self.advance();
+                match token.text.as_str() {
+                    "1" | "2" | "4" | "8" => Some(Type::Size(token.text.parse().unwrap())),
+                    _ => {
+                        Log::new(ERR, token.span, format!("Invalid size: {}", token.text), "Expected 1, 2, 4 or 8").push();
+                        None
+                    },
+                }
+            },
+            TokenKind::Colon => {
+                self.advance();
+                let hi = self.expect(TokenKind::DecLiteral, "Expected the upper bit")?;
+                let hi = self.bit(&hi)?;
+                Some(Type::Bits(0, hi))
+            },
+            TokenKind::Identifier => {
+                self.advance();
+                Some(match token.text.as_str() {
+                    "u1" | "u2" | "u4" | "u8" => Type::Size(token.text[1..].parse().unwrap()),
+                    "s1" | "s2" | "s4" | "s8" => Type::Signed(token.text[1..].parse().unwrap()),
+                    _ => Type::Named(token.text),
+                })
+            },
+            TokenKind::LeftBracket => {
+                self.advance();
+                let elem = self.parse_type()?;
+                let len = match self.at(TokenKind::Comma) {
+                    true => {
+                        self.advance();
+                        let len = self.expect(TokenKind::DecLiteral, "Expected the array length")?;
+                        Some(self.int(&len, 10)? as u64)
+                    },
+                    false => None,
+                };
+                self.expect(TokenKind::RightBracket, "Expected ']'")?;
+                Some(Type::Array(Box::new(elem), len))
+            },
+            _ => {
+                self.unexpected("Expected a type");
+                None
+            },
+        }
+    }
+
+    fn bit(&self, token: &Token) -> Option<u8> {
+        match token.text.parse::<u8>() {
+            Ok(n) => Some(n),
+            Err(_) => {
+                Log::new(ERR, token.span, format!("Invalid bit: {}", token.text), "Expected 0 to 64").push();
+                None
+            },
+        }
+    }
+
+    // EXPR, a single operand. Operators are only allowed within parentheses
+    fn parse_expr(&mut self) -> Option<Expr> {
+        let token = self.cur().clone();
+        let kind = match token.kind {
+            // 0*, as long as the '*' can't be a multiplication
+            _ if is_fill(&token, self.peek(), self.peek_n(2)) => {
+                let n = match token.kind {
+                    TokenKind::CharLiteral => token.text.chars().next().map_or(0, |c| c as i128),
+                    TokenKind::HexLiteral => self.int(&token, 16)?,
+                    TokenKind::BinLiteral => self.int(&token, 2)?,
+                    TokenKind::OctLiteral => self.int(&token, 8)?,
+                    _ => self.int(&token, 10)?,
+                };
+                self.advance();
+                self.advance();
+                return Some(Expr {
+                    kind: ExprKind::Fill(n),
+                    span: self.span_from(token.span),
+                });
+            },
+            TokenKind::DecLiteral => ExprKind::Int(self.int(&token, 10)?),
+            TokenKind::HexLiteral => ExprKind::Int(self.int(&token, 16)?),
+            TokenKind::BinLiteral => ExprKind::Int(self.int(&token, 2)?),
+            TokenKind::OctLiteral => ExprKind::Int(self.int(&token, 8)?),
+            TokenKind::CharLiteral => ExprKind::Int(token.text.chars().next().map_or(0, |c| c as i128)),
+            // a minus directly in front of a literal makes it negative, `(a - 1)` stays a subtraction
+            TokenKind::Minus if !token.whitespace_after() && is_int(self.peek().kind) => {
+                self.advance();
+                let mut expr = self.parse_expr()?;
+                let (n, fill) = match expr.kind {
+                    ExprKind::Int(n) => (n, false),
+                    ExprKind::Fill(n) => (n, true),
+                    _ => {
+                        Log::new(ERR, token.span.extend(&expr.span), "Only a number can be negated here", "Put it in parentheses to subtract").push();
+                        return None;
+                    },
+                };
+                if n > i64::MAX as i128 + 1 {
+                    Log::new(ERR, expr.span, format!("Invalid integer literal: -{}", n), "Does not fit in 64 bits").push();
+                    return None;
+                }
+                expr.kind = if fill { ExprKind::Fill(-n) } else { ExprKind::Int(-n) };
+                expr.span = token.span.extend(&expr.span);
+                return Some(expr);
+            },
+            TokenKind::Identifier if !token.whitespace_after() && self.peek().kind == TokenKind::Dot => {
+                self.advance();
+                self.advance();
+                let index = match self.at(TokenKind::LeftParen) {
+                    true => self.parse_math_block()?,
+                    false => {
+                        let index = self.expect(TokenKind::DecLiteral, "Expected an index or '('")?;
+                        Expr {
+                            kind: ExprKind::Int(self.int(&index, 10)?),
+                            span: index.span,
+                        }
+                    },
+                };
+                return Some(Expr {
+                    kind: ExprKind::ArrIndex(token.text, Box::new(index)),
+                    span: self.span_from(token.span),
+                });
+            },
+            TokenKind::Identifier if !token.whitespace_after() && self.peek().kind == TokenKind::Pound => {
+                self.advance();
+                self.advance();
+                let field = self.field_ref()?;
+                return Some(Expr {
+                    kind: ExprKind::StructIndex(token.text, field),
+                    span: self.span_from(token.span),
+                });
+            },
+            // sizeof(Point), offsetof(Point, y)
+            TokenKind::Identifier if matches!(token.text.as_str(), "sizeof" | "offsetof")
+                && self.peek().kind == TokenKind::LeftParen =>
+            {
+                self.advance();
+                self.advance();
+                let kind = match token.text.as_str() {
+                    "sizeof" => ExprKind::SizeOf(self.parse_type()?),
+                    _ => {
+                        let block = self.expect(TokenKind::Identifier, "Expected a data block")?.text;
+                        self.expect(TokenKind::Comma, "Expected ','")?;
+                        ExprKind::OffsetOf(block, self.field_ref()?)
+                    },
+                };
+                self.expect(TokenKind::RightParen, "Expected ')'")?;
+                return Some(Expr {
+                    kind,
+                    span: self.span_from(token.span),
+                });
+            },
+            TokenKind::Identifier => ExprKind::Ident(token.text.clone()),
+            TokenKind::Register => ExprKind::Register(self.register(&token)?),
+            TokenKind::LeftParen => return self.parse_math_block(),
+            TokenKind::StringLiteral => ExprKind::Str(token.text.clone()),
+            TokenKind::Ampersand if self.peek().kind == TokenKind::Identifier => {
+                self.advance();
+                ExprKind::LabelAddr(self.cur().text.clone())
+            },
+            TokenKind::Pound | TokenKind::Dollar | TokenKind::Star | TokenKind::TinyArrowLeft => {
+                let (callee, args) = self.parse_call()?;
+                return Some(Expr {
+                    kind: ExprKind::Call(callee, args),
+                    span: self.span_from(token.span),
+                });
+            },
+            TokenKind::LeftBracket => {
+                self.advance();
+                let addr = self.parse_math(0)?;
+                self.expect(TokenKind::RightBracket, "Expected ']'")?;
+                return Some(Expr {
+                    kind: ExprKind::Deref(Box::new(addr)),
+                    span: self.span_from(token.span),
+                });
+            },
+            TokenKind::LeftBrace => {
+                self.advance();
+                let mut elems = Vec::new();
+                while !self.at(TokenKind::RightBrace) {
+                    elems.push(self.parse_expr()?);
+                    if !self.at(TokenKind::RightBrace) {
+                        self.expect(TokenKind::Comma, "Expected ',' or '}'")?;
+                    }
+                }
+                self.advance();
+                return Some(Expr {
+                    kind: ExprKind::Array(elems),
+                    span: self.span_from(token.span),
+                });
+            },
+            TokenKind::Tilde => {
+                self.advance();
+                if !self.at(TokenKind::LeftParen) {
+                    self.unexpected("Expected '(' after '~'");
+                    return None;
+                }
+                let inner = self.parse_math_block()?;
+                return Some(Expr {
+                    kind: ExprKind::Not(Box::new(inner)),
+                    span: self.span_from(token.span),
+                });
+            },
+            _ => {
+                self.unexpected("Expected an expression");
+                return None;
+            },
+        };
+        self.advance();
+
+        Some(Expr {
+            kind,
+            span: token.span,
+        })
+    }
+
+    // IDENT | DECNUM
+    fn field_ref(&mut self) -> Option<FieldRef> {
+        let field = self.cur().clone();
+        let field = match field.kind {
+            TokenKind::Identifier => FieldRef::Name(field.text),
+            TokenKind::DecLiteral => FieldRef::Index(self.int(&field, 10)? as u64),
+            _ => {
+                self.unexpected("Expected a field name or index");
+                return None;
+            },
+        };
+        self.advance();
+        Some(field)
+    }
+
+    // '(' MathExpr ')'
+    fn parse_math_block(&mut self) -> Option<Expr> {
+        let start = self.expect(TokenKind::LeftParen, "Expected '('")?.span;
+        let mut expr = self.parse_math(0)?;
+        self.expect(TokenKind::RightParen, "Expected ')'")?;
+        expr.span = self.span_from(start);
+        Some(expr)
+    }
+
+    // precedence climbing, all operators are left associative
+    fn parse_math(&mut self, min_prec: u8) -> Option<Expr> {
+        let mut lhs = self.parse_expr()?;
+
+        loop {
+            if self.at(TokenKind::Colon) {
+                Log::new(ERR, self.cur().span, "Operator ':' is not supported in math expressions", "").push();
+                return None;
+            }
+            let Some(op) = binop(self.cur().kind) else { break };
+            let prec = precedence(op);
+            if prec < min_prec {
+                break;
+            }
+            self.advance();
+
+            let rhs = self.parse_math(prec + 1)?;
+            let span = lhs.span.extend(&rhs.span);
+            lhs = Expr {
+                kind: ExprKind::Binary(op, Box::new(lhs), Box::new(rhs)),
+                span,
+            };
+        }
+
+        Some(lhs)
+    }
+
+    fn int(&self, token: &Token, radix: u32) -> Option<i128> {
+        match u64::from_str_radix(&token.text, radix) {
+            Ok(n) => Some(n as i128),
+            Err(e) => {
+                let note = match e.kind() {
+                    IntErrorKind::Empty => "Expected digits after the base prefix",
+                    IntErrorKind::InvalidDigit => "Invalid digit for the base",
+                    _ => "Does not fit in 64 bits",
+                };
+                Log::new(ERR, token.span, format!("Invalid integer literal: {}", token.text), note).push();
+                None
+            },
+        }
+    }
+
+    fn register(&self, token: &Token) -> Option<Register> {
+        let Ok(num) = token.text.parse::<u8>() else {
+            Log::new(ERR, token.span, format!("Invalid register: r{}", token.text), "Expected r0 to r255").push();
+            return None;
+        };
+
+        let size = match token.register_size() {
+            1 => RegSize::ByteLow,
+            2 => RegSize::ByteHigh,
+            3 => RegSize::Word,
+            4 => RegSize::DWord,
+            _ => RegSize::QWord,
+        };
+
+        Some(Register { num, size })
+    }
+}
+
+fn is_fill(token: &Token, star: &Token, next: &Token) -> bool {
+    (is_int(token.kind) || token.kind == TokenKind::CharLiteral)
+        && !token.whitespace_after()
+        && star.kind == TokenKind::Star
+        && matches!(next.kind, TokenKind::Comma | TokenKind::RightBrace | TokenKind::RightParen | TokenKind::Newline | TokenKind::EOF)
+}
+
+// |inline|, |macro|, |ignore|, |sysv| or |notail|. Inline and macro are two ways of splicing a
+// function, so only one of them can be used, and neither means anything for an ignored function
+fn fn_attrs(tokens: Vec<Token>) -> FnAttrs {
+    let mut attrs = FnAttrs::default();
+    for (i, token) in tokens.iter().enumerate() {
+        let attr = match token.text.as_str() {
+            "inline" => &mut attrs.inline,
+            "macro" => &mut attrs.macro_,
+            "ignore" => &mut attrs.ignore,
+            "sysv" => &mut attrs.sysv,
+            "notail" => &mut attrs.notail,
+            a => {
+                Log::new(ERR, token.span, format!("Unknown attribute `{}` for a function", a),
+                    "Expected inline, macro, ignore, sysv or notail").push();
+                continue;
+            },
+        };
+        if tokens[..i].iter().any(|t| t.text == token.text) {
+            Log::new(WARN, token.span, format!("Duplicate attribute `{}`", token.text), "").push();
+        }
+        *attr = true;
+    }
+
+    let mut spliced = tokens.iter().filter(|t| t.text == "inline" || t.text == "macro");
+    if attrs.inline && attrs.macro_ {
+        let span = spliced.next_back().unwrap().span;
+        Log::new(ERR, span, "`inline` and `macro` can't be combined", "A function is spliced either way").push();
+    } else if attrs.ignore {
+        for token in spliced {
+            Log::new(WARN, token.span, format!("`{}` has no effect on an ignored function", token.text), "").push();
+        }
+    }
+    if attrs.inline || attrs.macro_ || attrs.ignore {
+        for token in tokens.iter().filter(|t| t.text == "sysv" || t.text == "notail") {
+            Log::new(WARN, token.span, format!("`{}` has no effect on a function that is never called", token.text),
+                "It is spliced or ignored").push();
+        }
+    }
+    attrs
+}
+
+fn starts_expr(kind: TokenKind) -> bool {
+    is_int(kind) || matches!(kind, TokenKind::CharLiteral | TokenKind::Minus | TokenKind::Identifier | TokenKind::Register
+        | TokenKind::LeftParen | TokenKind::LeftBracket | TokenKind::Tilde)
+}
+
+// `(#f - 1)` subtracts from the result, `#f -1` passes -1. Likewise `(#f *getpid)` passes a pid
+fn starts_arg(token: &Token) -> bool {
+    match token.kind {
+        TokenKind::Minus | TokenKind::Star | TokenKind::Ampersand => !token.whitespace_after(),
+        TokenKind::Pound | TokenKind::Dollar | TokenKind::TinyArrowLeft | TokenKind::LeftBrace | TokenKind::StringLiteral => true,
+        kind => starts_expr(kind),
+    }
+}
+
+fn is_int(kind: TokenKind) -> bool {
+    matches!(kind, TokenKind::DecLiteral | TokenKind::HexLiteral | TokenKind::BinLiteral | TokenKind::OctLiteral)
+}
+
+fn binop(kind: TokenKind) -> Option<BinOp> {
+    Some(match kind {
+        TokenKind::Plus => BinOp::Add,
+        TokenKind::Minus => BinOp::Sub,
+        TokenKind::Star => BinOp::Mul,
+        TokenKind::Slash => BinOp::Div,
+        TokenKind::Percent => BinOp::Mod,
+        TokenKind::Tilde => BinOp::AndNot,
+        TokenKind::Pipe => BinOp::Or,
+        TokenKind::Ampersand => BinOp::And,
+        TokenKind::Caret => BinOp::Xor,
+        TokenKind::PipePipe => BinOp::LogOr,
+        TokenKind::AmpersandAmpersand => BinOp::LogAnd,
+        TokenKind::CaretCaret => BinOp::LogXor,
+        TokenKind::GreaterThan => BinOp::Gt,
+        TokenKind::LessThan => BinOp::Lt,
+        TokenKind::GreaterThanEquals => BinOp::Ge,
+        TokenKind::LessThanEquals => BinOp::Le,
+        TokenKind::Equals => BinOp::Eq,
+        TokenKind::TildeEquals => BinOp::Ne,
+        _ => return None,
+    })
+}
+
+// higher binds tighter
+fn precedence(op: BinOp) -> u8 {
+    match op {
+        BinOp::LogOr => 1,
+        BinOp::LogAnd => 2,
+        BinOp::LogXor => 3,
+        BinOp::Gt | BinOp::Lt | BinOp::Ge | BinOp::Le | BinOp::Eq | BinOp::Ne => 4,
+        BinOp::Or => 5,
+        BinOp::Xor => 6,
+        BinOp::And | BinOp::AndNot => 7,
+        BinOp::Add | BinOp::Sub => 8,
+        BinOp::Mul | BinOp::Div | BinOp::Mod => 9,
+    }
+}
+
+fn describe(token: &Token) -> String {
+    match token.kind {
+        TokenKind::Newline => String::from("end of line"),
+        TokenKind::EOF => String::from("end of file"),
+        TokenKind::Register => format!("'r{}'", token.text),
+        _ if token.text.is_empty() => format!("{:?}", token.kind),
+        _ => format!("'{}'", token.text),
+    }
+}
@@ -0,0 +1,236 @@
+use crate::args_parser::ARGS;
+use crate::ast::RegSize;
+use crate::x86_64::{Instr, Mem, Operand, Reg};
+
+// Rewrites of the emitted instructions, one or two neighbours at a time, again and again while
+// any applies. Lowering each IR instruction on its own leaves seams behind, such as a value
+// stored and loaded right back. It runs at -O1 and up, --opt and --no-opt decide otherwise.
+//
+//   before                                     after
+//   mov rbx, rbx                               -
+//   mov rbx, 0                                 xor ebx, ebx
+//   add rbx, 1                                 inc rbx
+//   sub qword ptr [rbp - 8], 1                 dec qword ptr [rbp - 8]
+//   add rbx, 0                                 -
+//   cmp rbx, 0                                 test rbx, rbx
+//   push r11 / pop r11                         -
+//   push 3 / pop r11                           mov r11, 3
+//   mov qword ptr [rbp - 8], r11 /             mov qword ptr [rbp - 8], r11
+//   mov r11, qword ptr [rbp - 8]
+//   mov r11, rbx / mov rbx, r11                mov r11, rbx
+//   mov r11, rbx / mov r11, rcx                mov r11, rcx
+//   mov r11, rbx / add r11, rcx                lea r11, qword ptr [rbx + rcx*1]
+//   mov r11, rbx / sub r11, 8                  lea r11, qword ptr [rbx - 8]
+//   shl r11, 3 / add r11, rbx                  lea r11, qword ptr [rbx + r11*8]
+//   jmp .L1 / .L1:                             .L1:
+//
+// A label ends the window, control may come in between. Rewrites that change what the flags end
+// up as only happen where nothing reads them before they're set again: xor sets them, inc and
+// dec keep the carry and lea leaves them alone. Writing a 32-bit register clears its upper half,
+// so `mov ebx, ebx` and `add ebx, 0` stay.
+
+pub fn enabled() -> bool { unsafe { ARGS.passes.peephole.unwrap_or(ARGS.opt_level >= 1) } }
+
+pub fn peephole(text: &mut Vec<Instr>) {
+    let mut i = 0;
+    while i < text.len() {
+        let with = match text.get(i + 1).and_then(|next| pair(&text[i], next, flags_read(&text[i + 2..]))) {
+            Some(with) => Some((2, with)),
+            None => single(&text[i], flags_read(&text[i + 1..])).map(|with| (1, with)),
+        };
+        match with {
+            // what came out may go with the one before it now
+            Some((n, with)) => {
+                text.splice(i..i + n, with);
+                i = i.saturating_sub(1);
+            },
+            None => i += 1,
+        }
+    }
+}
+
+fn single(instr: &Instr, flags: bool) -> Option<Vec<Instr>> {
+    let out = match instr {
+        Instr::Mov(dst @ Operand::Reg(..), src) if dst == src && !is_dword(dst) => vec![],
+        Instr::Mov(Operand::Reg(dst, RegSize::DWord | RegSize::QWord), Operand::Imm(0)) if !flags => {
+            let dst = dst.sized(RegSize::DWord);
+            vec![Instr::Xor(dst.clone(), dst)]
+        },
+        Instr::Add(dst, Operand::Imm(1)) | Instr::Sub(dst, Operand::Imm(-1)) if !flags => vec![Instr::Inc(dst.clone())],
+        Instr::Sub(dst, Operand::Imm(1)) | Instr::Add(dst, Operand::Imm(-1)) if !flags => vec![Instr::Dec(dst.clone())],
+        Instr::Add(dst, Operand::Imm(0)) | Instr::Sub(dst, Operand::Imm(0)) | Instr::Or(dst, Operand::Imm(0))
+        | Instr::Xor(dst, Operand::Imm(0)) if !flags && !is_dword(dst) => vec![],
+        Instr::Cmp(lhs @ Operand::Reg(..), Operand::Imm(0)) => vec![Instr::Test(lhs.clone(), lhs.clone())],
+        _ => return None,
+    };
+    Some(out)
+}
+
+fn pair(first: &Instr, second: &Instr, flags: bool) -> Option<Vec<Instr>> {
+    let out = match (first, second) {
+        (Instr::Push(src), Instr::Pop(dst)) if src == dst => vec![],
+        // there's no move from memory to memory
+        (Instr::Push(Operand::Mem(_)), Instr::Pop(Operand::Mem(_))) => return None,
+        (Instr::Push(src @ (Operand::Reg(..) | Operand::Mem(_) | Operand::Imm(_))), Instr::Pop(dst))
+            if !mentions(src, Reg::Rsp) && !mentions(dst, Reg::Rsp) =>
+        {
+            vec![Instr::Mov(dst.clone(), src.clone())]
+        },
+
+        // moved back where it came from, the address mustn't be what the first one wrote
+        (Instr::Mov(dst, src), Instr::Mov(back, from)) if back == src && from == dst && !is_dword(back) => match dst {
+            Operand::Reg(reg, _) if mentions(src, *reg) => return None,
+            _ => vec![first.clone()],
+        },
+        // overwritten before anything read it
+        (load, Instr::Mov(Operand::Reg(reg, size), src)) if !mentions(src, *reg) => match loads(load) {
+            Some((dst, old)) if dst == *reg && overwrites(*size, old) => vec![second.clone()],
+            _ => return None,
+        },
+        (Instr::Mov(Operand::Mem(dst), _), Instr::Mov(Operand::Mem(again), Operand::Reg(..) | Operand::Imm(_))) if dst == again => {
+            vec![second.clone()]
+        },
+
+        (Instr::Mov(Operand::Reg(dst, RegSize::QWord), Operand::Reg(src, RegSize::QWord)), Instr::Add(Operand::Reg(to, RegSize::QWord), rhs)
+            | Instr::Sub(Operand::Reg(to, RegSize::QWord), rhs)) if dst == to && !flags =>
+        {
+            let negate = matches!(second, Instr::Sub(..));
+            let mem = match rhs {
+                Operand::Reg(index, RegSize::QWord) if !negate && index != dst && *index != Reg::Rsp => Mem {
+                    index: Some((*index, 1)),
+                    ..Mem::base(8, *src, 0)
+                },
+                Operand::Imm(n) if !negate || *n != i32::MIN as i64 => Mem::base(8, *src, if negate { -n } else { *n }),
+                _ => return None,
+            };
+            vec![Instr::Lea(dst.q(), Operand::Mem(mem))]
+        },
+        (Instr::Shl(Operand::Reg(dst, RegSize::QWord), Operand::Imm(shift @ 1..=3)), Instr::Add(Operand::Reg(to, RegSize::QWord), Operand::Reg(base, RegSize::QWord)))
+            if dst == to && base != dst && *dst != Reg::Rsp && !flags =>
+        {
+            let mem = Mem {
+                index: Some((*dst, 1 << shift)),
+                ..Mem::base(8, *base, 0)
+            };
+            vec![Instr::Lea(dst.q(), Operand::Mem(mem))]
+        },
+
+        (Instr::Jmp(Operand::Label(to)), Instr::Label(label)) if to == label => vec![second.clone()],
+        _ => return None,
+    };
+    Some(out)
+}
+
+// whether an instruction in `rest` reads the flags before they're set again. Control leaving
+// or coming in ends the search, the backend only tests flags right where it set them
+fn flags_read(rest: &[Instr]) -> bool {
+    for instr in rest {
+        match instr {
+            Instr::J(..) | Instr::Set(..) => return true,
+            Instr::Cmp(..) | Instr::Test(..) | Instr::Add(..) | Instr::Sub(..) | Instr::And(..) | Instr::Or(..)
+            | Instr::Xor(..) | Instr::Imul(..) => return false,
+            Instr::Label(_) | Instr::Jmp(_) | Instr::Call(_) | Instr::Syscall | Instr::Ret | Instr::Ud2 => return false,
+            _ => (),
+        }
+    }
+    false
+}
+
+// the register an instruction only loads, and how much of it
+fn loads(instr: &Instr) -> Option<(Reg, RegSize)> {
+    match instr {
+        Instr::Mov(Operand::Reg(reg, size), _) | Instr::Movzx(Operand::Reg(reg, size), _) | Instr::Movsx(Operand::Reg(reg, size), _)
+        | Instr::Movsxd(Operand::Reg(reg, size), _) | Instr::Lea(Operand::Reg(reg, size), _) => Some((*reg, *size)),
+        Instr::Movabs(Operand::Reg(reg, size), _) => Some((*reg, *size)),
+        _ => None,
+    }
+}
+
+// whether writing `size` of a register leaves nothing of what writing `old` of it put there.
+// 32-bit writes clear the rest
+fn overwrites(size: RegSize, old: RegSize) -> bool { matches!(size, RegSize::QWord | RegSize::DWord) || size == old }
+
+fn mentions(operand: &Operand, reg: Reg) -> bool {
+    match operand {
+        Operand::Reg(r, _) => *r == reg,
+        Operand::Mem(mem) => mem.base == Some(reg) || mem.index.is_some_and(|(r, _)| r == reg),
+        Operand::Imm(_) | Operand::Label(_) => false,
+    }
+}
+
+fn is_dword(operand: &Operand) -> bool { matches!(operand, Operand::Reg(_, RegSize::DWord)) }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::x86_64::Cond;
+    use Reg::*;
+
+    fn mem(base: Reg, disp: i64) -> Operand { Operand::Mem(Mem::base(8, base, disp)) }
+
+    fn label(name: &str) -> Operand { Operand::Label(name.to_string()) }
+
+    fn show(text: &[Instr]) -> String { text.iter().map(|i| i.to_string().trim().to_string()).collect::<Vec<_>>().join(" / ") }
+
+    // what the pass makes of `text`
+    fn run(mut text: Vec<Instr>) -> String {
+        peephole(&mut text);
+        show(&text)
+    }
+
+    #[test]
+    fn rewrites() {
+        let table = [
+            (vec![Instr::Mov(Rbx.q(), Rbx.q())], ""),
+            (vec![Instr::Mov(Rbx.q(), Operand::Imm(0))], "xor ebx, ebx"),
+            (vec![Instr::Add(Rbx.q(), Operand::Imm(1))], "inc rbx"),
+            (vec![Instr::Sub(mem(Rbp, -8), Operand::Imm(1))], "dec qword ptr [rbp - 8]"),
+            (vec![Instr::Add(Rbx.q(), Operand::Imm(0))], ""),
+            (vec![Instr::Cmp(Rbx.q(), Operand::Imm(0))], "test rbx, rbx"),
+            (vec![Instr::Push(R11.q()), Instr::Pop(R11.q())], ""),
+            (vec![Instr::Push(Operand::Imm(3)), Instr::Pop(R11.q())], "mov r11, 3"),
+            (vec![Instr::Mov(mem(Rbp, -8), R11.q()), Instr::Mov(R11.q(), mem(Rbp, -8))], "mov qword ptr [rbp - 8], r11"),
+            (vec![Instr::Mov(R11.q(), Rbx.q()), Instr::Mov(Rbx.q(), R11.q())], "mov r11, rbx"),
+            (vec![Instr::Mov(R11.q(), Rbx.q()), Instr::Mov(R11.q(), Rcx.q())], "mov r11, rcx"),
+            (vec![Instr::Mov(R11.q(), Rbx.q()), Instr::Add(R11.q(), Rcx.q())], "lea r11, qword ptr [rbx + rcx*1]"),
+            (vec![Instr::Mov(R11.q(), Rbx.q()), Instr::Sub(R11.q(), Operand::Imm(8))], "lea r11, qword ptr [rbx - 8]"),
+            (vec![Instr::Shl(R11.q(), Operand::Imm(3)), Instr::Add(R11.q(), Rbx.q())], "lea r11, qword ptr [rbx + r11*8]"),
+            (vec![Instr::Jmp(label(".L1")), Instr::Label(".L1".to_string())], ".L1:"),
+            // what came out goes with the one before it
+            (vec![Instr::Push(Rbx.q()), Instr::Push(R11.q()), Instr::Pop(R11.q()), Instr::Pop(Rcx.q())], "mov rcx, rbx"),
+        ];
+        for (before, after) in table {
+            let shown = run(before.clone());
+            assert_eq!(shown, after, "from {:?}", before);
+        }
+    }
+
+    #[test]
+    fn leaves_alone() {
+        let table = [
+            // the jump reads what cmp set, xor would clobber it
+            vec![Instr::Cmp(Rax.q(), Rbx.q()), Instr::Mov(Rcx.q(), Operand::Imm(0)), Instr::J(Cond::L, label(".L1"))],
+            // jb reads the carry, which inc keeps
+            vec![Instr::Add(Rbx.q(), Operand::Imm(1)), Instr::J(Cond::B, label(".L1"))],
+            vec![Instr::Sub(Rbx.q(), Operand::Imm(1)), Instr::Set(Cond::B, Rcx.sized(RegSize::ByteLow))],
+            vec![Instr::Mov(R11.q(), Rbx.q()), Instr::Add(R11.q(), Rcx.q()), Instr::J(Cond::E, label(".L1"))],
+            // 32-bit writes clear the upper half
+            vec![Instr::Mov(Rbx.sized(RegSize::DWord), Rbx.sized(RegSize::DWord))],
+            vec![Instr::Add(Rbx.sized(RegSize::DWord), Operand::Imm(0))],
+            // rsp moves between the two
+            vec![Instr::Push(Rsp.q()), Instr::Pop(R11.q())],
+            vec![Instr::Push(mem(Rbp, -8)), Instr::Pop(mem(Rbp, -16))],
+            // the address is what the first one wrote
+            vec![Instr::Mov(R11.q(), mem(R11, 8)), Instr::Mov(mem(R11, 8), R11.q())],
+            // a label in between, control may come in there
+            vec![Instr::Mov(R11.q(), Rbx.q()), Instr::Label(".L1".to_string()), Instr::Mov(Rbx.q(), R11.q())],
+            vec![Instr::Jmp(label(".L1")), Instr::Label(".L2".to_string())],
+            // lea can't subtract a register
+            vec![Instr::Mov(R11.q(), Rbx.q()), Instr::Sub(R11.q(), Rcx.q())],
+        ];
+        for before in table {
+            let shown = run(before.clone());
+            assert_eq!(shown, show(&before), "from {:?}", before);
+        }
+    }
+}
@@ -0,0 +1,371 @@
+use std::collections::HashMap;
+
+use crate::args_parser::ARGS;
+use crate::cfg::{self, Cfg, Regs};
+use crate::compiler::ARG_REGS;
+use crate::frame::Layout;
+use crate::ir::{Callee, Inst, Mem, Operand, Program, VReg, ACC, TMP};
+use crate::logger::{Log, ERR};
+use crate::x86_64::Reg;
+
+// Gives Shard's registers homes in x86_64 ones. r10 and r11 are never handed out, they hold the
+// temporaries TMP and ACC and the backend uses r10 as scratch.
+//
+// With -R the mapping is fixed: r0 = rax, r1 = rbx, r2 = rcx, r3 = rdx, r4 = rsi, r5 = rdi,
+// r6 = r8, r7 = r9, r8 = r12, r9 = r13, r10 = r14 and r11 = r15, see FIXED_REGS. Only r0 to r11
+// exist then, and what a register holds is where the hardware sees it, e.g. r5 is the first
+// argument of a System V function. `rNh` is the exception: ah, bh, ch and dh can't be encoded
+// in an instruction with a REX prefix, which every move through ACC and TMP (r11 and r10) needs,
+// so bits 8 to 15 are reached by shifting and masking the whole home, even for r0 to r3, and
+// the high byte registers are never emitted.
+//
+// Otherwise any of r0 to r255 may be used. r0 stays in rax, where results come back, the others
+// are spread over the remaining eleven by linear scan. Registers are shared by the whole
+// program, a function reads what its caller left in them and jumps carry them from one function
+// into another, so each one gets a single home everywhere. Its live interval runs from the first
+// to the last instruction it's live at, with the functions one after the other in program order,
+// and registers whose intervals don't overlap share a home. Calls needn't split intervals, a
+// native callee saves whatever it overwrites. Registers a callee reads are live at the call and
+// stay out of the argument registers, which the call loads.
+//
+// When more registers are live at once than there are homes, the one staying live the longest
+// lives in memory instead, in a `.spill<n>` slot of the function's frame. SCRATCH then keeps
+// three homes back, and spilled registers are loaded into them around each instruction that
+// mentions them. A frame belongs to one call of one function, so only registers that never
+// leave their function can be spilled: mentioned nowhere else, and not live where control comes
+// in from outside it. Each call gets a slot of its own the way a callee saves the homes it
+// overwrites, so recursion works the same either way. When only registers that are passed
+// between functions are left, that's an error.
+
+pub const FIXED_REGS: [Reg; 12] = [
+    Reg::Rax, Reg::Rbx, Reg::Rcx, Reg::Rdx, Reg::Rsi, Reg::Rdi,
+    Reg::R8, Reg::R9, Reg::R12, Reg::R13, Reg::R14, Reg::R15,
+];
+
+// spilled registers are loaded into these, an instruction mentions three registers at most
+pub const SCRATCH: [VReg; 3] = [VReg(258), VReg(259), VReg(260)];
+
+pub struct Homes {
+    regs: HashMap<VReg, Reg>,
+}
+
+impl Homes {
+    pub fn reg(&self, reg: VReg) -> Reg {
+        match reg {
+            ACC => Reg::R11,
+            TMP => Reg::R10,
+            reg => self.regs[&reg],
+        }
+    }
+}
+
+// how many of Shard's registers there are
+pub fn available() -> usize {
+    match unsafe { ARGS.fixed_regs } {
+        true => FIXED_REGS.len(),
+        false => 256,
+    }
+}
+
+pub fn allocate(program: &mut Program, layout: &mut Layout) -> Homes {
+    if unsafe { ARGS.fixed_regs } {
+        return Homes {
+            regs: FIXED_REGS.iter().enumerate().map(|(i, r)| (VReg(i as u16), *r)).collect(),
+        };
+    }
+
+    let cfg = Cfg::new(program);
+    let (intervals, forbidden) = intervals(&cfg);
+    let local = local(&cfg);
+    let pool = &FIXED_REGS[1..];
+    let (mut regs, mut spilled) = scan(&intervals, &forbidden, &local, pool);
+    if !spilled.is_empty() {
+        let (kept, scratch) = pool.split_at(pool.len() - SCRATCH.len());
+        (regs, spilled) = scan(&intervals, &forbidden, &local, kept);
+        regs.extend(SCRATCH.iter().copied().zip(scratch.iter().copied()));
+    }
+    regs.insert(VReg(0), Reg::Rax);
+
+    let shared = spilled.iter().filter(|r| !cfg::contains(&local, **r)).map(|r| r.to_string()).collect::<Vec<_>>();
+    if !shared.is_empty() {
+        Log::new(ERR, None, format!("Too many registers live at once, {} can't be spilled", shared.join(", ")),
+            "Only registers used within a single function can live in its stack frame").push();
+        return Homes { regs };
+    }
+    spill(program, layout, &spilled);
+    Homes { regs }
+}
+
+// a register and the first and last instruction it's live at
+type Interval = (VReg, usize, usize);
+
+// the interval of every register but r0, sorted by where they start, and the homes each must
+// stay out of
+fn intervals(cfg: &Cfg) -> (Vec<Interval>, HashMap<VReg, Vec<Reg>>) {
+    let (ins, outs) = cfg.liveness();
+    let mut bounds: HashMap<VReg, (usize, usize)> = HashMap::new();
+    let mut forbidden: HashMap<VReg, Vec<Reg>> = HashMap::new();
+    let mut extend = |reg: VReg, at: usize| {
+        if reg.is_temp() || reg == VReg(0) {
+            return;
+        }
+        let (lo, hi) = bounds.entry(reg).or_insert((at, at));
+        *lo = (*lo).min(at);
+        *hi = (*hi).max(at);
+    };
+
+    let mut at = 0;
+    for (i, &out) in outs.iter().enumerate() {
+        let insts = &cfg.block(i).insts;
+        let mut live = out;
+        cfg::members(&live).for_each(|reg| extend(reg, at + insts.len()));
+        for (k, inst) in insts.iter().enumerate().rev() {
+            inst.regs().into_iter().chain(inst.writes()).for_each(|reg| extend(reg, at + k));
+            live = cfg.live_before(&ins, inst, live);
+            cfg::members(&live).for_each(|reg| extend(reg, at + k));
+            if let Inst::Call(Callee::Native(name), args @ 1.., _) = inst {
+                let Some(&callee) = cfg.index.get(name.as_str()) else { continue };
+                for reg in cfg::members(&ins[callee]) {
+                    forbidden.entry(reg).or_default().extend(&ARG_REGS[..(*args).min(ARG_REGS.len())]);
+                }
+            }
+        }
+        at += insts.len() + 1;
+    }
+
+    let mut intervals = bounds.into_iter().map(|(reg, (lo, hi))| (reg, lo, hi)).collect::<Vec<_>>();
+    intervals.sort_by_key(|&(reg, lo, _)| (lo, reg));
+    (intervals, forbidden)
+}
+
+// the registers that never leave their function: mentioned in no other one and live neither
+// where control comes into the graph nor in another function's blocks
+fn local(cfg: &Cfg) -> Regs {
+    let (ins, outs) = cfg.liveness();
+    let mut owner: HashMap<VReg, Option<usize>> = HashMap::new();
+    for (i, &(f, _)) in cfg.nodes.iter().enumerate() {
+        for inst in &cfg.block(i).insts {
+            for reg in inst.regs().into_iter().chain(inst.writes()) {
+                let only = owner.entry(reg).or_insert(Some(f));
+                if *only != Some(f) {
+                    *only = None;
+                }
+            }
+        }
+    }
+
+    let mut local = [0; 5];
+    for (reg, f) in owner {
+        if let Some(f) = f {
+            cfg::insert(&mut local, reg);
+            for (i, &(g, _)) in cfg.nodes.iter().enumerate() {
+                if g != f && (cfg::contains(&ins[i], reg) || cfg::contains(&outs[i], reg)) {
+                    cfg::remove(&mut local, reg);
+                }
+            }
+        }
+    }
+    for root in cfg.roots() {
+        for reg in cfg::members(&ins[root]) {
+            cfg::remove(&mut local, reg);
+        }
+    }
+    local
+}
+
+// linear scan over `pool`, handing back the homes and the registers that got none. Only `local`
+// registers give way to another one
+fn scan(intervals: &[Interval], forbidden: &HashMap<VReg, Vec<Reg>>, local: &Regs, pool: &[Reg]) -> (HashMap<VReg, Reg>, Vec<VReg>) {
+    let mut homes = HashMap::new();
+    let mut spilled = Vec::new();
+    let mut active: Vec<(usize, VReg, Reg)> = Vec::new(); // where it ends
+    for &(reg, start, end) in intervals {
+        active.retain(|&(until, ..)| until >= start);
+        let allowed = |home: &Reg| forbidden.get(&reg).is_none_or(|f| !f.contains(home));
+        let free = pool.iter().find(|&home| allowed(home) && active.iter().all(|&(.., h)| h != *home));
+        if let Some(&home) = free {
+            homes.insert(reg, home);
+            active.push((end, reg, home));
+            continue;
+        }
+
+        // the one staying live the longest gives way, or one that can when this one can't
+        let spillable = cfg::contains(local, reg);
+        let longest = active.iter().enumerate()
+            .filter(|(_, (until, victim, home))| (*until > end || !spillable) && cfg::contains(local, *victim) && allowed(home))
+            .max_by_key(|(_, (until, ..))| *until)
+            .map(|(i, _)| i);
+        match longest {
+            Some(i) => {
+                let (_, victim, home) = active.remove(i);
+                homes.remove(&victim);
+                spilled.push(victim);
+                homes.insert(reg, home);
+                active.push((end, reg, home));
+            },
+            None => spilled.push(reg),
+        }
+    }
+    (homes, spilled)
+}
+
+// gives every spilled register a slot in the frame of its function, loading it into a scratch
+// register before each instruction that needs it and storing it back after each one that writes it
+fn spill(program: &mut Program, layout: &mut Layout, spilled: &[VReg]) {
+    for func in &mut program.functions {
+        let frame = &mut layout.functions[func.func].frame;
+        for block in &mut func.blocks {
+            let mut insts = Vec::new();
+            for (i, mut inst) in std::mem::take(&mut block.insts).into_iter().enumerate() {
+                let mut regs = inst.regs().into_iter().chain(inst.writes()).filter(|r| spilled.contains(r)).collect::<Vec<_>>();
+                regs.sort();
+                regs.dedup();
+                assert!(regs.len() <= SCRATCH.len(), "`{}` mentions more spilled registers than there are scratch ones", inst);
+
+                let (uses, writes) = (inst.uses(), inst.writes());
+                let mut stores = Vec::new();
+                for (reg, scratch) in regs.iter().zip(SCRATCH) {
+                    let span = block.span_at(i).expect("lowered instructions come from a statement");
+                    let offset = frame.spill_slot(format!(".spill{}", reg.0), span);
+                    let mem = Operand::Mem(Mem::frame(8, -(offset as i64)));
+                    if uses.contains(reg) {
+                        insts.push(Inst::Mov(scratch.q(), mem.clone()));
+                    }
+                    if writes == Some(*reg) {
+                        stores.push(Inst::Mov(mem, scratch.q()));
+                    }
+                }
+                inst.map_regs(&mut |r| regs.iter().position(|&s| s == r).map_or(r, |i| SCRATCH[i]));
+                insts.push(inst);
+                insts.extend(stores);
+            }
+            block.insts = insts;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::{self, Frame};
+    use crate::ir::tests::read;
+
+    // a frame of its own for every function, with nothing in it yet
+    fn layout(program: &Program) -> Layout {
+        Layout {
+            blocks: Vec::new(),
+            functions: program.functions.iter()
+                .map(|f| frame::Function {
+                    name: f.name.clone(),
+                    frame: Frame { slots: Vec::new(), regs: Vec::new(), size: 0 },
+                    def: None,
+                })
+                .collect(),
+            labels: HashMap::new(),
+            taken: Vec::new(),
+        }
+    }
+
+    // r1 to r14 are written first, then read one after the other with r1 last
+    fn crowded() -> Program {
+        let mut text = String::from("fn main sysv {\nmain:\n");
+        text += &(1..=14).map(|r| format!("    mov r{}, {}\n", r, r)).collect::<String>();
+        text += "    mov r0, 0\n";
+        text += &(2..=14).chain([1]).map(|r| format!("    add r0, r{}\n", r)).collect::<String>();
+        text += "    ret r0\n}\n";
+        read(&text)
+    }
+
+    #[test]
+    fn overlapping_registers_get_different_homes() {
+        let mut program = read("
+fn main sysv {
+main:
+    mov r1, 1
+    mov r2, 2
+    add r1, r2
+    mov r3, r1
+    add r3, 1
+    mov r0, r3
+    ret r0
+}
+");
+        let mut layout = layout(&program);
+        let homes = allocate(&mut program, &mut layout);
+        assert_eq!(homes.reg(VReg(0)), Reg::Rax);
+        assert_ne!(homes.reg(VReg(1)), homes.reg(VReg(2)));
+        assert_ne!(homes.reg(VReg(1)), homes.reg(VReg(3)));
+        assert!(layout.functions[0].frame.slots.is_empty());
+        assert_eq!(layout.functions[0].frame.size, 0);
+    }
+
+    #[test]
+    fn the_longest_lived_register_is_spilled() {
+        let mut program = crowded();
+        let mut layout = layout(&program);
+        let homes = allocate(&mut program, &mut layout);
+
+        // three homes go to SCRATCH, which leaves eight for fourteen registers
+        let spilled = (1..=14).map(VReg).filter(|r| !homes.regs.contains_key(r)).collect::<Vec<_>>();
+        assert_eq!(spilled.len(), 6, "{:?}", spilled);
+        assert!(spilled.contains(&VReg(1)), "{:?}", spilled);
+        for scratch in SCRATCH {
+            assert!(!(1..=14).any(|r| homes.regs.get(&VReg(r)) == Some(&homes.reg(scratch))));
+        }
+
+        let frame = &layout.functions[0].frame;
+        let mut slots = frame.slots.iter().map(|s| (s.name.as_str(), s.offset)).collect::<Vec<_>>();
+        slots.sort_by_key(|&(_, offset)| offset);
+        assert_eq!(slots.len(), spilled.len());
+        assert_eq!(slots.iter().map(|&(_, o)| o).collect::<Vec<_>>(), [8, 16, 24, 32, 40, 48]);
+        assert_eq!(frame.size, 48);
+
+        // loaded before each use and stored after each write, from its own slot
+        let r1 = frame.slot(".spill1").unwrap().offset;
+        let text = program.to_string();
+        assert!(text.contains(&format!("    mov t2, 1\n    mov q[frame - {}], t2\n", r1)), "{}", text);
+        assert!(text.contains(&format!("    mov t2, q[frame - {}]\n    add r0, t2\n    ret r0\n", r1)), "{}", text);
+    }
+
+    #[test]
+    fn local_registers_stay_in_one_function() {
+        let program = read("
+fn main sysv {
+main:
+    mov r1, 1
+    mov r2, 2
+    mov r3, 3
+    call f, 0 -> r0
+    add r0, r2
+    ret r0
+}
+
+fn f {
+f:
+    add r3, r4
+    mov r5, 5
+    add r5, r1
+    mov r0, r5
+    ret r0
+}
+");
+        let local = local(&Cfg::new(&program));
+        // r1 goes into f, r3 is mentioned in both and r4 comes in from whoever calls f
+        assert_eq!(cfg::members(&local).map(|r| r.to_string()).collect::<Vec<_>>(), ["r2", "r5"]);
+    }
+
+    // an instruction can't be rewritten when it needs more scratch registers than there are
+    #[test]
+    #[should_panic(expected = "mentions more spilled registers than there are scratch ones")]
+    fn four_spilled_registers_in_one_instruction() {
+        let mut program = read("
+fn main sysv {
+main:
+    set.eq r1l, q[r2 + r3*8], r4
+    ret
+}
+");
+        let mut layout = layout(&program);
+        spill(&mut program, &mut layout, &[VReg(1), VReg(2), VReg(3), VReg(4)]);
+    }
+}
@@ -1,14 +1,17 @@
 use crate::location::Span;
 
 #[allow(clippy::upper_case_acronyms)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum TokenKind {
     Ampersand,
+    AmpersandAmpersand,
+    Apostrophe,
     At,
     Backslash,
     Bang,
     BinLiteral,
     Caret,
+    CaretCaret,
     CharLiteral,
     Colon,
     Comma,
@@ -16,6 +19,7 @@ pub enum TokenKind {
     Dollar,
     Dot,
     EOF,
+    End,
     Equals,
     FatArrow,
     FloatLiteral,
@@ -36,6 +40,7 @@ pub enum TokenKind {
     OctLiteral,
     Percent,
     Pipe,
+    PipePipe,
     Plus,
     PlusPlus,
     Pound,
@@ -50,11 +55,13 @@ pub enum TokenKind {
     Star,
     StringLiteral,
     Tilde,
+    TildeEquals,
     TinyArrowLeft,
     TinyArrowRight,
     Underscore,
 }
 
+#[derive(Clone)]
 pub struct Token {
     pub kind: TokenKind,
     pub span: Span,
@@ -90,6 +97,7 @@ impl Token {
             kind: match text.as_ref() {
                 "ret" => TokenKind::Ret,
                 "jmp" => TokenKind::Jmp,
+                "end" => TokenKind::End,
                 _ => TokenKind::Identifier,
             },
             span,
@@ -0,0 +1,1044 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+
+use crate::ast::*;
+use crate::frame::{align_up, Frame, Layout};
+use crate::location::Span;
+use crate::logger::{Log, ERR, WARN};
+use crate::x86_64::{syscall, SYSCALL_ARGS};
+
+// Everything in Shard is either a number of some bit width, or an aggregate that lives in
+// memory (a data block or an array). Numbers mix freely, aggregates only with their own type.
+
+pub struct Types {
+    blocks: HashMap<Name, DataBlock>,
+    returns: HashMap<Name, Type>, // of `@` functions and `.extern`s that return something
+}
+
+enum Value {
+    Number(u32, bool), // width in bits, signed
+    Aggregate(Type),
+    Error, // of an unknown type, which was reported already and goes with anything
+}
+
+impl Types {
+    pub fn block(&self, name: &str) -> Option<&DataBlock> { self.blocks.get(name) }
+
+    pub fn size_of(&self, ty: &Type) -> u64 {
+        match ty {
+            Type::Size(s) | Type::Signed(s) => *s as u64,
+            Type::Bits(_, hi) => storage_bytes(*hi),
+            // the end of the last field, padded so arrays of the block stay aligned
+            Type::Named(name) => self.block(name).map_or(0, |b| {
+                let offsets = self.offsets(b);
+                let end = match (offsets.last(), b.fields.last()) {
+                    (Some(offset), Some(field)) => offset + self.size_of(&field.ty),
+                    _ => 0,
+                };
+                align_up(end, self.align_of(ty))
+            }),
+            Type::Array(elem, len) => self.size_of(elem) * len.unwrap_or(0),
+        }
+    }
+
+    pub fn align_of(&self, ty: &Type) -> u64 {
+        match ty {
+            Type::Size(s) | Type::Signed(s) => *s as u64,
+            Type::Bits(_, hi) => storage_bytes(*hi),
+            Type::Named(name) => match self.block(name) {
+                Some(b) if !b.packed => b.fields.iter().map(|f| self.align_of(&f.ty)).max().unwrap_or(1),
+                _ => 1,
+            },
+            Type::Array(elem, _) => self.align_of(elem),
+        }
+    }
+
+    // whether an expression is computed signed, the code generator picks its instructions by this
+    pub fn is_signed(&self, frame: &Frame, expr: &Expr) -> bool {
+        let signed = |ty: &Type| matches!(ty, Type::Signed(_));
+        match &expr.kind {
+            ExprKind::Int(n) | ExprKind::Fill(n) => *n < 0,
+            ExprKind::Ident(name) => match frame.reg(name) {
+                Some(var) => var.signed,
+                None => frame.slot(name).is_some_and(|s| signed(&s.ty)),
+            },
+            ExprKind::ArrIndex(name, _) => match frame.slot(name).map(|s| &s.ty) {
+                Some(Type::Array(elem, _)) => signed(elem),
+                _ => false,
+            },
+            ExprKind::StructIndex(name, field) => match frame.slot(name).map(|s| &s.ty) {
+                Some(Type::Named(block)) => self.block(block)
+                    .and_then(|b| self.field(b, field))
+                    .is_some_and(|f| signed(&f.ty)),
+                _ => false,
+            },
+            ExprKind::Not(inner) => self.is_signed(frame, inner),
+            ExprKind::Deref(_) => false,
+            ExprKind::Binary(op, _, _) if op.is_comparison() || op.is_logical() => false,
+            ExprKind::Binary(_, lhs, rhs) => self.is_signed(frame, lhs) || self.is_signed(frame, rhs),
+            ExprKind::Call(Callee::Named(name) | Callee::Extern(name), _) => self.returns.get(name).is_some_and(signed),
+            // negative errno values on failure
+            ExprKind::Call(Callee::Sys(_), _) => true,
+            ExprKind::Register(_) | ExprKind::Array(_) | ExprKind::Str(_) | ExprKind::SizeOf(_) | ExprKind::OffsetOf(..)
+            | ExprKind::Call(..) | ExprKind::LabelAddr(_) => false,
+        }
+    }
+
+    // byte offset of every field of a data block. Every field starts at the next multiple of its
+    // alignment (its size, or the largest alignment within it), `|packed|` blocks drop the padding.
+    // A bit field shares the storage unit of the bit field right before it when both units have
+    // the same size and its range starts at or after where the previous one ends, so
+    //     a: 0:1
+    //     b: 1:4
+    //     c: 8:16
+    // puts `a` and `b` into the byte at offset 0, while `c` (a 2 byte unit) starts at offset 2, or
+    // right after at offset 1 in a `|packed|` block.
+    // Unused bits of a unit are left alone by every write.
+    pub fn offsets(&self, block: &DataBlock) -> Vec<u64> {
+        let mut offsets = Vec::with_capacity(block.fields.len());
+        let mut end = 0;
+        let mut unit: Option<(u64, u8)> = None; // the last bit field's unit size and its `hi`
+
+        for field in &block.fields {
+            match (&field.ty, unit) {
+                (Type::Bits(lo, hi), Some((size, prev_hi))) if storage_bytes(*hi) == size && *lo >= prev_hi => {
+                    offsets.push(end - size);
+                    unit = Some((size, *hi));
+                },
+                (ty, _) => {
+                    if !block.packed {
+                        end = align_up(end, self.align_of(ty));
+                    }
+                    offsets.push(end);
+                    end += self.size_of(ty);
+                    unit = match ty {
+                        Type::Bits(_, hi) => Some((storage_bytes(*hi), *hi)),
+                        _ => None,
+                    };
+                },
+            }
+        }
+        offsets
+    }
+
+    // bytes of a typed expression. Literals and derefs have none of their own, a deref loads as
+    // many bytes as what it's combined with or stored into, 8 when nothing says otherwise
+    pub fn width(&self, frame: &Frame, expr: &Expr) -> Option<u64> {
+        match &expr.kind {
+            ExprKind::Ident(name) => match frame.reg(name) {
+                Some(var) => Some(reg_bytes(var.reg.size) as u64),
+                None => frame.slot(name).and_then(|s| self.number_size(&s.ty)),
+            },
+            ExprKind::Register(reg) => Some(reg_bytes(reg.size) as u64),
+            ExprKind::ArrIndex(name, _) => match frame.slot(name).map(|s| &s.ty) {
+                Some(Type::Array(elem, _)) => self.number_size(elem),
+                _ => None,
+            },
+            ExprKind::StructIndex(name, field) => match frame.slot(name).map(|s| &s.ty) {
+                Some(Type::Named(block)) => self.block(block)
+                    .and_then(|b| self.field(b, field))
+                    .and_then(|f| self.number_size(&f.ty)),
+                _ => None,
+            },
+            ExprKind::Not(inner) => self.width(frame, inner),
+            ExprKind::Binary(op, _, _) if op.is_comparison() || op.is_logical() => None,
+            ExprKind::Binary(_, lhs, rhs) => match (self.width(frame, lhs), self.width(frame, rhs)) {
+                (Some(l), Some(r)) => Some(l.max(r)),
+                (l, r) => l.or(r),
+            },
+            ExprKind::Call(Callee::Named(name) | Callee::Extern(name), _) => self.returns.get(name).map(|ty| self.size_of(ty)),
+            _ => None,
+        }
+    }
+
+    fn number_size(&self, ty: &Type) -> Option<u64> {
+        match ty {
+            Type::Size(_) | Type::Signed(_) | Type::Bits(..) => Some(self.size_of(ty)),
+            Type::Named(_) | Type::Array(..) => None,
+        }
+    }
+
+    pub fn field_index(&self, block: &DataBlock, field: &FieldRef) -> Option<usize> {
+        match field {
+            FieldRef::Name(name) => block.fields.iter().position(|f| f.name.as_ref() == Some(name)),
+            FieldRef::Index(i) => Some(*i as usize).filter(|&i| i < block.fields.len()),
+        }
+    }
+
+    fn field<'t>(&self, block: &'t DataBlock, field: &FieldRef) -> Option<&'t Field> {
+        self.field_index(block, field).map(|i| &block.fields[i])
+    }
+
+    fn value_of(&self, ty: &Type) -> Value {
+        match ty {
+            Type::Size(s) => Value::Number(*s as u32 * 8, false),
+            Type::Signed(s) => Value::Number(*s as u32 * 8, true),
+            Type::Bits(lo, hi) => Value::Number(hi.saturating_sub(*lo) as u32, false),
+            Type::Named(name) if !self.blocks.contains_key(name) => Value::Error,
+            Type::Array(elem, _) if matches!(self.value_of(elem), Value::Error) => Value::Error,
+            ty => Value::Aggregate(ty.clone()),
+        }
+    }
+
+    fn check_type(&self, ty: &Type, span: Span) -> bool {
+        match ty {
+            Type::Size(_) | Type::Signed(_) => true,
+            Type::Bits(lo, hi) if lo < hi && *hi <= 64 => true,
+            Type::Bits(lo, hi) => {
+                Log::new(ERR, span, format!("Invalid bit range {}:{}", lo, hi), "Expected lo < hi <= 64").push();
+                false
+            },
+            Type::Named(name) if self.blocks.contains_key(name) => true,
+            Type::Named(name) => {
+                Log::new(ERR, span, format!("Unknown type `{}`", name), "Expected a size or a data block").push();
+                false
+            },
+            Type::Array(_, Some(0)) => {
+                Log::new(ERR, span, "Arrays need at least one element", "").push();
+                false
+            },
+            Type::Array(elem, _) if matches!(**elem, Type::Array(..)) => {
+                Log::new(ERR, span, "Arrays of arrays are not supported", "Wrap the inner array in a data block").push();
+                false
+            },
+            Type::Array(elem, _) => self.check_type(elem, span),
+        }
+    }
+
+    // data blocks can't contain themselves, not even through another block or an array
+    fn contains(&self, ty: &Type, target: &str, seen: &mut Vec<Name>) -> bool {
+        match ty {
+            Type::Named(name) if name == target => true,
+            Type::Named(name) if seen.contains(name) => false,
+            Type::Named(name) => {
+                seen.push(name.clone());
+                self.block(name).is_some_and(|b| b.fields.iter().any(|f| self.contains(&f.ty, target, seen)))
+            },
+            Type::Array(elem, _) => self.contains(elem, target, seen),
+            _ => false,
+        }
+    }
+}
+
+pub fn check(program: &Program, layout: &mut Layout) -> Types {
+    let mut types = Types {
+        blocks: HashMap::new(),
+        returns: program.functions.iter().map(|f| (&f.name, &f.ret))
+            .chain(program.externs.iter().map(|e| (&e.name, &e.ret)))
+            .filter_map(|(name, ret)| Some((name.clone(), ret.clone()?)))
+            .collect(),
+    };
+
+    for block in &program.data {
+        if types.blocks.contains_key(&block.name) || layout.labels.contains_key(&block.name) {
+            Log::new(ERR, block.span, format!("`{}` is already defined", block.name), "").push();
+            continue;
+        }
+        types.blocks.insert(block.name.clone(), block.clone());
+    }
+
+    for block in &program.data {
+        check_datablock(&types, block);
+    }
+
+    resolve_lengths(program, layout);
+
+    for func in &layout.functions {
+        for slot in &func.frame.slots {
+            types.check_type(&slot.ty, slot.span);
+        }
+    }
+
+    // parameters and return values are passed in registers
+    let params = program.functions.iter()
+        .flat_map(|f| f.params.iter().map(|p| (&p.ty, p.span, "Parameters")).chain(f.ret.iter().map(|ty| (ty, f.span, "Return values"))));
+    let extern_params = program.externs.iter()
+        .flat_map(|e| e.params.iter().map(|ty| (ty, e.span, "Parameters")).chain(e.ret.iter().map(|ty| (ty, e.span, "Return values"))));
+    for (ty, span, what) in params.chain(extern_params) {
+        if !matches!(ty, Type::Size(_) | Type::Signed(_)) {
+            Log::new(ERR, span, format!("{} have to be numbers, found `{}`", what, ty),
+                "Pass data blocks and arrays by their address").push();
+        }
+    }
+    for e in &program.externs {
+        if program.functions.iter().any(|f| f.name == e.name) || layout.labels.contains_key(&e.name) {
+            Log::new(ERR, e.span, format!("`{}` is already defined", e.name), "External functions come from outside the program").push();
+        }
+    }
+
+    for block in &layout.blocks {
+        let func = &layout.functions[block.func];
+        let checker = Checker {
+            types: &types,
+            frame: Some(&func.frame),
+            program: Some(program),
+            func: func.def.map(|def| &program.functions[def]),
+            hint: Cell::new(8),
+        };
+        for stmt in &program.stmts[block.stmts.clone()] {
+            checker.stmt(stmt);
+        }
+    }
+
+    types
+}
+
+fn check_datablock(types: &Types, block: &DataBlock) {
+    let checker = Checker {
+        types,
+        frame: None,
+        program: None,
+        func: None,
+        hint: Cell::new(8),
+    };
+
+    for (i, field) in block.fields.iter().enumerate() {
+        if let Some(name) = &field.name {
+            if block.fields[..i].iter().any(|f| f.name.as_ref() == Some(name)) {
+                Log::new(ERR, field.span, format!("Field `{}` is already defined in `{}`", name, block.name), "").push();
+            }
+        }
+
+        if !types.check_type(&field.ty, field.span) {
+            continue;
+        }
+        if let Type::Array(_, None) = field.ty {
+            Log::new(ERR, field.span, "Array fields need a length", "Like [4, 16]").push();
+            continue;
+        }
+        if types.contains(&field.ty, &block.name, &mut Vec::new()) {
+            Log::new(ERR, field.span, format!("Data block `{}` contains itself", block.name), "").push();
+            continue;
+        }
+        if let Some(value) = &field.value {
+            checker.assign(&field.ty, value);
+        }
+    }
+}
+
+// `%a [4] = {1, 2, 3}` gives `a` a length of 3, other declarations have to fit into it
+fn resolve_lengths(program: &Program, layout: &mut Layout) {
+    for block in &layout.blocks {
+        let frame = &mut layout.functions[block.func].frame;
+        for stmt in &program.stmts[block.stmts.clone()] {
+            stmt.walk(&mut |stmt| {
+                let StmtKind::StackAssign(name, _, value) = &stmt.kind else { return };
+                let ExprKind::Array(elems) = &value.kind else { return };
+
+                if let Some(slot) = frame.slots.iter_mut().find(|s| s.name == *name) {
+                    if let Type::Array(_, len @ None) = &mut slot.ty {
+                        *len = Some(elems.len() as u64);
+                    }
+                }
+            });
+        }
+    }
+}
+
+struct Checker<'a> {
+    types: &'a Types,
+    frame: Option<&'a Frame>, // None within data blocks
+    program: Option<&'a Program>,
+    func: Option<&'a FnDef>,  // the `@` function being checked
+    hint: Cell<u64>,          // bytes a deref loads, see Types::width
+}
+
+impl Checker<'_> {
+    fn with_hint<T>(&self, bytes: u64, f: impl FnOnce() -> T) -> T {
+        let old = self.hint.replace(bytes);
+        let result = f();
+        self.hint.set(old);
+        result
+    }
+
+    fn width(&self, expr: &Expr) -> Option<u64> { self.frame.and_then(|f| self.types.width(f, expr)) }
+    fn stmt(&self, stmt: &Stmt) {
+        match &stmt.kind {
+            StmtKind::StackAssign(name, _, value) => {
+                let Some(slot) = self.frame.and_then(|f| f.slot(name)) else { return };
+                if let Type::Array(_, None) = slot.ty {
+                    Log::new(ERR, stmt.span, format!("Can't infer the length of array `{}`", name),
+                        "Initialize it with an array literal").push();
+                    return;
+                }
+                self.assign(&slot.ty, value);
+            },
+            StmtKind::RegAssign(_, var, value) => self.assign(&reg_type(*var), value),
+            // 'p : v is '[p] = v
+            StmtKind::Mutate(target, MutateOp::Store, value) => {
+                let deref = Expr {
+                    kind: ExprKind::Deref(Box::new(target.clone())),
+                    span: target.span,
+                };
+                self.mutate(&deref, MutateOp::Set, value, stmt.span);
+            },
+            StmtKind::Mutate(target, op, value) => self.mutate(target, *op, value, stmt.span),
+            // anything that isn't 0 is true
+            StmtKind::Cond(cond, then, otherwise) => {
+                self.with_hint(8, || self.number(cond));
+                self.stmt(then);
+                if let Some(otherwise) = otherwise {
+                    self.stmt(otherwise);
+                }
+            },
+            StmtKind::Ret(value) => self.ret(value.as_ref(), stmt.span),
+            StmtKind::Call(callee, args) => {
+                self.call(callee, args, stmt.span);
+            },
+            StmtKind::Push(value, ty) => match ty {
+                Type::Size(_) | Type::Signed(_) => self.assign(ty, value),
+                _ => {
+                    Log::new(ERR, stmt.span, format!("Only numbers can be pushed, found `{}`", ty),
+                        "Push the address of data blocks and arrays").push();
+                },
+            },
+            // as wide as the target, 8 bytes for a deref
+            StmtKind::Pop(target) | StmtKind::Peek(target) => {
+                let Some(ty) = self.with_hint(8, || self.target(target)) else { return };
+                if matches!(ty, Type::Named(_) | Type::Array(..)) {
+                    Log::new(ERR, target.span, format!("Can't pop into a `{}`", ty),
+                        "Pop into a number, register or field").push();
+                }
+            },
+            // only the low byte reaches the parent
+            StmtKind::End(code) => {
+                self.with_hint(4, || self.number(code));
+                if let ExprKind::Int(n) = code.kind {
+                    if !(0..=255).contains(&n) {
+                        Log::new(WARN, code.span, format!("Exit status {} is seen as {}", n, n & 0xff),
+                            "Only its low 8 bits reach the parent process").push();
+                    }
+                }
+            },
+            StmtKind::JmpTo(target) => {
+                self.with_hint(8, || self.number(target));
+            },
+            // the labels themselves are known to the layout
+            StmtKind::Switch(index, labels, _) => {
+                self.with_hint(8, || self.number(index));
+                if labels.is_empty() {
+                    Log::new(ERR, stmt.span, "A switch needs at least one label", "").push();
+                }
+            },
+            StmtKind::Label(_) | StmtKind::Jmp(_) => (),
+        }
+    }
+
+    fn ret(&self, value: Option<&Expr>, span: Span) {
+        let Some(f) = self.func else {
+            // a plain label hands back a full register
+            if let Some(value) = value {
+                self.assign(&Type::Size(8), value);
+            }
+            return;
+        };
+        match (&f.ret, value) {
+            (Some(ty), Some(value)) => self.assign(ty, value),
+            (Some(ty), None) => {
+                Log::new(ERR, span, "Missing return value", format!("`{}` returns {}", f.name, ty)).push();
+            },
+            (None, Some(value)) => {
+                Log::new(ERR, value.span, format!("`{}` doesn't return a value", f.name),
+                    "Declare a return type with `-> Type`").push();
+            },
+            (None, None) => (),
+        }
+    }
+
+    // `#name args`, which either has to match the parameters of an `@` function or call a plain
+    // label without arguments, `$name args` matching its `.extern`. Nothing is known about what
+    // `<- addr` or an undeclared `$name` call, their arguments and results are full registers.
+    // Gives back what the call leaves in r0, if anything
+    fn call(&self, callee: &Callee, args: &[Expr], span: Span) -> Option<Value> {
+        let program = self.program?;
+        let name = match callee {
+            Callee::Named(name) => name,
+            Callee::Sys(name) => return self.syscall(name, args, span),
+            Callee::Extern(name) if program.externs.iter().any(|e| e.name == *name) => {
+                return self.extern_call(program.externs.iter().find(|e| e.name == *name).unwrap(), args, span);
+            },
+            Callee::Extern(_) | Callee::Addr(_) => {
+                if let Callee::Extern(name) = callee {
+                    Log::new(WARN, span, format!("`${}` isn't declared", name),
+                        format!("Declare it with `.extern {}(...)`, or `.use libc` for the C library", name)).push();
+                }
+                let addr = callee.addr().map(|addr| self.with_hint(8, || self.number(addr)));
+                let args = args.iter().map(|arg| self.with_hint(8, || self.number(arg))).collect::<Vec<_>>();
+                return (addr.is_none_or(|a| a.is_some()) && args.iter().all(Option::is_some)).then_some(Value::Number(64, false));
+            },
+        };
+        let Some(f) = program.functions.iter().find(|f| f.name == *name) else {
+            let is_label = program.stmts.iter().any(|s| matches!(&s.kind, StmtKind::Label(l) if l == name));
+            match (is_label, args) {
+                (true, []) => return Some(Value::Number(64, false)),
+                (true, _) => Log::new(ERR, span, format!("`{}` is a label, it takes no arguments", name),
+                    "Define it with `@` to give it parameters").push(),
+                (false, _) => Log::new(ERR, span, format!("Unknown function `{}`", name), "").push(),
+            }
+            return None;
+        };
+
+        if args.len() != f.params.len() {
+            Log::new(ERR, span, format!("`{}` takes {} arguments, but {} were given", name, f.params.len(), args.len()),
+                format!("Defined as {}", f.signature())).push();
+            return None;
+        }
+        for (param, arg) in f.params.iter().zip(args) {
+            self.assign(&param.ty, arg);
+        }
+        f.ret.as_ref().map(|ty| self.types.value_of(ty))
+    }
+
+    // up to six arguments in registers, the result is a full register or -errno
+    fn syscall(&self, name: &str, args: &[Expr], span: Span) -> Option<Value> {
+        let args = args.iter().map(|arg| self.with_hint(8, || self.number(arg))).collect::<Vec<_>>();
+        if syscall(name).is_none() {
+            Log::new(ERR, span, format!("Unknown system call `{}`", name), "Expected an x86_64 Linux system call").push();
+            return None;
+        }
+        if args.len() > SYSCALL_ARGS.len() {
+            Log::new(ERR, span, format!("System calls take at most {} arguments, but {} were given", SYSCALL_ARGS.len(), args.len()), "").push();
+            return None;
+        }
+        args.iter().all(Option::is_some).then_some(Value::Number(64, true))
+    }
+
+    // arguments past the declared ones of a variadic function are full registers
+    fn extern_call(&self, ext: &ExternDef, args: &[Expr], span: Span) -> Option<Value> {
+        let count = match ext.variadic {
+            true => format!("at least {}", ext.params.len()),
+            false => ext.params.len().to_string(),
+        };
+        if args.len() < ext.params.len() || (args.len() > ext.params.len() && !ext.variadic) {
+            Log::new(ERR, span, format!("`{}` takes {} arguments, but {} were given", ext.name, count, args.len()),
+                format!("Declared as {}", ext.signature())).push();
+            return None;
+        }
+        for (i, arg) in args.iter().enumerate() {
+            match ext.params.get(i) {
+                Some(ty) => self.assign(ty, arg),
+                None => {
+                    self.with_hint(8, || self.number(arg));
+                },
+            }
+        }
+        ext.ret.as_ref().map(|ty| self.types.value_of(ty))
+    }
+
+    fn mutate(&self, target: &Expr, op: MutateOp, value: &Expr, span: Span) {
+        let hint = match op {
+            MutateOp::Shl | MutateOp::Shr => 8,
+            _ => self.width(value).unwrap_or(8),
+        };
+        let Some(ty) = self.with_hint(hint, || self.target(target)) else { return };
+        match (op, op.binop()) {
+            (MutateOp::Shl | MutateOp::Shr, _) => self.shift(&ty, value),
+            (_, None) => self.assign(&ty, value),
+            (_, Some(binop)) => {
+                let applied = Expr {
+                    kind: ExprKind::Binary(binop, Box::new(target.clone()), Box::new(value.clone())),
+                    span,
+                };
+                self.assign(&ty, &applied);
+            },
+        }
+    }
+
+    // the count of a shift is a number of any width, literals should stay below the target's bits
+    fn shift(&self, ty: &Type, count: &Expr) {
+        let bits = match self.types.value_of(ty) {
+            Value::Number(bits, _) => bits,
+            Value::Error => return,
+            Value::Aggregate(_) => {
+                Log::new(ERR, count.span, format!("Can't shift a `{}`", ty), "Only numbers can be shifted").push();
+                return;
+            },
+        };
+        if self.with_hint(8, || self.number(count)).is_none() {
+            return;
+        }
+        if let ExprKind::Int(n) = count.kind {
+            if n < 0 || n >= bits as i128 {
+                Log::new(WARN, count.span, format!("Shifting a {} bit value by {}", bits, n),
+                    "x86 only uses the low 5 or 6 bits of the count").push();
+            }
+        }
+    }
+
+    // checks that `value` can be stored into something of type `ty`
+    fn assign(&self, ty: &Type, value: &Expr) {
+        if let ExprKind::Fill(n) = value.kind {
+            let int = Expr {
+                kind: ExprKind::Int(n),
+                span: value.span,
+            };
+            return self.fill(ty, &int);
+        }
+
+        match (ty, &value.kind) {
+            (Type::Size(_) | Type::Signed(_) | Type::Bits(..), _) => {
+                let Some((bits, _)) = self.with_hint(self.types.size_of(ty), || self.number(value)) else { return };
+                let Value::Number(target, signed) = self.types.value_of(ty) else { unreachable!() };
+                match value.kind {
+                    ExprKind::Int(n) if !fits(n, target, signed) => {
+                        Log::new(WARN, value.span, format!("Implicit truncation, {} doesn't fit into {}", n, ty),
+                            format!("It is stored as {}", wrap(n, target, signed))).push();
+                    },
+                    ExprKind::Int(_) => (),
+                    _ if bits > target => {
+                        Log::new(WARN, value.span, format!("Implicit truncation from {} to {} bits", bits, target),
+                            format!("The value is stored as {}", ty)).push();
+                    },
+                    _ => (),
+                }
+            },
+
+            (Type::Named(name), ExprKind::Array(elems)) => {
+                let Some(block) = self.types.block(name) else { return };
+                if elems.len() != block.fields.len() {
+                    Log::new(ERR, value.span,
+                        format!("`{}` has {} fields, but {} values were given", name, block.fields.len(), elems.len()), "").push();
+                    return;
+                }
+                for (field, elem) in block.fields.iter().zip(elems) {
+                    self.assign(&field.ty, elem);
+                }
+            },
+
+            (Type::Array(elem_ty, len), ExprKind::Array(elems)) => {
+                if let Some(len) = len.filter(|&len| elems.len() as u64 > len) {
+                    Log::new(ERR, value.span, format!("Too many elements, the array holds {} but {} were given", len, elems.len()), "").push();
+                    return;
+                }
+                for elem in elems {
+                    self.assign(elem_ty, elem);
+                }
+            },
+
+            (Type::Named(_) | Type::Array(..), _) => match self.value(value) {
+                _ if matches!(self.types.value_of(ty), Value::Error) => (),
+                Some(Value::Aggregate(found)) if found == *ty => (),
+                Some(Value::Error) | None => (),
+                Some(found) => {
+                    Log::new(ERR, value.span, format!("Mismatched types, expected `{}` but found {}", ty, describe(&found)), "").push();
+                },
+            },
+        }
+    }
+
+    // a fill writes its value into every number within `ty`
+    fn fill(&self, ty: &Type, int: &Expr) {
+        match ty {
+            Type::Named(name) => {
+                for field in self.types.block(name).map_or(&[][..], |b| &b.fields) {
+                    self.fill(&field.ty, int);
+                }
+            },
+            Type::Array(elem, _) => self.fill(elem, int),
+            ty => self.assign(ty, int),
+        }
+    }
+
+    // the type of something a mutation writes to
+    fn target(&self, expr: &Expr) -> Option<Type> {
+        let frame = self.frame?;
+        match &expr.kind {
+            ExprKind::Ident(name) => match (frame.reg(name), frame.slot(name)) {
+                (Some(var), _) => Some(reg_type(var)),
+                (None, Some(slot)) => Some(slot.ty.clone()),
+                (None, None) => {
+                    Log::new(ERR, expr.span, format!("Unknown variable `{}`", name), "").push();
+                    None
+                },
+            },
+            ExprKind::Register(reg) => Some(Type::Size(reg_bytes(reg.size))),
+            ExprKind::Deref(_) => {
+                self.value(expr)?;
+                Some(Type::Size(self.hint.get() as u8))
+            },
+            ExprKind::ArrIndex(name, _) | ExprKind::StructIndex(name, _) => {
+                self.value(expr)?;
+                match (self.aggregate(name, expr.span)?, &expr.kind) {
+                    (Type::Array(elem, _), _) => Some((**elem).clone()),
+                    (Type::Named(block), ExprKind::StructIndex(_, field)) => {
+                        self.types.block(block).and_then(|b| self.types.field(b, field)).map(|f| f.ty.clone())
+                    },
+                    _ => None,
+                }
+            },
+            _ => {
+                Log::new(ERR, expr.span, "Can't mutate a temporary value", "Expected a variable, register or field").push();
+                None
+            },
+        }
+    }
+
+    // a value that has to be a number
+    fn number(&self, expr: &Expr) -> Option<(u32, bool)> {
+        match self.value(expr)? {
+            Value::Number(bits, signed) => Some((bits, signed)),
+            Value::Error => None,
+            found => {
+                Log::new(ERR, expr.span, format!("Expected a number, found {}", describe(&found)), "").push();
+                None
+            },
+        }
+    }
+
+    fn value(&self, expr: &Expr) -> Option<Value> {
+        match &expr.kind {
+            ExprKind::Int(n) => Some(Value::Number(bits_needed(*n), *n < 0)),
+
+            // anywhere else than in an initializer, an array literal is put into static data as
+            // 8 byte elements and stands for its address
+            ExprKind::Array(elems) => match elems.iter().find(|e| !matches!(e.kind, ExprKind::Int(_) | ExprKind::LabelAddr(_))) {
+                None => {
+                    for elem in elems {
+                        self.value(elem);
+                    }
+                    Some(Value::Number(64, false))
+                },
+                Some(elem) => {
+                    Log::new(ERR, elem.span, "Array literals in static data can only hold integers and label addresses",
+                        "Use a stack variable to build it at runtime").push();
+                    None
+                },
+            },
+
+            // the address of its bytes
+            ExprKind::Str(_) => Some(Value::Number(64, false)),
+
+            ExprKind::LabelAddr(label) => {
+                let program = self.program?;
+                if !program.stmts.iter().any(|s| matches!(&s.kind, StmtKind::Label(l) if l == label)) {
+                    Log::new(ERR, expr.span, format!("Unknown label `{}`", label), "").push();
+                    return None;
+                }
+                Some(Value::Number(64, false))
+            },
+
+            ExprKind::Fill(_) => {
+                Log::new(ERR, expr.span, "Unexpected fill", "Fills can only initialize arrays and data blocks").push();
+                None
+            },
+
+            ExprKind::Ident(name) => {
+                let frame = self.frame?;
+                if let Some(var) = frame.reg(name) {
+                    return Some(self.types.value_of(&reg_type(var)));
+                }
+                match frame.slot(name) {
+                    Some(slot) => Some(self.types.value_of(&slot.ty)),
+                    None => {
+                        Log::new(ERR, expr.span, format!("Unknown variable `{}`", name), "").push();
+                        None
+                    },
+                }
+            },
+
+            ExprKind::Register(reg) => Some(Value::Number(reg_bytes(reg.size) as u32 * 8, false)),
+
+            ExprKind::ArrIndex(name, index) => {
+                let slot_ty = self.aggregate(name, expr.span)?;
+                let index_bits = self.with_hint(8, || self.number(index));
+                let Type::Array(elem, len) = slot_ty else {
+                    Log::new(ERR, expr.span, format!("`{}` is not an array", name), format!("It is `{}`", slot_ty)).push();
+                    return None;
+                };
+                if let (ExprKind::Int(i), Some(len)) = (&index.kind, len) {
+                    if *i < 0 || *i as u64 >= *len {
+                        Log::new(ERR, index.span, format!("Index {} is out of bounds", i), format!("`{}` has {} elements", name, len)).push();
+                    }
+                }
+                index_bits?;
+                Some(self.types.value_of(elem))
+            },
+
+            ExprKind::StructIndex(name, field) => {
+                let slot_ty = self.aggregate(name, expr.span)?;
+                let Type::Named(block) = slot_ty else {
+                    Log::new(ERR, expr.span, format!("`{}` is not a data block", name), format!("It is `{}`", slot_ty)).push();
+                    return None;
+                };
+                let block = self.types.block(block)?;
+                match self.types.field(block, field) {
+                    Some(field) => Some(self.types.value_of(&field.ty)),
+                    None => {
+                        Log::new(ERR, expr.span, format!("`{}` has no field `{}`", block.name, field), "").push();
+                        None
+                    },
+                }
+            },
+
+            ExprKind::SizeOf(ty) => {
+                if !self.types.check_type(ty, expr.span) {
+                    return None;
+                }
+                if let Type::Array(_, None) = ty {
+                    Log::new(ERR, expr.span, "An array type has no size on its own", "Take the size of a data block instead").push();
+                    return None;
+                }
+                Some(Value::Number(bits_needed(self.types.size_of(ty) as i128), false))
+            },
+
+            ExprKind::OffsetOf(name, field) => {
+                let Some(block) = self.types.block(name) else {
+                    Log::new(ERR, expr.span, format!("Unknown data block `{}`", name), "").push();
+                    return None;
+                };
+                let Some(i) = self.types.field_index(block, field) else {
+                    Log::new(ERR, expr.span, format!("`{}` has no field `{}`", name, field), "").push();
+                    return None;
+                };
+                Some(Value::Number(bits_needed(self.types.offsets(block)[i] as i128), false))
+            },
+
+            ExprKind::Deref(addr) => {
+                let (bits, _) = self.with_hint(8, || self.number(addr))?;
+                if bits < 64 && !matches!(addr.kind, ExprKind::Int(_)) {
+                    Log::new(WARN, addr.span, format!("Dereferencing a {} bit number", bits), "Addresses are 64 bits wide").push();
+                }
+                Some(Value::Number(self.hint.get() as u32 * 8, false))
+            },
+
+            ExprKind::Not(inner) => self.number(inner).map(|(bits, signed)| Value::Number(bits, signed)),
+
+            ExprKind::Binary(op, lhs_expr, rhs_expr) => {
+                let hint = self.hint.get();
+                let lhs = self.with_hint(self.width(rhs_expr).unwrap_or(hint), || self.number(lhs_expr));
+                let rhs = self.with_hint(self.width(lhs_expr).unwrap_or(hint), || self.number(rhs_expr));
+                let ((lhs, lhs_signed), (rhs, rhs_signed)) = (lhs?, rhs?);
+
+                // literals take on the sign of the other side, only variables can disagree
+                let literal = matches!(lhs_expr.kind, ExprKind::Int(_)) || matches!(rhs_expr.kind, ExprKind::Int(_));
+                if matches!(op, BinOp::Div | BinOp::Mod) && matches!(rhs_expr.kind, ExprKind::Int(0)) {
+                    Log::new(ERR, rhs_expr.span, "Division by zero", "").push();
+                }
+
+                let sign_matters = op.is_comparison() || matches!(op, BinOp::Div | BinOp::Mod);
+                if sign_matters && !literal && lhs_signed != rhs_signed {
+                    Log::new(WARN, expr.span, "Mixing signed and unsigned operands", "The operation is done signed").push();
+                }
+
+                match op {
+                    op if op.is_comparison() || op.is_logical() => Some(Value::Number(1, false)),
+                    _ => Some(Value::Number(lhs.max(rhs), lhs_signed || rhs_signed)),
+                }
+            },
+
+            ExprKind::Call(callee, args) => {
+                if self.frame.is_none() {
+                    Log::new(ERR, expr.span, "Calls can't initialize a data block", "Its values are known when compiling").push();
+                    return None;
+                }
+                let value = self.call(callee, args, expr.span);
+                let program = self.program?;
+                let (name, signature) = match callee {
+                    Callee::Named(name) => (name, program.functions.iter().find(|f| f.name == *name && f.ret.is_none())
+                        .map(|f| format!("Defined as {}", f.signature()))),
+                    Callee::Extern(name) => (name, program.externs.iter().find(|e| e.name == *name && e.ret.is_none())
+                        .map(|e| format!("Declared as {}", e.signature()))),
+                    Callee::Sys(_) | Callee::Addr(_) => return value,
+                };
+                if let (Some(signature), None) = (signature, &value) {
+                    Log::new(ERR, expr.span, format!("`{}` doesn't return a value", name), signature).push();
+                }
+                value
+            },
+        }
+    }
+
+    // the type of a stack variable indexed with `.` or `#`
+    fn aggregate(&self, name: &str, span: Span) -> Option<&Type> {
+        let frame = self.frame?;
+        match frame.slot(name) {
+            Some(slot) => Some(&slot.ty),
+            None => {
+                let notes = match frame.reg(name) {
+                    Some(_) => "Registers can't be indexed",
+                    None => "",
+                };
+                Log::new(ERR, span, format!("Unknown variable `{}`", name), notes).push();
+                None
+            },
+        }
+    }
+}
+
+fn describe(value: &Value) -> String {
+    match value {
+        Value::Number(bits, true) => format!("a {} bit signed number", bits),
+        Value::Number(bits, false) => format!("a {} bit number", bits),
+        Value::Aggregate(ty) => format!("`{}`", ty),
+        Value::Error => "an unknown type".to_string(),
+    }
+}
+
+// bits needed for the magnitude of a literal, negative ones count their sign bit too
+fn bits_needed(n: i128) -> u32 {
+    match n {
+        n if n < 0 => 129 - n.leading_ones(),
+        n => (128 - n.leading_zeros()).max(1),
+    }
+}
+
+fn fits(n: i128, bits: u32, signed: bool) -> bool {
+    match signed {
+        true => n >= -(1 << (bits - 1)) && n < 1 << (bits - 1),
+        false => n >= 0 && n < 1 << bits,
+    }
+}
+
+// what `n` turns into once stored into `bits` bits
+fn wrap(n: i128, bits: u32, signed: bool) -> i128 {
+    let n = n & ((1 << bits) - 1);
+    match signed && n >= 1 << (bits - 1) {
+        true => n - (1 << bits),
+        false => n,
+    }
+}
+
+pub fn reg_type(var: RegVar) -> Type {
+    match var.signed {
+        true => Type::Signed(reg_bytes(var.reg.size)),
+        false => Type::Size(reg_bytes(var.reg.size)),
+    }
+}
+
+// the smallest SIZE that holds bits 0 to hi
+fn storage_bytes(hi: u8) -> u64 {
+    match hi {
+        0..=8 => 1,
+        9..=16 => 2,
+        17..=32 => 4,
+        _ => 8,
+    }
+}
+
+pub fn reg_bytes(size: RegSize) -> u8 {
+    match size {
+        RegSize::ByteLow | RegSize::ByteHigh => 1,
+        RegSize::Word => 2,
+        RegSize::DWord => 4,
+        RegSize::QWord => 8,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    // the data blocks of `source`, the way the checker keeps them
+    fn types(source: &str) -> Types {
+        let program = Parser::new(Lexer::new(source.to_string(), "test.shd").lex()).parse();
+        Types {
+            blocks: program.data.into_iter().map(|b| (b.name.clone(), b)).collect(),
+            returns: HashMap::new(),
+        }
+    }
+
+    fn sizeof(types: &Types, block: &str) -> u64 { types.size_of(&Type::Named(block.to_string())) }
+
+    fn offsetof(types: &Types, block: &str, field: &str) -> u64 {
+        let block = types.block(block).unwrap();
+        types.offsets(block)[types.field_index(block, &FieldRef::Name(field.to_string())).unwrap()]
+    }
+
+    const FLAGS: &str = "
+Flags: {
+    a: 0:1
+    b: 1:4
+    c: 8:16
+}
+
+|packed|
+Packed: {
+    a: 0:1
+    b: 1:4
+    c: 8:16
+}
+";
+
+    // the example on `Types::offsets`
+    #[test]
+    fn offsets_example() {
+        let types = types(FLAGS);
+        assert_eq!(types.offsets(types.block("Flags").unwrap()), [0, 0, 2]);
+        assert_eq!(types.offsets(types.block("Packed").unwrap()), [0, 0, 1]);
+    }
+
+    #[test]
+    fn bit_fields_share_units() {
+        let types = types(FLAGS);
+        assert_eq!(offsetof(&types, "Flags", "a"), 0);
+        assert_eq!(offsetof(&types, "Flags", "b"), 0);
+        assert_eq!(offsetof(&types, "Flags", "c"), 2);
+        assert_eq!(sizeof(&types, "Flags"), 4);
+
+        assert_eq!(offsetof(&types, "Packed", "b"), 0);
+        assert_eq!(offsetof(&types, "Packed", "c"), 1);
+        assert_eq!(sizeof(&types, "Packed"), 3);
+    }
+
+    #[test]
+    fn fields_are_aligned_unless_packed() {
+        let types = types("
+Point: {
+    tag: 1
+    x: 8
+    y: s4
+}
+
+|packed|
+Wire: {
+    tag: 1
+    x: 8
+    y: s4
+}
+
+Outer: {
+    flag: 1
+    p: Point
+}
+");
+        assert_eq!(offsetof(&types, "Point", "x"), 8);
+        assert_eq!(offsetof(&types, "Point", "y"), 16);
+        assert_eq!(sizeof(&types, "Point"), 24);
+
+        assert_eq!(offsetof(&types, "Wire", "x"), 1);
+        assert_eq!(offsetof(&types, "Wire", "y"), 9);
+        assert_eq!(sizeof(&types, "Wire"), 13);
+
+        assert_eq!(offsetof(&types, "Outer", "p"), 8);
+        assert_eq!(sizeof(&types, "Outer"), 32);
+    }
+
+    // a range too wide for the unit before it starts a unit of its own
+    #[test]
+    fn bit_ranges_crossing_a_unit_start_a_new_one() {
+        let types = types("
+Crossing: {
+    lo: 0:6
+    mid: 6:10
+    hi: 10:16
+}
+
+|packed|
+PackedCrossing: {
+    lo: 0:6
+    mid: 6:10
+    hi: 10:16
+}
+");
+        assert_eq!(offsetof(&types, "Crossing", "lo"), 0);
+        assert_eq!(offsetof(&types, "Crossing", "mid"), 2);
+        assert_eq!(offsetof(&types, "Crossing", "hi"), 2);
+        assert_eq!(sizeof(&types, "Crossing"), 4);
+
+        assert_eq!(offsetof(&types, "PackedCrossing", "mid"), 1);
+        assert_eq!(offsetof(&types, "PackedCrossing", "hi"), 1);
+        assert_eq!(sizeof(&types, "PackedCrossing"), 3);
+    }
+}
@@ -0,0 +1,313 @@
+use std::fmt::{self, Display, Formatter};
+
+use crate::ast::RegSize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Reg {
+    Rax,
+    Rbx,
+    Rcx,
+    Rdx,
+    Rsi,
+    Rdi,
+    Rbp,
+    Rsp,
+    R8,
+    R9,
+    R10,
+    R11,
+    R12,
+    R13,
+    R14,
+    R15,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mem {
+    pub size: u8, // 1 | 2 | 4 | 8
+    pub base: Option<Reg>,
+    pub index: Option<(Reg, u8)>,
+    pub disp: i64,
+    pub label: Option<String>, // rip relative
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Reg(Reg, RegSize),
+    Mem(Mem),
+    Imm(i64),
+    Label(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Cond {
+    E,
+    Ne,
+    A,
+    Ae,
+    B,
+    Be,
+    G,
+    Ge,
+    L,
+    Le,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instr {
+    Label(String),
+
+    Mov(Operand, Operand),
+    Movabs(Operand, i64),
+    Movzx(Operand, Operand),
+    Movsx(Operand, Operand),
+    Movsxd(Operand, Operand),
+    Lea(Operand, Operand),
+
+    Add(Operand, Operand),
+    Sub(Operand, Operand),
+    Inc(Operand),
+    Dec(Operand),
+    Imul(Operand, Operand),
+    Div(Operand),
+    Idiv(Operand),
+    Cqo,
+    And(Operand, Operand),
+    Or(Operand, Operand),
+    Xor(Operand, Operand),
+    Not(Operand),
+    Shl(Operand, Operand),
+    Shr(Operand, Operand),
+    Sar(Operand, Operand),
+
+    Cmp(Operand, Operand),
+    Test(Operand, Operand),
+    Set(Cond, Operand),
+
+    Jmp(Operand),
+    J(Cond, Operand),
+    Call(Operand),
+    Syscall,
+    Push(Operand),
+    Pop(Operand),
+    Leave,
+    Ret,
+    Ud2,
+    RepStos(u8), // the element size in bytes
+}
+
+impl Reg {
+    pub fn sized(self, size: RegSize) -> Operand { Operand::Reg(self, size) }
+
+    pub fn q(self) -> Operand { Operand::Reg(self, RegSize::QWord) }
+
+    fn name(self, size: RegSize) -> &'static str {
+        const NAMES: [[&str; 5]; 16] = [
+            ["al", "ah", "ax", "eax", "rax"],
+            ["bl", "bh", "bx", "ebx", "rbx"],
+            ["cl", "ch", "cx", "ecx", "rcx"],
+            ["dl", "dh", "dx", "edx", "rdx"],
+            ["sil", "", "si", "esi", "rsi"],
+            ["dil", "", "di", "edi", "rdi"],
+            ["bpl", "", "bp", "ebp", "rbp"],
+            ["spl", "", "sp", "esp", "rsp"],
+            ["r8b", "", "r8w", "r8d", "r8"],
+            ["r9b", "", "r9w", "r9d", "r9"],
+            ["r10b", "", "r10w", "r10d", "r10"],
+            ["r11b", "", "r11w", "r11d", "r11"],
+            ["r12b", "", "r12w", "r12d", "r12"],
+            ["r13b", "", "r13w", "r13d", "r13"],
+            ["r14b", "", "r14w", "r14d", "r14"],
+            ["r15b", "", "r15w", "r15d", "r15"],
+        ];
+        let column = match size {
+            RegSize::ByteLow => 0,
+            RegSize::ByteHigh => 1,
+            RegSize::Word => 2,
+            RegSize::DWord => 3,
+            RegSize::QWord => 4,
+        };
+        NAMES[self as usize][column]
+    }
+}
+
+impl Mem {
+    pub fn base(size: u8, base: Reg, disp: i64) -> Mem {
+        Mem {
+            size,
+            base: Some(base),
+            index: None,
+            disp,
+            label: None,
+        }
+    }
+
+    pub fn rip(size: u8, label: String) -> Mem {
+        Mem {
+            size,
+            base: None,
+            index: None,
+            disp: 0,
+            label: Some(label),
+        }
+    }
+}
+
+// x86_64 Linux system calls by name, `*write 1, msg, len` makes one
+const SYSCALLS: [(&str, u32); 97] = [
+    ("read", 0), ("write", 1), ("open", 2), ("close", 3), ("stat", 4), ("fstat", 5), ("lstat", 6),
+    ("poll", 7), ("lseek", 8), ("mmap", 9), ("mprotect", 10), ("munmap", 11), ("brk", 12),
+    ("rt_sigaction", 13), ("rt_sigprocmask", 14), ("rt_sigreturn", 15), ("ioctl", 16),
+    ("pread64", 17), ("pwrite64", 18), ("readv", 19), ("writev", 20), ("access", 21), ("pipe", 22),
+    ("select", 23), ("sched_yield", 24), ("mremap", 25), ("msync", 26), ("mincore", 27),
+    ("madvise", 28), ("dup", 32), ("dup2", 33), ("pause", 34), ("nanosleep", 35), ("getpid", 39),
+    ("sendfile", 40), ("socket", 41), ("connect", 42), ("accept", 43), ("sendto", 44),
+    ("recvfrom", 45), ("sendmsg", 46), ("recvmsg", 47), ("shutdown", 48), ("bind", 49),
+    ("listen", 50), ("clone", 56), ("fork", 57), ("vfork", 58), ("execve", 59), ("exit", 60),
+    ("wait4", 61), ("kill", 62), ("uname", 63), ("fcntl", 72), ("flock", 73), ("fsync", 74),
+    ("fdatasync", 75), ("truncate", 76), ("ftruncate", 77), ("getdents", 78), ("getcwd", 79),
+    ("chdir", 80), ("fchdir", 81), ("rename", 82), ("mkdir", 83), ("rmdir", 84), ("creat", 85),
+    ("link", 86), ("unlink", 87), ("symlink", 88), ("readlink", 89), ("chmod", 90), ("fchmod", 91),
+    ("chown", 92), ("umask", 95), ("gettimeofday", 96), ("getrlimit", 97), ("getuid", 102),
+    ("getgid", 104), ("geteuid", 107), ("getegid", 108), ("getppid", 110), ("getpgrp", 111),
+    ("setsid", 112), ("arch_prctl", 158), ("gettid", 186), ("time", 201), ("futex", 202),
+    ("getdents64", 217), ("clock_gettime", 228), ("clock_nanosleep", 230), ("exit_group", 231),
+    ("openat", 257), ("mkdirat", 258), ("unlinkat", 263), ("pipe2", 293), ("getrandom", 318),
+];
+
+// where the arguments of a system call go, the kernel overwrites rcx and r11 besides rax
+pub const SYSCALL_ARGS: [Reg; 6] = [Reg::Rdi, Reg::Rsi, Reg::Rdx, Reg::R10, Reg::R8, Reg::R9];
+
+pub fn syscall(name: &str) -> Option<u32> { SYSCALLS.iter().find(|(n, _)| *n == name).map(|(_, num)| *num) }
+
+pub fn size_of_bytes(bytes: u8) -> RegSize {
+    match bytes {
+        1 => RegSize::ByteLow,
+        2 => RegSize::Word,
+        4 => RegSize::DWord,
+        _ => RegSize::QWord,
+    }
+}
+
+impl Display for Reg {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result { write!(f, "{}", self.name(RegSize::QWord)) }
+}
+
+impl Display for Mem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let ptr = match self.size {
+            1 => "byte",
+            2 => "word",
+            4 => "dword",
+            _ => "qword",
+        };
+        write!(f, "{} ptr [", ptr)?;
+
+        let mut first = true;
+        if let Some(label) = &self.label {
+            write!(f, "rip + {}", label)?;
+            first = false;
+        }
+        if let Some(base) = self.base {
+            write!(f, "{}", base)?;
+            first = false;
+        }
+        if let Some((index, scale)) = self.index {
+            if !first {
+                write!(f, " + ")?;
+            }
+            write!(f, "{}*{}", index, scale)?;
+            first = false;
+        }
+        match self.disp {
+            0 if !first => (),
+            d if first => write!(f, "{}", d)?,
+            d if d < 0 => write!(f, " - {}", d.unsigned_abs())?,
+            d => write!(f, " + {}", d)?,
+        }
+        write!(f, "]")
+    }
+}
+
+impl Display for Operand {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Reg(reg, size) => write!(f, "{}", reg.name(*size)),
+            Operand::Mem(mem) => write!(f, "{}", mem),
+            Operand::Imm(imm) => write!(f, "{}", imm),
+            Operand::Label(label) => write!(f, "{}", label),
+        }
+    }
+}
+
+impl Display for Cond {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Cond::E => "e",
+            Cond::Ne => "ne",
+            Cond::A => "a",
+            Cond::Ae => "ae",
+            Cond::B => "b",
+            Cond::Be => "be",
+            Cond::G => "g",
+            Cond::Ge => "ge",
+            Cond::L => "l",
+            Cond::Le => "le",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl Display for Instr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Instr::Label(name) => write!(f, "{}:", name),
+
+            Instr::Mov(a, b) => write!(f, "    mov {}, {}", a, b),
+            Instr::Movabs(a, imm) => write!(f, "    movabs {}, {}", a, imm),
+            Instr::Movzx(a, b) => write!(f, "    movzx {}, {}", a, b),
+            Instr::Movsx(a, b) => write!(f, "    movsx {}, {}", a, b),
+            Instr::Movsxd(a, b) => write!(f, "    movsxd {}, {}", a, b),
+            Instr::Lea(a, b) => write!(f, "    lea {}, {}", a, b),
+
+            Instr::Add(a, b) => write!(f, "    add {}, {}", a, b),
+            Instr::Sub(a, b) => write!(f, "    sub {}, {}", a, b),
+            Instr::Inc(a) => write!(f, "    inc {}", a),
+            Instr::Dec(a) => write!(f, "    dec {}", a),
+            Instr::Imul(a, b) => write!(f, "    imul {}, {}", a, b),
+            Instr::Div(a) => write!(f, "    div {}", a),
+            Instr::Idiv(a) => write!(f, "    idiv {}", a),
+            Instr::Cqo => write!(f, "    cqo"),
+            Instr::And(a, b) => write!(f, "    and {}, {}", a, b),
+            Instr::Or(a, b) => write!(f, "    or {}, {}", a, b),
+            Instr::Xor(a, b) => write!(f, "    xor {}, {}", a, b),
+            Instr::Not(a) => write!(f, "    not {}", a),
+            Instr::Shl(a, b) => write!(f, "    shl {}, {}", a, b),
+            Instr::Shr(a, b) => write!(f, "    shr {}, {}", a, b),
+            Instr::Sar(a, b) => write!(f, "    sar {}, {}", a, b),
+
+            Instr::Cmp(a, b) => write!(f, "    cmp {}, {}", a, b),
+            Instr::Test(a, b) => write!(f, "    test {}, {}", a, b),
+            Instr::Set(cond, a) => write!(f, "    set{} {}", cond, a),
+
+            Instr::Jmp(a) => write!(f, "    jmp {}", a),
+            Instr::J(cond, a) => write!(f, "    j{} {}", cond, a),
+            Instr::Call(a) => write!(f, "    call {}", a),
+            Instr::Syscall => write!(f, "    syscall"),
+            Instr::Push(a) => write!(f, "    push {}", a),
+            Instr::Pop(a) => write!(f, "    pop {}", a),
+            Instr::Leave => write!(f, "    leave"),
+            Instr::Ret => write!(f, "    ret"),
+            Instr::Ud2 => write!(f, "    ud2"),
+            Instr::RepStos(size) => {
+                let suffix = match size {
+                    1 => "b",
+                    2 => "w",
+                    4 => "d",
+                    _ => "q",
+                };
+                write!(f, "    rep stos{}", suffix)
+            },
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use std::path::PathBuf;
+use std::process::Command;
+
+// What the compiler reports for small programs, one `[LEVEL]: message` per diagnostic with the
+// colours taken out. Notes are checked in the whole output
+
+fn temp(name: &str) -> PathBuf { std::env::temp_dir().join(format!("shard-{}-{}", std::process::id(), name)) }
+
+// the output of compiling `source`, which has to fail exactly when `fails`
+fn compile(name: &str, source: &str, fails: bool) -> String {
+    let (src, asm) = (temp(&format!("{}.shd", name)), temp(&format!("{}.s", name)));
+    std::fs::write(&src, source).unwrap();
+    let out = Command::new(env!("CARGO_BIN_EXE_shard"))
+        .arg(&src)
+        .args(["-A", "--log=warn", "-o"])
+        .arg(&asm)
+        .output()
+        .unwrap();
+    std::fs::remove_file(&src).unwrap();
+    let _ = std::fs::remove_file(&asm);
+
+    let mut text = String::from_utf8_lossy(&out.stdout).into_owned();
+    while let Some(start) = text.find('\x1b') {
+        let end = start + text[start..].find('m').unwrap();
+        text.replace_range(start..=end, "");
+    }
+    assert_eq!(!out.status.success(), fails, "{}:\n{}", name, text);
+    text
+}
+
+fn messages(output: &str) -> Vec<&str> {
+    output.lines()
+        .filter(|l| (l.starts_with("[ERR]") || l.starts_with("[WARN]")) && !l.contains("Could Not Compile") && !l.contains("Warnings Emmited"))
+        .collect()
+}
+
+#[test]
+fn unknown_types_are_reported_once() {
+    let out = compile("unknown", "
+Holder: {
+    n: Nope
+}
+
+main:
+    %x Nope = 5
+    %p Holder = {1}
+    %q Holder = {2}
+    'q = x
+    'x + 1
+    ;r r1 = (x + 1)
+    'r = p#n
+    ret 0
+", true);
+    assert_eq!(messages(&out), ["[ERR]: Unknown type `Nope`", "[ERR]: Unknown type `Nope`"]);
+}
+
+// nothing installs lib/, the compiler carries its own copy
+#[test]
+fn libc_is_built_in() {
+    let out = compile("builtin-libc", "
+.use libc
+
+main:
+    $puts \"Hello World\"
+    ret
+", false);
+    assert_eq!(messages(&out), Vec::<&str>::new());
+
+    let out = compile("missing-lib", ".use nolib\n\nmain:\n    ret\n", true);
+    assert!(messages(&out)[0].starts_with("[ERR]: Can't read `"), "{}", out);
+}
+
+#[test]
+fn jumping_to_a_variable_suggests_parentheses() {
+    let out = compile("jmp-var", "
+main:
+    %t 8 = &done
+    jmp t
+done:
+    ret 0
+", true);
+    assert_eq!(messages(&out), ["[ERR]: Unknown label `t`"]);
+    assert!(out.contains("use `jmp (t)` to jump to an address held in it"), "{}", out);
+
+    let out = compile("jmp-missing", "main:\n    jmp nowhere\n", true);
+    assert!(!out.contains("jmp (nowhere)"), "{}", out);
+    compile("jmp-var-parens", "main:\n    %t 8 = &done\n    jmp (t)\ndone:\n    ret 0\n", false);
+}
+
+// a store reads the variable it goes through, like a deref or an index does
+#[test]
+fn store_targets_are_read() {
+    let out = compile("store", "
+main:
+    'r1 = 4096
+    %q 8 = r1
+    'q : 22
+    %p 8 = r1
+    '[p] = 4
+    %arr [8, 2] = {0, 0}
+    %i 8 = 1
+    'arr.(i) = 3
+    'arr.0 = arr.1
+    %w 8 = 0
+    'w = 5
+    'w + 1
+    ret 0
+", false);
+    assert_eq!(messages(&out), ["[WARN]: `w` is assigned but never read"]);
+}
+
+// a register passed from one function into another has no frame of its own to be spilled into
+#[test]
+fn shared_registers_are_not_spilled() {
+    let regs = 1..20;
+    let sum = regs.clone().map(|r| format!("r{}", r)).reduce(|sum, r| format!("({} + {})", sum, r)).unwrap();
+    let mut source = format!("@sum -> 8 {{\n    ret {}\n}}\n\nmain:\n", sum);
+    source += &regs.map(|r| format!("    'r{} = {}\n", r, r)).collect::<String>();
+    source += "    #sum\n    ret r0\n";
+
+    let out = compile("shared", &source, true);
+    let messages = messages(&out);
+    assert_eq!(messages.len(), 1, "{}", out);
+    assert!(messages[0].starts_with("[ERR]: Too many registers live at once, r"), "{}", out);
+    assert!(out.contains("Only registers used within a single function can live in its stack frame"), "{}", out);
+}
@@ -0,0 +1,60 @@
+use std::path::PathBuf;
+use std::process::Command;
+
+// More registers live at once than there are homes for them, which spills some into the
+// frame. The programs are assembled and linked with gcc
+
+fn temp(name: &str) -> PathBuf { std::env::temp_dir().join(format!("shard-{}-{}", std::process::id(), name)) }
+
+// what the program prints, compiled with each set of flags
+fn run(name: &str, source: &str, flags: &[&str]) -> String {
+    let (src, asm, bin) = (temp(&format!("{}.shd", name)), temp(&format!("{}.s", name)), temp(name));
+    std::fs::write(&src, source).unwrap();
+    let status = Command::new(env!("CARGO_BIN_EXE_shard")).arg(&src).args(["-A", "-o"]).arg(&asm).args(flags).status().unwrap();
+    assert!(status.success(), "{} {:?} didn't compile", name, flags);
+    let status = Command::new("gcc").arg("-no-pie").arg(&asm).arg("-o").arg(&bin).status().unwrap();
+    assert!(status.success(), "gcc couldn't link {} {:?}", name, flags);
+    let out = Command::new(&bin).output().unwrap();
+    for file in [src, asm, bin] {
+        std::fs::remove_file(file).unwrap();
+    }
+    assert!(out.status.success(), "{} {:?} exited with {}", name, flags, out.status);
+    String::from_utf8_lossy(&out.stdout).into_owned()
+}
+
+const FLAGS: [&[&str]; 4] = [&["-O0"], &["-O2"], &["-O0", "-F"], &["-O2", "-F"]];
+
+// `(v0 + v1) + ...` over fourteen names with `prefix`
+fn sum(prefix: &str) -> String {
+    let mut terms = (0..14).map(|k| format!("{}{}", prefix, k)).collect::<Vec<_>>();
+    while terms.len() > 1 {
+        terms = terms.chunks(2).map(|pair| pair.join(" + ")).map(|s| if s.contains(' ') { format!("({})", s) } else { s }).collect();
+    }
+    terms.remove(0)
+}
+
+#[test]
+fn fourteen_registers_in_one_function() {
+    let mut source = String::from(".use libc\n\nmain:\n    ;i r20 = 0\n");
+    source += &(0..14).map(|k| format!("    ;v{} r{} = 0\n", k, k + 1)).collect::<String>();
+    source += "loop:\n";
+    source += &(0..14).map(|k| format!("    'v{} + {}\n", k, k + 1)).collect::<String>();
+    source += &format!("    'i ++\n    (i < 10) => jmp loop\n    ;s r21 = {}\n    $printf \"%ld\\n\", s\n    ret 0\n", sum("v"));
+
+    for flags in FLAGS {
+        assert_eq!(run("spill-loop", &source, flags), "1050\n", "{:?}", flags);
+    }
+}
+
+// every call keeps what it spilled in a frame of its own
+#[test]
+fn fourteen_registers_across_a_recursive_call() {
+    let mut source = String::from(".use libc\n@work n 8 -> 8 {\n    (n = 0) => ret 0\n");
+    source += &(0..14).map(|k| format!("    ;w{} r{} = (n + {})\n", k, k + 30, k)).collect::<String>();
+    source += &format!("    ;m r50 = n\n    'm - 1\n    #work m\n    ;s r51 = r0\n    's + {}\n    ret s\n}}\n\n", sum("w"));
+    source += "main:\n    #work 20\n    $printf \"%ld\\n\", r0\n    ret 0\n";
+
+    for flags in FLAGS {
+        assert_eq!(run("spill-rec", &source, flags), "4760\n", "{:?}", flags);
+    }
+}
@@ -0,0 +1,84 @@
+use std::path::PathBuf;
+use std::process::Command;
+
+// Recursion through a call right before a return runs in constant stack space at -O2, and
+// `|notail|` keeps the call. The programs are assembled and linked with gcc
+
+const COUNT: &str = "
+.use libc
+@count n 8, acc 8 -> 8 {
+    (n = 0) => ret acc
+    ;m r2 = n
+    'm - 1
+    ;a r3 = acc
+    'a + 2
+    #count m, a
+    ret r0
+}
+
+main:
+    #count 10000000, 0
+    $printf \"%ld\\n\", r0
+    ret 0
+";
+
+fn temp(name: &str) -> PathBuf { std::env::temp_dir().join(format!("shard-{}-{}", std::process::id(), name)) }
+
+// the assembly shard emits for `source`
+fn compile(name: &str, source: &str, flags: &[&str]) -> PathBuf {
+    let (src, asm) = (temp(&format!("{}.shd", name)), temp(&format!("{}.s", name)));
+    std::fs::write(&src, source).unwrap();
+    let status = Command::new(env!("CARGO_BIN_EXE_shard"))
+        .arg(&src)
+        .args(["-A", "-o"])
+        .arg(&asm)
+        .arg("-L")
+        .arg(concat!(env!("CARGO_MANIFEST_DIR"), "/lib"))
+        .args(flags)
+        .status()
+        .unwrap();
+    std::fs::remove_file(&src).unwrap();
+    assert!(status.success(), "{} didn't compile", name);
+    asm
+}
+
+// the instructions of one function, up to the label of the next
+fn body(asm: &str, func: &str) -> Vec<String> {
+    asm.lines()
+        .skip_while(|l| *l != format!("{}:", func))
+        .skip(1)
+        .take_while(|l| l.starts_with(' ') || l.starts_with(".L"))
+        .map(|l| l.trim().to_string())
+        .collect()
+}
+
+#[test]
+fn deep_recursion_keeps_the_stack_flat() {
+    for (name, flags) in [("deep", &["-O2"][..]), ("deep-fp", &["-O2", "-F"])] {
+        let asm = compile(name, COUNT, flags);
+        let count = body(&std::fs::read_to_string(&asm).unwrap(), "count");
+        assert!(!count.iter().any(|l| l == "call count"), "{:?} still calls count:\n{}", flags, count.join("\n"));
+        assert!(count.iter().any(|l| l == "jmp count"), "{:?} doesn't jump to count:\n{}", flags, count.join("\n"));
+
+        // ten million frames would be far past the default 8 MiB stack
+        let bin = temp(name);
+        let status = Command::new("gcc").arg("-no-pie").arg(&asm).arg("-o").arg(&bin).status().unwrap();
+        assert!(status.success(), "gcc couldn't link {:?}", flags);
+        let out = Command::new(&bin).output().unwrap();
+        std::fs::remove_file(&asm).unwrap();
+        std::fs::remove_file(&bin).unwrap();
+        assert!(out.status.success(), "{:?} exited with {}", flags, out.status);
+        assert_eq!(String::from_utf8_lossy(&out.stdout), "20000000\n");
+    }
+}
+
+#[test]
+fn notail_keeps_the_call() {
+    let source = COUNT.replace("@count", "|notail|\n@count");
+    let path = compile("notail", &source, &["-O2"]);
+    let asm = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let count = body(&asm, "count");
+    assert!(count.iter().any(|l| l == "call count"), "no call left:\n{}", count.join("\n"));
+    assert!(!count.iter().any(|l| l == "jmp count"), "the call became a jump:\n{}", count.join("\n"));
+}